@@ -0,0 +1,63 @@
+// backwash.rs - Multiport valve positions and the backwash routine's settings
+use core::fmt;
+
+/// Handle positions on a sand or DE filter's multiport valve
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Multiport {
+  Filter,
+  Backwash,
+  Rinse,
+  Waste,
+  Recirculate,
+  Closed,
+}
+
+impl fmt::Display for Multiport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Multiport::Filter => write!(f, "Filter"),
+      Multiport::Backwash => write!(f, "Backwash"),
+      Multiport::Rinse => write!(f, "Rinse"),
+      Multiport::Waste => write!(f, "Waste"),
+      Multiport::Recirculate => write!(f, "Recirculate"),
+      Multiport::Closed => write!(f, "Closed"),
+    }
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Backwash {
+  /// The multiport has an actuator behind `Mech::mech_multiport_to`.
+  /// Without one the routine asks for the handle to be turned by hand.
+  pub actuated: bool,
+  /// Time given to turn the handle by hand
+  pub handle_secs: u64,
+  pub backwash_secs: u64,
+  /// Settles the bed again so dirt isn't blown back into the pool
+  pub rinse_secs: u64,
+  /// Where the valve is, as far as anyone knows
+  pub position: Multiport,
+  /// When the last backwash finished, on the `History` clock. Seconds to
+  /// compare with `History::now_secs`, not a date.
+  pub last_history_secs: Option<u64>,
+}
+
+impl Default for Backwash {
+  fn default() -> Self {
+    Backwash {
+      actuated: false,
+      handle_secs: 60,
+      backwash_secs: 3 * 60,
+      rinse_secs: 30,
+      position: Multiport::Filter,
+      last_history_secs: None,
+    }
+  }
+}
+
+impl Backwash {
+  /// Mid-routine, anywhere but filtering
+  pub fn running(&self) -> bool {
+    self.position != Multiport::Filter
+  }
+}
@@ -0,0 +1,288 @@
+// buttons.rs - Physical button input: debounce, long press and combos
+
+/// Source of raw button pin samples
+pub trait Buttons {
+  /// Bitmask of currently pressed buttons, bit N is `Button` with index N
+  fn sample(&self) -> u16;
+}
+
+/// Front panel buttons, indexed to match the web UI `button-N` ids
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Button {
+  AutoSpa = 0,
+  Jets = 1,
+  FilterSchedule = 2,
+  QuickClean = 3,
+  MainValve = 4,
+  HeaterPower = 6,
+  HeatMode = 7,
+}
+
+impl Button {
+  pub const ALL: [Button; 7] = [
+    Button::AutoSpa,
+    Button::Jets,
+    Button::FilterSchedule,
+    Button::QuickClean,
+    Button::MainValve,
+    Button::HeaterPower,
+    Button::HeatMode,
+  ];
+
+  pub fn from_index(i: usize) -> Option<Button> {
+    Button::ALL.iter().copied().find(|b| b.index() == i)
+  }
+
+  pub fn index(self) -> usize {
+    self as usize
+  }
+
+  pub fn mask(self) -> u16 {
+    1 << self.index()
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ButtonEvent {
+  Short(Button),
+  Long(Button),
+  /// Two or more buttons held together, as a bitmask
+  Combo(u16),
+}
+
+/// Something the system can be asked to do from an input
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Action {
+  AutoSpa,
+  CancelRoutine,
+  ToggleJets,
+  ToggleFilterSchedule,
+  ToggleQuickClean,
+  StopFilter,
+  ToggleMainValves,
+  ToggleSpillover,
+  ToggleHeater,
+  ToggleHeatMode,
+  DisplayStatus,
+}
+
+impl Action {
+  /// Action for a single short press of `b`
+  pub fn for_press(b: Button) -> Action {
+    match b {
+      Button::AutoSpa => Action::AutoSpa,
+      Button::Jets => Action::ToggleJets,
+      Button::FilterSchedule => Action::ToggleFilterSchedule,
+      Button::QuickClean => Action::ToggleQuickClean,
+      Button::MainValve => Action::ToggleMainValves,
+      Button::HeaterPower => Action::ToggleHeater,
+      Button::HeatMode => Action::ToggleHeatMode,
+    }
+  }
+
+  pub fn for_event(e: ButtonEvent) -> Option<Action> {
+    match e {
+      ButtonEvent::Short(b) => Some(Action::for_press(b)),
+      ButtonEvent::Long(Button::AutoSpa) => Some(Action::CancelRoutine),
+      ButtonEvent::Long(Button::HeatMode) => Some(Action::DisplayStatus),
+      ButtonEvent::Long(Button::MainValve) => Some(Action::ToggleSpillover),
+      ButtonEvent::Long(_) => None,
+      ButtonEvent::Combo(m) if m == Button::FilterSchedule.mask() | Button::QuickClean.mask() => {
+        Some(Action::StopFilter)
+      }
+      ButtonEvent::Combo(_) => None,
+    }
+  }
+}
+
+#[derive(Copy, Clone)]
+pub struct ButtonConfig {
+  /// Raw level must be stable this long before it is accepted
+  pub debounce_ms: u64,
+  /// Held at least this long counts as a long press
+  pub long_press_ms: u64,
+  /// Several buttons held together this long counts as a combo
+  pub combo_ms: u64,
+}
+
+impl Default for ButtonConfig {
+  fn default() -> Self {
+    ButtonConfig {
+      debounce_ms: 30,
+      long_press_ms: 1500,
+      combo_ms: 500,
+    }
+  }
+}
+
+/// Turns raw pin samples into debounced press events
+pub struct ButtonProcessor {
+  config: ButtonConfig,
+  raw: u16,
+  raw_since: u64,
+  stable: u16,
+  pressed_at: [u64; 16],
+  long_fired: u16,
+  combo_since: Option<u64>,
+  // Buttons that took part in a combo or long press; they emit nothing
+  // further until released
+  consumed: u16,
+}
+
+impl ButtonProcessor {
+  pub const fn new(config: ButtonConfig) -> Self {
+    ButtonProcessor {
+      config,
+      raw: 0,
+      raw_since: 0,
+      stable: 0,
+      pressed_at: [0; 16],
+      long_fired: 0,
+      combo_since: None,
+      consumed: 0,
+    }
+  }
+
+  /// Currently debounced pressed buttons
+  pub fn pressed(&self) -> u16 {
+    self.stable
+  }
+
+  pub fn update<B: Buttons, F: FnMut(ButtonEvent)>(&mut self, now_ms: u64, buttons: &B, emit: F) {
+    self.poll(now_ms, buttons.sample(), emit);
+  }
+
+  /// Feed one raw sample taken at `now_ms`, calling `emit` for every event it completes
+  pub fn poll<F: FnMut(ButtonEvent)>(&mut self, now_ms: u64, raw: u16, mut emit: F) {
+    if raw != self.raw {
+      self.raw = raw;
+      self.raw_since = now_ms;
+    }
+
+    if self.raw != self.stable && now_ms.saturating_sub(self.raw_since) >= self.config.debounce_ms {
+      let pressed = self.raw & !self.stable;
+      let released = self.stable & !self.raw;
+      self.stable = self.raw;
+
+      for i in 0..16 {
+        if pressed & (1 << i) != 0 {
+          self.pressed_at[i] = now_ms;
+        }
+      }
+
+      for b in Button::ALL {
+        if released & b.mask() != 0 && self.consumed & b.mask() == 0 {
+          emit(ButtonEvent::Short(b));
+        }
+      }
+
+      self.long_fired &= !released;
+      self.consumed &= !released;
+
+      if self.stable.count_ones() >= 2 {
+        self.combo_since = Some(now_ms);
+      } else {
+        self.combo_since = None;
+      }
+    }
+
+    if let Some(since) = self.combo_since {
+      if now_ms.saturating_sub(since) >= self.config.combo_ms {
+        self.combo_since = None;
+        self.consumed |= self.stable;
+        emit(ButtonEvent::Combo(self.stable));
+      }
+      return;
+    }
+
+    for b in Button::ALL {
+      let m = b.mask();
+      if self.stable & m == 0 || self.long_fired & m != 0 || self.consumed & m != 0 {
+        continue;
+      }
+      if now_ms.saturating_sub(self.pressed_at[b.index()]) >= self.config.long_press_ms {
+        self.long_fired |= m;
+        self.consumed |= m;
+        emit(ButtonEvent::Long(b));
+      }
+    }
+  }
+}
+
+impl Default for ButtonProcessor {
+  fn default() -> Self {
+    Self::new(ButtonConfig::default())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn run(p: &mut ButtonProcessor, samples: &[(u64, u16)]) -> Vec<ButtonEvent> {
+    let mut events = Vec::new();
+    for &(t, raw) in samples {
+      p.poll(t, raw, |e| events.push(e));
+    }
+    events
+  }
+
+  #[test]
+  fn bounce_is_ignored() {
+    let mut p = ButtonProcessor::default();
+    let jets = Button::Jets.mask();
+
+    let events = run(&mut p, &[(0, jets), (5, 0), (10, jets), (15, 0), (100, 0)]);
+    assert!(events.is_empty());
+    assert_eq!(p.pressed(), 0);
+  }
+
+  #[test]
+  fn short_press_fires_on_release() {
+    let mut p = ButtonProcessor::default();
+    let jets = Button::Jets.mask();
+
+    let events = run(&mut p, &[(0, jets), (40, jets), (200, 0), (240, 0)]);
+    assert_eq!(events, vec![ButtonEvent::Short(Button::Jets)]);
+  }
+
+  #[test]
+  fn long_press_fires_once_and_suppresses_short() {
+    let mut p = ButtonProcessor::default();
+    let spa = Button::AutoSpa.mask();
+
+    let events = run(
+      &mut p,
+      &[
+        (0, spa),
+        (40, spa),
+        (1600, spa),
+        (2000, spa),
+        (2100, 0),
+        (2200, 0),
+      ],
+    );
+    assert_eq!(events, vec![ButtonEvent::Long(Button::AutoSpa)]);
+    assert_eq!(Action::for_event(events[0]), Some(Action::CancelRoutine));
+  }
+
+  #[test]
+  fn combo_fires_and_suppresses_members() {
+    let mut p = ButtonProcessor::default();
+    let both = Button::FilterSchedule.mask() | Button::QuickClean.mask();
+
+    let events = run(
+      &mut p,
+      &[
+        (0, both),
+        (40, both),
+        (600, both),
+        (2000, both),
+        (2100, 0),
+        (2200, 0),
+      ],
+    );
+    assert_eq!(events, vec![ButtonEvent::Combo(both)]);
+    assert_eq!(Action::for_event(events[0]), Some(Action::StopFilter));
+  }
+}
@@ -0,0 +1,130 @@
+// chemistry.rs - pH/ORP readings and acid/chlorine dosing pumps
+use crate::structs::Mech;
+use core::fmt;
+
+/// Probes in the circulation line; only meaningful with water moving
+pub trait ChemSensors {
+  fn ph(&self) -> Option<f32>;
+  fn orp_mv(&self) -> Option<f32>;
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DosePump {
+  Acid,
+  Chlorine,
+}
+
+impl fmt::Display for DosePump {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DosePump::Acid => write!(f, "Acid"),
+      DosePump::Chlorine => write!(f, "Chlorine"),
+    }
+  }
+}
+
+/// Peristaltic pumps injecting into the return line
+pub trait DosingMech: Mech {
+  fn dose_pump(&self, pump: DosePump, on: bool) -> bool;
+}
+
+/// No acid until pH is this far above target
+pub const PH_DEADBAND: f32 = 0.1;
+/// No chlorine until ORP is this far below target
+pub const ORP_DEADBAND_MV: f32 = 20.0;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Doser {
+  pub on: bool,
+  pub ml_per_min: f32,
+  /// Nothing more is dosed once today's total reaches this
+  pub max_ml_per_day: f32,
+  pub dosed_ml_today: f32,
+  /// Left of the dose running now
+  pub dose_left_secs: u64,
+  /// Left of the wait for the last dose to mix in before reading again
+  pub rest_left_secs: u64,
+  /// Logged once a day when the limit stops dosing
+  pub limit_logged: bool,
+}
+
+impl Doser {
+  pub fn new(ml_per_min: f32, max_ml_per_day: f32) -> Self {
+    Doser {
+      on: false,
+      ml_per_min,
+      max_ml_per_day,
+      dosed_ml_today: 0.0,
+      dose_left_secs: 0,
+      rest_left_secs: 0,
+      limit_logged: false,
+    }
+  }
+
+  pub fn at_limit(&self) -> bool {
+    self.dosed_ml_today >= self.max_ml_per_day
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Chemistry {
+  /// Last readings, refreshed by `chemistry_tick`
+  pub ph: Option<f32>,
+  pub orp_mv: Option<f32>,
+  pub ph_target: f32,
+  pub orp_target_mv: f32,
+  pub acid: Doser,
+  pub chlorine: Doser,
+  /// Each dose runs this long...
+  pub dose_secs: u64,
+  /// ...then waits this long before the readings are trusted again
+  pub mix_secs: u64,
+  /// Readings go to the log this often
+  pub log_every_secs: u64,
+  pub(crate) since_logged_secs: u64,
+  pub(crate) last_minute: Option<u32>,
+}
+
+impl Default for Chemistry {
+  fn default() -> Self {
+    Chemistry {
+      ph: None,
+      orp_mv: None,
+      ph_target: 7.5,
+      orp_target_mv: 700.0,
+      acid: Doser::new(50.0, 1000.0),
+      chlorine: Doser::new(100.0, 4000.0),
+      dose_secs: 60,
+      mix_secs: 15 * 60,
+      log_every_secs: 60 * 60,
+      since_logged_secs: 0,
+      last_minute: None,
+    }
+  }
+}
+
+impl Chemistry {
+  pub fn doser(&self, pump: DosePump) -> &Doser {
+    match pump {
+      DosePump::Acid => &self.acid,
+      DosePump::Chlorine => &self.chlorine,
+    }
+  }
+
+  pub fn doser_mut(&mut self, pump: DosePump) -> &mut Doser {
+    match pump {
+      DosePump::Acid => &mut self.acid,
+      DosePump::Chlorine => &mut self.chlorine,
+    }
+  }
+
+  /// Whether the readings call for `pump`; never without a reading
+  pub fn wants(&self, pump: DosePump) -> bool {
+    match pump {
+      DosePump::Acid => self.ph.is_some_and(|ph| ph > self.ph_target + PH_DEADBAND),
+      DosePump::Chlorine => self
+        .orp_mv
+        .is_some_and(|orp| orp < self.orp_target_mv - ORP_DEADBAND_MV),
+    }
+  }
+}
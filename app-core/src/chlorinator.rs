@@ -0,0 +1,76 @@
+// chlorinator.rs - Salt chlorine generator: cell output, salt and cell temp
+use crate::structs::{Mech, PoolOrSpa};
+
+/// Salt cell power supply and the sensors that come with it
+pub trait ChlorinatorMech: Mech {
+  /// Cell output as a percentage of full production, 0 is off
+  fn cell_output(&self, percent: u8) -> bool;
+  fn salt_ppm(&self) -> Option<u32>;
+  fn cell_temp_f(&self) -> Option<f32>;
+}
+
+/// Below this the cell is switched off and a fault raised
+pub const SALT_MIN_PPM: u32 = 2600;
+/// Above this the cell still runs but status warns
+pub const SALT_HIGH_PPM: u32 = 4500;
+/// Cells barely produce in cold water and wear out trying
+pub const CELL_MIN_TEMP_F: f32 = 52.0;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Chlorinator {
+  /// Output while circulating the pool
+  pub pool_percent: u8,
+  /// Output while circulating the spa, which needs far less
+  pub spa_percent: u8,
+  /// What the cell is being driven at now
+  pub output: u8,
+  /// Super-chlorinate runs at 100% for this long
+  pub super_limit_secs: u64,
+  /// Counted down by `chlorinator_tick` while super-chlorinating
+  pub super_remaining_secs: Option<u64>,
+  /// Last readings, refreshed by `chlorinator_tick`
+  pub salt_ppm: Option<u32>,
+  pub cell_temp_f: Option<f32>,
+  /// Why the cell is held off, for status
+  pub held_off: Option<&'static str>,
+}
+
+impl Default for Chlorinator {
+  fn default() -> Self {
+    Chlorinator {
+      pool_percent: 50,
+      spa_percent: 20,
+      output: 0,
+      super_limit_secs: 24 * 60 * 60,
+      super_remaining_secs: None,
+      salt_ppm: None,
+      cell_temp_f: None,
+      held_off: None,
+    }
+  }
+}
+
+impl Chlorinator {
+  pub fn percent_for(&self, body: PoolOrSpa) -> u8 {
+    match body {
+      PoolOrSpa::Pool => self.pool_percent,
+      PoolOrSpa::Spa => self.spa_percent,
+    }
+  }
+
+  pub fn super_chlorinating(&self) -> bool {
+    self.super_remaining_secs.is_some()
+  }
+
+  pub fn salt_low(&self) -> bool {
+    self.salt_ppm.is_some_and(|s| s < SALT_MIN_PPM)
+  }
+
+  pub fn salt_high(&self) -> bool {
+    self.salt_ppm.is_some_and(|s| s > SALT_HIGH_PPM)
+  }
+
+  pub fn too_cold(&self) -> bool {
+    self.cell_temp_f.is_some_and(|t| t < CELL_MIN_TEMP_F)
+  }
+}
@@ -0,0 +1,172 @@
+// circuits.rs - Named auxiliary relay circuits (lights, waterfall, cleaner...)
+use crate::structs::{Mech, Name};
+use core::ops::{Deref, DerefMut};
+
+/// Most aux relays a controller drives
+pub const MAX_AUX: usize = 8;
+
+/// Relays beyond the fixed set `Mech` knows about, by index into
+/// `System::aux`
+pub trait AuxMech: Mech {
+  fn aux_set(&self, index: usize, on: bool) -> bool;
+}
+
+/// Daily on/off window in minutes after midnight; wraps past midnight when
+/// `off_min < on_min`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct DailyWindow {
+  pub on_min: u32,
+  pub off_min: u32,
+}
+
+impl DailyWindow {
+  pub fn contains(&self, minute_of_day: u32) -> bool {
+    if self.on_min <= self.off_min {
+      (self.on_min..self.off_min).contains(&minute_of_day)
+    } else {
+      minute_of_day >= self.on_min || minute_of_day < self.off_min
+    }
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct AuxCircuit {
+  pub name: Name,
+  pub on: bool,
+  /// Only runs while the filter pump moves water, e.g. a pressure cleaner
+  pub needs_pump: bool,
+  /// Switches itself off after this long; `None` for no limit
+  pub limit_secs: Option<u64>,
+  /// Counted down by `aux_tick` while on
+  pub remaining_secs: Option<u64>,
+  pub schedule: Option<DailyWindow>,
+  // Whether the schedule window was open at the last tick, so only the
+  // edges switch the circuit and manual changes in between stick
+  in_window: bool,
+}
+
+impl AuxCircuit {
+  pub fn new(name: &str) -> Self {
+    AuxCircuit {
+      name: Name::new(name),
+      on: false,
+      needs_pump: false,
+      limit_secs: None,
+      remaining_secs: None,
+      schedule: None,
+      in_window: false,
+    }
+  }
+
+  pub fn needs_pump(mut self) -> Self {
+    self.needs_pump = true;
+    self
+  }
+
+  pub fn limit_mins(mut self, mins: u64) -> Self {
+    self.limit_secs = Some(mins * 60);
+    self
+  }
+
+  pub fn daily(mut self, on_min: u32, off_min: u32) -> Self {
+    self.schedule = Some(DailyWindow { on_min, off_min });
+    self
+  }
+
+  /// Which way the schedule wants to switch the circuit at `minute_of_day`,
+  /// if the window just opened or closed
+  pub(crate) fn schedule_edge(&mut self, minute_of_day: u32) -> Option<bool> {
+    let open = self.schedule?.contains(minute_of_day);
+    if open == self.in_window {
+      return None;
+    }
+    self.in_window = open;
+    Some(open)
+  }
+}
+
+/// Up to `MAX_AUX` circuits in the order they were added, used as a slice
+#[derive(Clone, Debug)]
+pub struct AuxCircuits {
+  circuits: [AuxCircuit; MAX_AUX],
+  len: usize,
+}
+
+impl Default for AuxCircuits {
+  fn default() -> Self {
+    AuxCircuits {
+      circuits: [AuxCircuit::new(""); MAX_AUX],
+      len: 0,
+    }
+  }
+}
+
+impl AuxCircuits {
+  /// Its index, or `None` once all `MAX_AUX` are taken
+  pub fn push(&mut self, c: AuxCircuit) -> Option<usize> {
+    let slot = self.circuits.get_mut(self.len)?;
+    *slot = c;
+    self.len += 1;
+    Some(self.len - 1)
+  }
+}
+
+impl Deref for AuxCircuits {
+  type Target = [AuxCircuit];
+
+  fn deref(&self) -> &[AuxCircuit] {
+    &self.circuits[..self.len]
+  }
+}
+
+impl<'a> IntoIterator for &'a AuxCircuits {
+  type Item = &'a AuxCircuit;
+  type IntoIter = core::slice::Iter<'a, AuxCircuit>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.iter()
+  }
+}
+
+impl DerefMut for AuxCircuits {
+  fn deref_mut(&mut self) -> &mut [AuxCircuit] {
+    &mut self.circuits[..self.len]
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn window_wraps_past_midnight() {
+    let night = DailyWindow {
+      on_min: 22 * 60,
+      off_min: 6 * 60,
+    };
+    assert!(night.contains(23 * 60));
+    assert!(night.contains(60));
+    assert!(!night.contains(12 * 60));
+  }
+
+  #[test]
+  fn schedule_only_switches_on_edges() {
+    let mut c = AuxCircuit::new("Waterfall").daily(9 * 60, 10 * 60);
+
+    assert_eq!(c.schedule_edge(8 * 60), None);
+    assert_eq!(c.schedule_edge(9 * 60), Some(true));
+    assert_eq!(c.schedule_edge(9 * 60 + 30), None);
+    assert_eq!(c.schedule_edge(10 * 60), Some(false));
+  }
+
+  #[test]
+  fn circuits_stop_at_capacity() {
+    let mut aux = AuxCircuits::default();
+    for i in 0..MAX_AUX {
+      assert_eq!(aux.push(AuxCircuit::new("Relay")), Some(i));
+    }
+    assert_eq!(aux.push(AuxCircuit::new("One too many")), None);
+    assert_eq!(aux.len(), MAX_AUX);
+    assert!(aux.iter().all(|c| c.name == "Relay"));
+  }
+}
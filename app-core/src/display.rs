@@ -0,0 +1,416 @@
+// display.rs - Character display status screen and message log
+use crate::message_queue::{Message, MessageQueue};
+use crate::structs::{ErrorCode, Lights, Mech, Sensors, System, Transition};
+use core::fmt::Write;
+
+/// A character display: an HD44780-style 16x2 LCD, or a 128x64 OLED driven
+/// as 21x8 text with a 6x8 font
+pub trait Display {
+  /// (columns, rows)
+  fn size(&self) -> (usize, usize);
+  /// Replace a whole row. `text` never exceeds the column count.
+  fn write_line(&self, row: usize, text: &str) -> bool;
+}
+
+/// Status pages, two rows each
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Page {
+  Temps,
+  Mode,
+  Routine,
+  Message,
+}
+
+impl Page {
+  pub const ALL: [Page; 4] = [Page::Temps, Page::Mode, Page::Routine, Page::Message];
+
+  pub fn next(self) -> Page {
+    Page::ALL[(self as usize + 1) % Page::ALL.len()]
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum View {
+  Status,
+  Log,
+}
+
+/// Pages through the system status, or scrolls back through the log
+pub struct StatusScreen<const N: usize> {
+  log: MessageQueue<N>,
+  pub page: Page,
+  pub view: View,
+  /// Messages back from the newest, in the log view
+  scroll: usize,
+  /// How long each page stays up before `tick` moves on; 0 disables paging
+  pub page_ms: u64,
+  paged_at: u64,
+}
+
+impl<const N: usize> Default for StatusScreen<N> {
+  fn default() -> Self {
+    StatusScreen {
+      log: MessageQueue::new(),
+      page: Page::Temps,
+      view: View::Status,
+      scroll: 0,
+      page_ms: 3000,
+      paged_at: 0,
+    }
+  }
+}
+
+impl<const N: usize> StatusScreen<N> {
+  /// Add one message to the log; also what `log_msg!` calls
+  pub fn push(&mut self, msg: Message) {
+    self.log.push(msg);
+    // Stay on the same message while scrolled back
+    if self.scroll > 0 {
+      self.scroll = (self.scroll + 1).min(self.log.len() - 1);
+    }
+  }
+
+  /// Move everything waiting in `queue` into the log; how many moved
+  pub fn feed<const Q: usize>(&mut self, queue: &mut MessageQueue<Q>) -> usize {
+    let mut moved = 0;
+    while let Some(msg) = queue.pop() {
+      self.push(msg);
+      moved += 1;
+    }
+    moved
+  }
+
+  /// The log, oldest first
+  pub fn messages(&self) -> impl Iterator<Item = &Message> + '_ {
+    self.log.iter()
+  }
+
+  pub fn clear_log(&mut self) {
+    self.log.clear();
+    self.scroll = 0;
+  }
+
+  pub fn latest(&self) -> Option<&str> {
+    self.log.iter().last().map(|m| m.get_str())
+  }
+
+  /// Advance the page once `page_ms` has passed since the last change
+  pub fn tick(&mut self, now_ms: u64) {
+    if self.page_ms > 0 && now_ms.saturating_sub(self.paged_at) >= self.page_ms {
+      self.page = self.page.next();
+      self.paged_at = now_ms;
+    }
+  }
+
+  pub fn next_page(&mut self, now_ms: u64) {
+    self.page = self.page.next();
+    self.paged_at = now_ms;
+  }
+
+  pub fn toggle_view(&mut self) {
+    self.view = match self.view {
+      View::Status => View::Log,
+      View::Log => View::Status,
+    };
+    self.scroll = 0;
+  }
+
+  /// Towards older messages
+  pub fn scroll_up(&mut self) {
+    self.scroll = (self.scroll + 1).min(self.log.len().saturating_sub(1));
+  }
+
+  /// Towards the newest message
+  pub fn scroll_down(&mut self) {
+    self.scroll = self.scroll.saturating_sub(1);
+  }
+
+  /// Draw every row of `d`; false if the display failed
+  pub fn render<M: Mech + Sensors, L: Lights, D: Display>(
+    &self,
+    sys: &System<M, L>,
+    d: &D,
+  ) -> bool {
+    let (cols, rows) = d.size();
+    let mut ok = true;
+    for row in 0..rows {
+      let line = match self.view {
+        View::Status => self.status_line(sys, cols, rows, row),
+        View::Log => self.log_line(rows, row),
+      };
+      ok &= d.write_line(row, truncate(line.get_str(), cols));
+    }
+    ok
+  }
+
+  fn status_line<M: Mech + Sensors, L: Lights>(
+    &self,
+    sys: &System<M, L>,
+    cols: usize,
+    rows: usize,
+    row: usize,
+  ) -> Message {
+    // Large displays show every page at once, small ones just the current one
+    let first = if rows >= Page::ALL.len() * 2 {
+      Page::Temps
+    } else {
+      self.page
+    };
+    let page = Page::ALL[(first as usize + row / 2) % Page::ALL.len()];
+    let second = row % 2 == 1;
+
+    let mut line = Message::new();
+    if row / 2 >= Page::ALL.len() {
+      return line;
+    }
+
+    match page {
+      Page::Temps => {
+        let r = sys.readings();
+        if !second {
+          line.write_str("Pool ").ok();
+          temp(&mut line, r.pool_temp_f);
+          line.write_str(" Air ").ok();
+          temp(&mut line, r.air_temp_f);
+        } else {
+          line.write_str("Spa ").ok();
+          temp(&mut line, r.spa_temp_f);
+          match r.flow_gpm {
+            Some(gpm) => write!(line, " {:.0}gpm", gpm).ok(),
+            None => line.write_str(" --gpm").ok(),
+          };
+        }
+      }
+      Page::Mode => {
+        if !second {
+          write!(line, "Valves {}", sys.valve_mode()).ok();
+        } else {
+          write!(line, "Heat {} {}", sys.heater.mode, on_off(sys.heater.on)).ok();
+        }
+      }
+      Page::Routine => {
+        if !second {
+          write!(line, "{}", routine(sys)).ok();
+        } else {
+          write!(line, "Pump {} Jets ", on_off(sys.pump_running())).ok();
+          match sys.jets_remaining_secs {
+            Some(left) if sys.jets_on => write!(line, "{}m", left.div_ceil(60)).ok(),
+            _ => line.write_str(on_off(sys.jets_on)).ok(),
+          };
+        }
+      }
+      Page::Message => {
+        let fault = sys
+          .errors
+          .iter()
+          .flatten()
+          .find_map(|e| ErrorCode::from_u32(*e));
+        let msg = self.latest().unwrap_or("");
+        match (fault, second) {
+          (Some(code), false) => write!(line, "FAULT {}", code as u32).ok(),
+          (Some(code), true) => write!(line, "{}", code).ok(),
+          // Wrap the latest message over both rows
+          (None, false) => line.write_str(truncate(msg, cols)).ok(),
+          (None, true) => line.write_str(skip(msg, cols)).ok(),
+        };
+      }
+    }
+    line
+  }
+
+  // Newest message on the bottom row, `scroll` messages back, but never
+  // so far that the oldest message leaves rows empty below it
+  fn log_line(&self, rows: usize, row: usize) -> Message {
+    let mut line = Message::new();
+    let len = self.log.len();
+    let newest = len.saturating_sub(self.scroll).max(rows.min(len));
+    let oldest = newest.saturating_sub(rows);
+    if let Some(msg) = self.log.iter().skip(oldest).take(newest - oldest).nth(row) {
+      line.write_str(msg.get_str()).ok();
+    }
+    line
+  }
+}
+
+fn routine<M: Mech, L: Lights>(sys: &System<M, L>) -> &str {
+  match sys.transition {
+    Some(Transition::Valves(_)) => "Moving valves",
+    Some(Transition::Priming(_)) => "Priming pump",
+    Some(Transition::StoppingFilter) => "Stopping filter",
+    None if sys.auto_spa_mode => "Auto spa",
+    None => match sys.active_scene.as_deref() {
+      Some(name) => name,
+      None if sys.filter.quick_clean => "Quick clean",
+      None if sys.filter.running_schedule => "Schedule",
+      None => "Idle",
+    },
+  }
+}
+
+fn temp(line: &mut Message, t: Option<f32>) {
+  match t {
+    Some(t) => write!(line, "{:.0}F", t).ok(),
+    None => line.write_str("--F").ok(),
+  };
+}
+
+fn on_off(b: bool) -> &'static str {
+  if b {
+    "ON"
+  } else {
+    "off"
+  }
+}
+
+fn truncate(s: &str, cols: usize) -> &str {
+  s.char_indices().nth(cols).map_or(s, |(i, _)| &s[..i])
+}
+
+fn skip(s: &str, cols: usize) -> &str {
+  s.char_indices().nth(cols).map_or("", |(i, _)| &s[i..])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::log_msg;
+  use crate::structs::Readings;
+  use crate::testing::{RecordingLights, RecordingMech};
+  use core::cell::RefCell;
+
+  struct TextDisplay {
+    cols: usize,
+    rows: RefCell<Vec<String>>,
+  }
+
+  impl TextDisplay {
+    fn new(cols: usize, rows: usize) -> Self {
+      TextDisplay {
+        cols,
+        rows: RefCell::new(vec![String::new(); rows]),
+      }
+    }
+
+    fn rows(&self) -> Vec<String> {
+      self.rows.borrow().clone()
+    }
+  }
+
+  impl Display for TextDisplay {
+    fn size(&self) -> (usize, usize) {
+      (self.cols, self.rows.borrow().len())
+    }
+
+    fn write_line(&self, row: usize, text: &str) -> bool {
+      self.rows.borrow_mut()[row] = text.to_string();
+      true
+    }
+  }
+
+  fn sys() -> System<RecordingMech, RecordingLights> {
+    let sys = System::new(RecordingMech::default(), RecordingLights::default());
+    sys.mech.readings.set(Readings {
+      pool_temp_f: Some(78.2),
+      spa_temp_f: Some(101.6),
+      air_temp_f: None,
+      flow_gpm: Some(55.0),
+      filter_psi: None,
+    });
+    sys
+  }
+
+  #[test]
+  fn small_display_pages_through_status() {
+    let mut sys = sys();
+    sys.filter.quick_clean = true;
+    let mut screen = StatusScreen::<8>::default();
+    let lcd = TextDisplay::new(16, 2);
+
+    screen.render(&sys, &lcd);
+    assert_eq!(lcd.rows(), ["Pool 78F Air --F", "Spa 102F 55gpm"]);
+
+    screen.tick(3000);
+    screen.tick(4000);
+    screen.render(&sys, &lcd);
+    assert_eq!(lcd.rows(), ["Valves POOL", "Heat POOL off"]);
+
+    screen.next_page(4000);
+    screen.render(&sys, &lcd);
+    assert_eq!(lcd.rows(), ["Quick clean", "Pump ON Jets off"]);
+
+    log_msg!(screen, "Finish: Quick clean started");
+    screen.next_page(4000);
+    screen.render(&sys, &lcd);
+    assert_eq!(lcd.rows(), ["Finish: Quick cl", "ean started"]);
+  }
+
+  #[test]
+  fn large_display_shows_every_page_and_faults() {
+    let mut sys = sys();
+    sys.errors[0] = Some(ErrorCode::Jets as u32);
+    let mut screen = StatusScreen::<8>::default();
+    screen.next_page(0);
+    let oled = TextDisplay::new(21, 8);
+
+    screen.render(&sys, &oled);
+    let rows = oled.rows();
+    assert_eq!(rows[1], "Spa 102F 55gpm");
+    assert_eq!(rows[4], "Idle");
+    assert_eq!(rows[6], "FAULT 5");
+    assert_eq!(rows[7], "Jets did not respond");
+  }
+
+  #[test]
+  fn log_view_scrolls_back_from_newest() {
+    let sys = sys();
+    let mut queue = MessageQueue::<8>::new();
+    for i in 0..5 {
+      log_msg!(queue, "msg {}", i);
+    }
+    let mut screen = StatusScreen::<4>::default();
+    screen.feed(&mut queue);
+    assert!(queue.is_empty());
+    screen.toggle_view();
+    let lcd = TextDisplay::new(16, 2);
+
+    screen.render(&sys, &lcd);
+    assert_eq!(lcd.rows(), ["msg 3", "msg 4"]);
+
+    screen.scroll_up();
+    screen.scroll_up();
+    screen.scroll_up();
+    screen.scroll_up();
+    screen.render(&sys, &lcd);
+    assert_eq!(lcd.rows(), ["msg 1", "msg 2"]);
+
+    // New messages don't move a scrolled view
+    screen.scroll_down();
+    screen.scroll_down();
+    screen.render(&sys, &lcd);
+    assert_eq!(lcd.rows(), ["msg 2", "msg 3"]);
+    log_msg!(screen, "msg 5");
+    screen.render(&sys, &lcd);
+    assert_eq!(lcd.rows(), ["msg 2", "msg 3"]);
+
+    // A taller display shows the whole log at any scroll
+    let oled = TextDisplay::new(21, 8);
+    screen.render(&sys, &oled);
+    assert_eq!(oled.rows()[..5], ["msg 2", "msg 3", "msg 4", "msg 5", ""]);
+  }
+
+  #[test]
+  fn feed_counts_moved_messages() {
+    let mut queue = MessageQueue::<8>::new();
+    log_msg!(queue, "a");
+    log_msg!(queue, "b");
+    let mut screen = StatusScreen::<4>::default();
+    assert_eq!(screen.feed(&mut queue), 2);
+    assert_eq!(screen.feed(&mut queue), 0);
+    let logged: Vec<&str> = screen.messages().map(|m| m.get_str()).collect();
+    assert_eq!(logged, ["a", "b"]);
+
+    screen.clear_log();
+    assert_eq!(screen.messages().count(), 0);
+    assert_eq!(screen.latest(), None);
+  }
+}
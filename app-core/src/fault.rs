@@ -0,0 +1,435 @@
+// fault.rs - Fault-injecting Mech/Lights wrappers for resilience testing
+use crate::backwash::Multiport;
+use crate::chemistry::{ChemSensors, DosePump, DosingMech};
+use crate::chlorinator::ChlorinatorMech;
+use crate::circuits::AuxMech;
+use crate::structs::{LightFrame, Lights, Mech, PoolOrSpa, PoolValve, Sensors};
+use core::cell::Cell;
+
+#[cfg(not(target_os = "none"))]
+use std::thread;
+#[cfg(not(target_os = "none"))]
+use std::time::Duration;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MechOp {
+  QuickClean,
+  FilterSchedule,
+  PoolValve,
+  MainValve,
+  ReturnValve,
+  HeaterOn,
+  HeaterMode,
+  Jets,
+  Aux,
+  PoolLight,
+  Chlorinator,
+  Dosing,
+  Multiport,
+}
+
+impl MechOp {
+  pub const ALL: [MechOp; 13] = [
+    MechOp::QuickClean,
+    MechOp::FilterSchedule,
+    MechOp::PoolValve,
+    MechOp::MainValve,
+    MechOp::ReturnValve,
+    MechOp::HeaterOn,
+    MechOp::HeaterMode,
+    MechOp::Jets,
+    MechOp::Aux,
+    MechOp::PoolLight,
+    MechOp::Chlorinator,
+    MechOp::Dosing,
+    MechOp::Multiport,
+  ];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      MechOp::QuickClean => "quick_clean",
+      MechOp::FilterSchedule => "filter_schedule",
+      MechOp::PoolValve => "pool_valve",
+      MechOp::MainValve => "main_valve",
+      MechOp::ReturnValve => "return_valve",
+      MechOp::HeaterOn => "heater_on",
+      MechOp::HeaterMode => "heater_mode",
+      MechOp::Jets => "jets",
+      MechOp::Aux => "aux",
+      MechOp::PoolLight => "pool_light",
+      MechOp::Chlorinator => "chlorinator",
+      MechOp::Dosing => "dosing",
+      MechOp::Multiport => "multiport",
+    }
+  }
+
+  pub fn from_name(s: &str) -> Option<MechOp> {
+    MechOp::ALL.iter().copied().find(|op| op.name() == s)
+  }
+
+  fn bit(self) -> u32 {
+    1 << self as u32
+  }
+}
+
+/// What to break. Interior mutability so it can be changed while the wrapper
+/// is owned by a `System`.
+#[derive(Default)]
+pub struct FaultPlan {
+  calls: Cell<u32>,
+  fail_nth: Cell<Option<u32>>,
+  fail_ops: Cell<u32>,
+  stuck_ops: Cell<u32>,
+  latency_ms: Cell<u64>,
+}
+
+impl FaultPlan {
+  /// Fail the `n`th call from now (1-based), once
+  pub fn fail_nth_call(&self, n: u32) {
+    self.calls.set(0);
+    self.fail_nth.set(Some(n));
+  }
+
+  /// Every call to `op` reports failure without reaching the hardware
+  pub fn fail_op(&self, op: MechOp) {
+    self.fail_ops.set(self.fail_ops.get() | op.bit());
+  }
+
+  /// Every call to `op` reports success without reaching the hardware,
+  /// like a welded relay or a seized valve
+  pub fn stick_op(&self, op: MechOp) {
+    self.stuck_ops.set(self.stuck_ops.get() | op.bit());
+  }
+
+  pub fn set_latency_ms(&self, ms: u64) {
+    self.latency_ms.set(ms);
+  }
+
+  pub fn clear(&self) {
+    self.calls.set(0);
+    self.fail_nth.set(None);
+    self.fail_ops.set(0);
+    self.stuck_ops.set(0);
+    self.latency_ms.set(0);
+  }
+
+  /// Apply a text command: `fail <op>`, `stuck <op>`, `nth <n>`,
+  /// `latency <ms>` or `clear`
+  pub fn apply(&self, cmd: &str) -> bool {
+    let mut parts = cmd.split_whitespace();
+    match (parts.next(), parts.next()) {
+      (Some("fail"), Some(op)) => MechOp::from_name(op).map(|op| self.fail_op(op)).is_some(),
+      (Some("stuck"), Some(op)) => MechOp::from_name(op).map(|op| self.stick_op(op)).is_some(),
+      (Some("nth"), Some(n)) => n.parse().map(|n| self.fail_nth_call(n)).is_ok(),
+      (Some("latency"), Some(ms)) => ms.parse().map(|ms| self.set_latency_ms(ms)).is_ok(),
+      (Some("clear"), None) => {
+        self.clear();
+        true
+      }
+      _ => false,
+    }
+  }
+
+  // Some(result) short-circuits the call, None lets it through
+  fn intercept(&self, op: Option<MechOp>) -> Option<bool> {
+    #[cfg(not(target_os = "none"))]
+    if self.latency_ms.get() > 0 {
+      thread::sleep(Duration::from_millis(self.latency_ms.get()));
+    }
+
+    let n = self.calls.get() + 1;
+    self.calls.set(n);
+    if self.fail_nth.get() == Some(n) {
+      self.fail_nth.set(None);
+      return Some(false);
+    }
+
+    let bit = op.map_or(0, |op| op.bit());
+    if self.fail_ops.get() & bit != 0 {
+      return Some(false);
+    }
+    if self.stuck_ops.get() & bit != 0 {
+      return Some(true);
+    }
+    None
+  }
+}
+
+pub struct FaultyMech<M: Mech> {
+  pub inner: M,
+  pub faults: FaultPlan,
+}
+
+impl<M: Mech> FaultyMech<M> {
+  pub fn new(inner: M) -> Self {
+    FaultyMech {
+      inner,
+      faults: FaultPlan::default(),
+    }
+  }
+
+  fn call(&self, op: MechOp, f: impl FnOnce(&M) -> bool) -> bool {
+    self
+      .faults
+      .intercept(Some(op))
+      .unwrap_or_else(|| f(&self.inner))
+  }
+}
+
+impl<M: Mech> Mech for FaultyMech<M> {
+  fn delay_secs(&self, secs: u64) {
+    self.inner.delay_secs(secs);
+  }
+
+  fn set_quick_clean(&self, v: bool) -> bool {
+    self.call(MechOp::QuickClean, |m| m.set_quick_clean(v))
+  }
+  fn mech_set_filter_sched(&self, v: bool) -> bool {
+    self.call(MechOp::FilterSchedule, |m| m.mech_set_filter_sched(v))
+  }
+
+  fn mech_pool_valve_to(&self, p: PoolValve) -> bool {
+    self.call(MechOp::PoolValve, |m| m.mech_pool_valve_to(p))
+  }
+  fn mech_main_valve_to(&self, p: PoolOrSpa) -> bool {
+    self.call(MechOp::MainValve, |m| m.mech_main_valve_to(p))
+  }
+  fn mech_return_valve_to(&self, p: PoolOrSpa) -> bool {
+    self.call(MechOp::ReturnValve, |m| m.mech_return_valve_to(p))
+  }
+  fn mech_pool_light(&self, on: bool) -> bool {
+    self.call(MechOp::PoolLight, |m| m.mech_pool_light(on))
+  }
+  fn mech_multiport_to(&self, p: Multiport) -> bool {
+    self.call(MechOp::Multiport, |m| m.mech_multiport_to(p))
+  }
+
+  fn heater_on_toggle(&self, b: bool) -> bool {
+    self.call(MechOp::HeaterOn, |m| m.heater_on_toggle(b))
+  }
+  fn heater_mode_toggle(&self, p: PoolOrSpa) -> bool {
+    self.call(MechOp::HeaterMode, |m| m.heater_mode_toggle(p))
+  }
+
+  fn jets_on_toggle(&self, b: bool) -> bool {
+    self.call(MechOp::Jets, |m| m.jets_on_toggle(b))
+  }
+
+  fn mech_aux_off(&self, index: usize) -> bool {
+    self.call(MechOp::Aux, |m| m.mech_aux_off(index))
+  }
+  fn mech_cell_off(&self) -> bool {
+    self.call(MechOp::Chlorinator, |m| m.mech_cell_off())
+  }
+  fn mech_dose_pump_off(&self, pump: DosePump) -> bool {
+    self.call(MechOp::Dosing, |m| m.mech_dose_pump_off(pump))
+  }
+}
+
+impl<M: AuxMech> AuxMech for FaultyMech<M> {
+  fn aux_set(&self, index: usize, on: bool) -> bool {
+    self.call(MechOp::Aux, |m| m.aux_set(index, on))
+  }
+}
+
+impl<M: ChlorinatorMech> ChlorinatorMech for FaultyMech<M> {
+  fn cell_output(&self, percent: u8) -> bool {
+    self.call(MechOp::Chlorinator, |m| m.cell_output(percent))
+  }
+  fn salt_ppm(&self) -> Option<u32> {
+    self.inner.salt_ppm()
+  }
+  fn cell_temp_f(&self) -> Option<f32> {
+    self.inner.cell_temp_f()
+  }
+}
+
+impl<M: DosingMech> DosingMech for FaultyMech<M> {
+  fn dose_pump(&self, pump: DosePump, on: bool) -> bool {
+    self.call(MechOp::Dosing, |m| m.dose_pump(pump, on))
+  }
+}
+
+impl<M: Mech + ChemSensors> ChemSensors for FaultyMech<M> {
+  fn ph(&self) -> Option<f32> {
+    self.inner.ph()
+  }
+  fn orp_mv(&self) -> Option<f32> {
+    self.inner.orp_mv()
+  }
+}
+
+impl<M: Mech + Sensors> Sensors for FaultyMech<M> {
+  fn pool_temp_f(&self) -> Option<f32> {
+    self.inner.pool_temp_f()
+  }
+  fn spa_temp_f(&self) -> Option<f32> {
+    self.inner.spa_temp_f()
+  }
+  fn air_temp_f(&self) -> Option<f32> {
+    self.inner.air_temp_f()
+  }
+  fn flow_gpm(&self) -> Option<f32> {
+    self.inner.flow_gpm()
+  }
+  fn filter_psi(&self) -> Option<f32> {
+    self.inner.filter_psi()
+  }
+}
+
+/// Lights only support `fail_nth_call` and latency; per-op faults are for `Mech`
+pub struct FaultyLights<L: Lights> {
+  pub inner: L,
+  pub faults: FaultPlan,
+}
+
+impl<L: Lights> FaultyLights<L> {
+  pub fn new(inner: L) -> Self {
+    FaultyLights {
+      inner,
+      faults: FaultPlan::default(),
+    }
+  }
+
+  /// `FaultPlan::apply`, refusing `fail <op>` and `stuck <op>` since lights
+  /// have no ops to pick from
+  pub fn apply(&self, cmd: &str) -> bool {
+    match cmd.split_whitespace().next() {
+      Some("fail" | "stuck") => false,
+      _ => self.faults.apply(cmd),
+    }
+  }
+
+  fn call(&self, f: impl FnOnce(&L) -> bool) -> bool {
+    self
+      .faults
+      .intercept(None)
+      .unwrap_or_else(|| f(&self.inner))
+  }
+}
+
+impl<L: Lights> Lights for FaultyLights<L> {
+  fn show(&self, frame: &LightFrame) -> bool {
+    self.call(|l| l.show(frame))
+  }
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+  use super::*;
+  use crate::structs::{ErrorCode, Filter, HasOSLights, HasOSMech, System};
+
+  fn faulty() -> System<FaultyMech<HasOSMech>, HasOSLights> {
+    let mut sys = System::new(FaultyMech::new(HasOSMech), HasOSLights);
+    sys.internal_test = true;
+    sys
+  }
+
+  #[test]
+  fn stalled_valve_keeps_orientation_and_records_error() {
+    let mut sys = faulty();
+    sys.mech.faults.fail_op(MechOp::MainValve);
+
+    assert_eq!(sys.set_main_valves(PoolOrSpa::Spa), false);
+    assert_eq!(sys.main_valve_orientation, PoolOrSpa::Pool);
+    assert!(sys.has_error(ErrorCode::MainValve));
+  }
+
+  #[test]
+  fn jets_that_wont_stop_are_reported_once() {
+    let mut sys = faulty();
+    sys.main_valve_orientation = PoolOrSpa::Spa;
+    sys.jets_limit_secs = 600;
+    sys.toggle_jets();
+    sys.mech.faults.fail_op(MechOp::Jets);
+
+    sys.tick(600);
+    assert_eq!(sys.jets_on, true);
+    assert_eq!(sys.jets_remaining_secs, None);
+    assert!(sys.has_error(ErrorCode::Jets));
+
+    while sys.pop_message().is_some() {}
+    for _ in 0..10 {
+      sys.tick(1);
+    }
+    assert_eq!(sys.pop_message(), None);
+  }
+
+  #[test]
+  fn valve_does_not_move_if_filter_fails_to_stop() {
+    let mut sys = faulty();
+    sys.filter = Filter {
+      running_schedule: true,
+      quick_clean: false,
+    };
+    sys.mech.faults.fail_op(MechOp::FilterSchedule);
+
+    assert_eq!(sys.set_main_valves(PoolOrSpa::Spa), false);
+    assert_eq!(sys.main_valve_orientation, PoolOrSpa::Pool);
+    assert_eq!(sys.filter.running_schedule, true);
+    assert!(sys.has_error(ErrorCode::Filter));
+  }
+
+  #[test]
+  fn failed_heater_relay_leaves_heater_off() {
+    let mut sys = faulty();
+    sys.filter.quick_clean = true;
+    sys.mech.faults.apply("fail heater_on");
+
+    assert_eq!(sys.set_heater_on(true), false);
+    assert_eq!(sys.heater.on, false);
+    assert!(sys.has_error(ErrorCode::Heater));
+  }
+
+  #[test]
+  fn nth_call_fails_once() {
+    let mut sys = faulty();
+    sys.main_valve_orientation = PoolOrSpa::Spa;
+    sys.mech.faults.fail_nth_call(2);
+
+    assert_eq!(sys.toggle_jets(), true);
+    assert_eq!(sys.toggle_jets(), false);
+    assert_eq!(sys.jets_on, true);
+    assert_eq!(sys.toggle_jets(), true);
+    assert_eq!(sys.jets_on, false);
+  }
+
+  #[test]
+  fn failed_lights_are_retried_on_next_change() {
+    let mut sys = System::new(HasOSMech, FaultyLights::new(HasOSLights));
+    sys.internal_test = true;
+    sys.main_valve_orientation = PoolOrSpa::Spa;
+    sys.lights.faults.fail_nth_call(1);
+
+    sys.toggle_jets();
+    assert!(sys.has_error(ErrorCode::Lights));
+    assert_eq!(sys.shown_lights, None);
+
+    sys.toggle_heat_mode();
+    assert_eq!(sys.shown_lights, Some(sys.light_frame()));
+  }
+
+  #[test]
+  fn stuck_op_reports_success_without_reaching_hardware() {
+    let plan = FaultPlan::default();
+    assert!(plan.apply("stuck jets"));
+    assert_eq!(plan.intercept(Some(MechOp::Jets)), Some(true));
+    assert_eq!(plan.intercept(Some(MechOp::HeaterOn)), None);
+    assert!(plan.apply("clear"));
+    assert_eq!(plan.intercept(Some(MechOp::Jets)), None);
+    assert!(!plan.apply("fail sprinklers"));
+  }
+
+  #[test]
+  fn lights_refuse_per_op_faults() {
+    let lights = FaultyLights::new(HasOSLights);
+    assert!(!lights.apply("fail jets"));
+    assert!(!lights.apply("stuck heater_on"));
+    assert_eq!(lights.faults.intercept(None), None);
+
+    assert!(lights.apply("nth 1"));
+    assert_eq!(lights.faults.intercept(None), Some(false));
+    assert!(lights.apply("clear"));
+  }
+}
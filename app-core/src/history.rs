@@ -0,0 +1,323 @@
+// history.rs - Fixed-memory time series, downsampled per minute, hour and day
+use crate::structs::ValveMode;
+
+/// One bucket of history. Readings are averages over the bucket, the rest
+/// are totals within it.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Sample {
+  /// Start of the bucket, on the same clock as `History::now_secs`
+  pub start_secs: u64,
+  pub pool_temp_f: Option<f32>,
+  pub spa_temp_f: Option<f32>,
+  pub ph: Option<f32>,
+  pub orp_mv: Option<f32>,
+  pub pump_secs: u32,
+  pub heater_secs: u32,
+  pub valve_changes: u16,
+  pub dosed_ml: f32,
+  pub energy_wh: f32,
+}
+
+/// What the system looks like right now, fed to `History::record`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Observation {
+  pub pool_temp_f: Option<f32>,
+  pub spa_temp_f: Option<f32>,
+  pub ph: Option<f32>,
+  pub orp_mv: Option<f32>,
+  pub pump_on: bool,
+  pub heater_on: bool,
+  pub valves: ValveMode,
+}
+
+/// Fixed-size circular buffer of samples, oldest overwritten first
+pub struct Ring<const N: usize> {
+  samples: [Sample; N],
+  head: usize,
+  count: usize,
+}
+
+impl<const N: usize> Ring<N> {
+  pub fn new() -> Self {
+    Ring {
+      samples: [Sample::default(); N],
+      head: 0,
+      count: 0,
+    }
+  }
+
+  pub fn push(&mut self, s: Sample) {
+    self.samples[self.head] = s;
+    self.head = (self.head + 1) % N;
+    self.count = (self.count + 1).min(N);
+  }
+
+  /// Oldest to newest
+  pub fn iter(&self) -> impl Iterator<Item = &Sample> + '_ {
+    let tail = (self.head + N - self.count) % N;
+    (0..self.count).map(move |i| &self.samples[(tail + i) % N])
+  }
+
+  pub fn latest(&self) -> Option<&Sample> {
+    self.iter().last()
+  }
+
+  pub fn len(&self) -> usize {
+    self.count
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.count == 0
+  }
+}
+
+impl<const N: usize> Default for Ring<N> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+// Weighted running mean that stays `None` without any readings
+#[derive(Copy, Clone, Default)]
+struct Mean {
+  sum: f32,
+  weight: f32,
+}
+
+impl Mean {
+  fn add(&mut self, v: Option<f32>, weight: f32) {
+    if let Some(v) = v {
+      self.sum += v * weight;
+      self.weight += weight;
+    }
+  }
+
+  fn get(&self) -> Option<f32> {
+    (self.weight > 0.0).then(|| self.sum / self.weight)
+  }
+}
+
+// The sample being filled for one tier
+#[derive(Copy, Clone, Default)]
+struct Bucket {
+  start_secs: u64,
+  pool: Mean,
+  spa: Mean,
+  ph: Mean,
+  orp: Mean,
+  pump_secs: u32,
+  heater_secs: u32,
+  valve_changes: u16,
+  dosed_ml: f32,
+  energy_wh: f32,
+}
+
+impl Bucket {
+  fn starting(start_secs: u64) -> Self {
+    Bucket {
+      start_secs,
+      ..Default::default()
+    }
+  }
+
+  // Fold in a finished sample from the tier below
+  fn absorb(&mut self, s: &Sample) {
+    self.pool.add(s.pool_temp_f, 1.0);
+    self.spa.add(s.spa_temp_f, 1.0);
+    self.ph.add(s.ph, 1.0);
+    self.orp.add(s.orp_mv, 1.0);
+    self.pump_secs += s.pump_secs;
+    self.heater_secs += s.heater_secs;
+    self.valve_changes = self.valve_changes.saturating_add(s.valve_changes);
+    self.dosed_ml += s.dosed_ml;
+    self.energy_wh += s.energy_wh;
+  }
+
+  fn sample(&self) -> Sample {
+    Sample {
+      start_secs: self.start_secs,
+      pool_temp_f: self.pool.get(),
+      spa_temp_f: self.spa.get(),
+      ph: self.ph.get(),
+      orp_mv: self.orp.get(),
+      pump_secs: self.pump_secs,
+      heater_secs: self.heater_secs,
+      valve_changes: self.valve_changes,
+      dosed_ml: self.dosed_ml,
+      energy_wh: self.energy_wh,
+    }
+  }
+}
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+/// Two hours of minutes, two days of hours and a month of days
+pub struct History {
+  pub minutes: Ring<120>,
+  pub hours: Ring<48>,
+  pub days: Ring<30>,
+  /// Input power used for `energy_wh`
+  pub pump_watts: f32,
+  /// A 250k BTU/hr gas heater burns about 73 kW
+  pub heater_watts: f32,
+  now_secs: u64,
+  minute: Bucket,
+  hour: Bucket,
+  day: Bucket,
+  last_valves: Option<ValveMode>,
+}
+
+impl History {
+  /// `start_secs` is the clock the buckets line up with, e.g. seconds
+  /// since midnight so hours and days break where they should
+  pub fn new(start_secs: u64) -> Self {
+    History {
+      minutes: Ring::new(),
+      hours: Ring::new(),
+      days: Ring::new(),
+      pump_watts: 1500.0,
+      heater_watts: 73_000.0,
+      now_secs: start_secs,
+      minute: Bucket::starting(start_secs - start_secs % MINUTE),
+      hour: Bucket::starting(start_secs - start_secs % HOUR),
+      day: Bucket::starting(start_secs - start_secs % DAY),
+      last_valves: None,
+    }
+  }
+
+  pub fn now_secs(&self) -> u64 {
+    self.now_secs
+  }
+
+  /// Account for `dt_secs` spent looking like `obs`, closing any buckets
+  /// that end on the way
+  pub fn record(&mut self, dt_secs: u64, obs: &Observation) {
+    if self.last_valves.is_some_and(|v| v != obs.valves) {
+      self.minute.valve_changes = self.minute.valve_changes.saturating_add(1);
+    }
+    self.last_valves = Some(obs.valves);
+
+    let mut left = dt_secs;
+    while left > 0 {
+      let step = left.min(MINUTE - self.now_secs % MINUTE);
+      self.accumulate(step, obs);
+      self.now_secs += step;
+      left -= step;
+      if self.now_secs.is_multiple_of(MINUTE) {
+        self.close_minute();
+      }
+    }
+  }
+
+  /// Count a chemical dose into the current minute
+  pub fn note_dose(&mut self, ml: f32) {
+    self.minute.dosed_ml += ml;
+  }
+
+  fn accumulate(&mut self, secs: u64, obs: &Observation) {
+    let w = secs as f32;
+    let m = &mut self.minute;
+    m.pool.add(obs.pool_temp_f, w);
+    m.spa.add(obs.spa_temp_f, w);
+    m.ph.add(obs.ph, w);
+    m.orp.add(obs.orp_mv, w);
+
+    let mut watts = 0.0;
+    if obs.pump_on {
+      m.pump_secs += secs as u32;
+      watts += self.pump_watts;
+    }
+    if obs.heater_on {
+      m.heater_secs += secs as u32;
+      watts += self.heater_watts;
+    }
+    m.energy_wh += watts * w / 3600.0;
+  }
+
+  fn close_minute(&mut self) {
+    let s = self.minute.sample();
+    self.minutes.push(s);
+    self.hour.absorb(&s);
+    self.minute = Bucket::starting(self.now_secs);
+
+    if self.now_secs.is_multiple_of(HOUR) {
+      let s = self.hour.sample();
+      self.hours.push(s);
+      self.day.absorb(&s);
+      self.hour = Bucket::starting(self.now_secs);
+    }
+    if self.now_secs.is_multiple_of(DAY) {
+      self.days.push(self.day.sample());
+      self.day = Bucket::starting(self.now_secs);
+    }
+  }
+}
+
+impl Default for History {
+  fn default() -> Self {
+    Self::new(0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn obs(pool: f32, pump_on: bool, valves: ValveMode) -> Observation {
+    Observation {
+      pool_temp_f: Some(pool),
+      spa_temp_f: None,
+      ph: None,
+      orp_mv: None,
+      pump_on,
+      heater_on: false,
+      valves,
+    }
+  }
+
+  #[test]
+  fn minutes_roll_up_into_hours() {
+    let mut h = History::new(0);
+    h.record(30 * MINUTE, &obs(70.0, true, ValveMode::Pool));
+    h.record(30 * MINUTE, &obs(80.0, false, ValveMode::Pool));
+
+    assert_eq!(h.minutes.len(), 60);
+    assert_eq!(h.minutes.latest().unwrap().pool_temp_f, Some(80.0));
+    assert_eq!(h.hours.len(), 1);
+    let hour = h.hours.latest().unwrap();
+    assert_eq!(hour.start_secs, 0);
+    assert_eq!(hour.pool_temp_f, Some(75.0));
+    assert_eq!(hour.pump_secs, 30 * 60);
+    assert_eq!(hour.energy_wh, 750.0);
+    assert_eq!(hour.spa_temp_f, None);
+  }
+
+  #[test]
+  fn ring_keeps_the_newest() {
+    let mut h = History::new(0);
+    h.record(3 * HOUR, &obs(70.0, false, ValveMode::Pool));
+
+    assert_eq!(h.minutes.len(), 120);
+    assert_eq!(h.minutes.iter().next().unwrap().start_secs, HOUR);
+    assert_eq!(h.minutes.latest().unwrap().start_secs, 3 * HOUR - MINUTE);
+  }
+
+  #[test]
+  fn buckets_line_up_with_the_clock() {
+    // Start half way through a minute
+    let mut h = History::new(90);
+    h.record(30, &obs(70.0, false, ValveMode::Pool));
+    h.record(30, &obs(70.0, false, ValveMode::Spa));
+
+    let first = h.minutes.latest().unwrap();
+    assert_eq!(first.start_secs, 60);
+    assert_eq!(first.valve_changes, 0);
+    assert_eq!(h.minutes.len(), 1);
+
+    h.record(30, &obs(70.0, false, ValveMode::Spa));
+    assert_eq!(h.minutes.len(), 2);
+    assert_eq!(h.minutes.latest().unwrap().valve_changes, 1);
+  }
+}
@@ -0,0 +1,218 @@
+// invariants.rs - Property tests driving System with random input sequences
+use crate::buttons::Action;
+use crate::structs::{Lights, Mech, PoolOrSpa, System, ValveMode};
+use crate::testing::{Call, RecordingLights, RecordingMech};
+use proptest::prelude::*;
+
+#[derive(Copy, Clone, Debug)]
+enum Op {
+  Act(Action),
+  /// `auto_spa(Some(true))`, leaves the spa running
+  AutoSpaStay,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+  prop_oneof![
+    Just(Op::Act(Action::AutoSpa)),
+    Just(Op::AutoSpaStay),
+    Just(Op::Act(Action::CancelRoutine)),
+    Just(Op::Act(Action::ToggleJets)),
+    Just(Op::Act(Action::ToggleFilterSchedule)),
+    Just(Op::Act(Action::ToggleQuickClean)),
+    Just(Op::Act(Action::StopFilter)),
+    Just(Op::Act(Action::ToggleMainValves)),
+    Just(Op::Act(Action::ToggleSpillover)),
+    Just(Op::Act(Action::ToggleHeater)),
+    Just(Op::Act(Action::ToggleHeatMode)),
+  ]
+}
+
+/// Reference state machine: what each input should leave behind
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct Model {
+  valves: ValveMode,
+  schedule: bool,
+  quick_clean: bool,
+  heater_on: bool,
+  heat_mode: PoolOrSpa,
+  jets: bool,
+}
+
+fn flip(m: PoolOrSpa) -> PoolOrSpa {
+  match m {
+    PoolOrSpa::Pool => PoolOrSpa::Spa,
+    PoolOrSpa::Spa => PoolOrSpa::Pool,
+  }
+}
+
+impl Model {
+  fn new() -> Self {
+    Model {
+      valves: ValveMode::Pool,
+      schedule: false,
+      quick_clean: false,
+      heater_on: false,
+      heat_mode: PoolOrSpa::Pool,
+      jets: false,
+    }
+  }
+
+  fn of<M: Mech, L: Lights>(sys: &System<M, L>) -> Self {
+    Model {
+      valves: sys.valve_mode(),
+      schedule: sys.filter.running_schedule,
+      quick_clean: sys.filter.quick_clean,
+      heater_on: sys.heater.on,
+      heat_mode: sys.heater.mode,
+      jets: sys.jets_on,
+    }
+  }
+
+  fn apply(&mut self, op: Op) {
+    match op {
+      Op::Act(Action::AutoSpa) => {
+        // Everything comes back except quick clean, which the spa ends
+        self.quick_clean = false;
+      }
+      Op::AutoSpaStay => {
+        if self.valves != ValveMode::Spa {
+          self.schedule = false;
+        }
+        self.valves = ValveMode::Spa;
+        self.heater_on = true;
+        self.heat_mode = PoolOrSpa::Spa;
+        self.quick_clean = true;
+      }
+      Op::Act(Action::CancelRoutine) => {
+        self.jets = false;
+        self.heater_on = false;
+        self.quick_clean = false;
+        self.schedule = false;
+      }
+      Op::Act(Action::ToggleJets) => {
+        if self.jets || self.valves == ValveMode::Spa {
+          self.jets = !self.jets;
+        }
+      }
+      Op::Act(Action::ToggleFilterSchedule) => self.schedule = !self.schedule,
+      Op::Act(Action::ToggleQuickClean) => self.quick_clean = !self.quick_clean,
+      Op::Act(Action::StopFilter) => {
+        self.quick_clean = false;
+        self.schedule = false;
+      }
+      Op::Act(Action::ToggleMainValves) => {
+        // The schedule is restored afterwards, quick clean is not
+        self.valves = match self.valves.suction() {
+          PoolOrSpa::Pool => ValveMode::Spa,
+          PoolOrSpa::Spa => ValveMode::Pool,
+        };
+        self.quick_clean = false;
+        if self.valves != ValveMode::Spa {
+          self.jets = false;
+        }
+      }
+      Op::Act(Action::ToggleSpillover) => {
+        self.valves = match self.valves {
+          ValveMode::Spillover => ValveMode::Pool,
+          _ => ValveMode::Spillover,
+        };
+        self.quick_clean = false;
+        self.jets = false;
+      }
+      Op::Act(Action::ToggleHeater) => self.heater_on = !self.heater_on,
+      Op::Act(Action::ToggleHeatMode) => self.heat_mode = flip(self.heat_mode),
+      Op::Act(Action::DisplayStatus) => {}
+    }
+  }
+}
+
+/// Relay states rebuilt from the recorded `Mech` calls
+#[derive(Default)]
+struct Relays {
+  quick_clean: bool,
+  schedule: bool,
+  heater: bool,
+  jets: bool,
+  suction: Option<PoolOrSpa>,
+  ret: Option<PoolOrSpa>,
+}
+
+impl Relays {
+  fn pump(&self) -> bool {
+    self.quick_clean || self.schedule
+  }
+}
+
+// Replays the whole log, failing on the first call that breaks a safety rule
+fn replay(calls: &[Call]) -> Result<Relays, TestCaseError> {
+  let mut r = Relays::default();
+  for (i, c) in calls.iter().enumerate() {
+    match *c {
+      Call::QuickClean(b) => r.quick_clean = b,
+      Call::FilterSchedule(b) => r.schedule = b,
+      Call::HeaterOn(b) => r.heater = b,
+      Call::Jets(b) => r.jets = b,
+      Call::MainValve(m) => {
+        prop_assert!(!r.pump(), "valve moved with pump running at call {}", i);
+        r.suction = Some(m);
+        r.ret = Some(m);
+      }
+      Call::ReturnValve(m) => {
+        prop_assert!(!r.pump(), "valve moved with pump running at call {}", i);
+        r.ret = Some(m);
+      }
+      Call::Multiport(_) => {
+        prop_assert!(!r.pump(), "multiport moved with pump running at call {}", i);
+      }
+      Call::Delay(_)
+      | Call::PoolValve(_)
+      | Call::HeaterMode(_)
+      | Call::Aux(..)
+      | Call::PoolLight(_)
+      | Call::Cell(_)
+      | Call::Dose(..) => {}
+    }
+    prop_assert!(
+      !r.heater || r.pump(),
+      "heater on without flow at call {}",
+      i
+    );
+  }
+  Ok(r)
+}
+
+proptest! {
+  #[test]
+  fn system_keeps_safety_invariants(ops in prop::collection::vec(op(), 1..40)) {
+    let mut sys = System::new(RecordingMech::default(), RecordingLights::default());
+    sys.internal_test = true;
+    let mut model = Model::new();
+
+    for op in ops {
+      match op {
+        Op::Act(a) => sys.perform(a),
+        Op::AutoSpaStay => {
+          sys.auto_spa(Some(true));
+        }
+      }
+      model.apply(op);
+
+      let relays = replay(&sys.mech.log.calls())?;
+
+      prop_assert_eq!(Model::of(&sys), model, "after {:?}", op);
+      prop_assert_eq!(relays.quick_clean, sys.filter.quick_clean);
+      prop_assert_eq!(relays.schedule, sys.filter.running_schedule);
+      prop_assert_eq!(relays.heater, sys.heater.on && sys.pump_running());
+      prop_assert_eq!(relays.heater, sys.heater_relay);
+      prop_assert_eq!(relays.jets, sys.jets_on);
+      prop_assert_eq!(relays.suction.unwrap_or(PoolOrSpa::Pool), sys.main_valve_orientation);
+      prop_assert_eq!(relays.ret.unwrap_or(PoolOrSpa::Pool), sys.return_valve_orientation);
+      prop_assert!(!sys.in_progress);
+      if let Some(shown) = sys.lights.log.calls().last() {
+        prop_assert_eq!(*shown, sys.light_frame());
+      }
+      prop_assert!(!sys.auto_spa_mode);
+      prop_assert!(sys.errors.iter().all(|e| e.is_none()));
+    }
+  }
+}
@@ -1,15 +1,47 @@
+extern crate alloc;
+
+pub mod backwash;
+pub mod buttons;
+pub mod chemistry;
+pub mod chlorinator;
+pub mod circuits;
+pub mod display;
+pub mod fault;
+pub mod history;
+#[cfg(test)]
+mod invariants;
+pub mod maintenance;
 pub mod message_queue;
+pub mod pool_light;
+pub mod pressure;
+pub mod scene;
 pub mod structs;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
 
 use crate::{
+  backwash::{Backwash, Multiport},
+  buttons::Action,
+  chemistry::{ChemSensors, Chemistry, DosePump, DosingMech},
+  chlorinator::{Chlorinator, ChlorinatorMech, SALT_MIN_PPM},
+  circuits::{AuxCircuit, AuxCircuits, AuxMech},
+  history::{History, Observation},
+  maintenance::{Maintenance, Reminder},
   message_queue::MessageQueue,
-  structs::{Filter, Heater, Lights, Mech, PoolOrSpa, PoolValve, PrevState, System},
+  pool_light::{PoolLight, CYCLE_OFF_SECS, CYCLE_ON_SECS, RESYNC_OFF_SECS, SHOWS},
+  pressure::{FilterPressure, DRY_PSI},
+  scene::{FilterRun, Scene},
+  structs::{
+    ErrorCode, Filter, Heater, Light, LightFrame, LightPattern, Lights, Mech, PoolOrSpa, PoolValve,
+    PrevState, Readings, Sensors, StopSignal, System, Transition, ValveMode,
+  },
 };
 
 impl<M: Mech, L: Lights> System<M, L> {
   pub fn new(mech: M, lights: L) -> Self {
     System {
       main_valve_orientation: PoolOrSpa::Pool,
+      return_valve_orientation: PoolOrSpa::Pool,
       pool_valve_orientation: PoolValve::Skimmer,
       filter: Filter {
         running_schedule: false,
@@ -19,20 +51,47 @@ impl<M: Mech, L: Lights> System<M, L> {
         mode: PoolOrSpa::Pool,
         on: false,
       },
+      heater_relay: false,
       jets_on: false,
-      errors: [None; 10],
+      errors: [None; ErrorCode::COUNT],
       in_progress: false,
+      transition: None,
       mech,
       lights,
       internal_test: false,
       prev_state: None,
       message_queue: MessageQueue::new(),
       auto_spa_mode: false,
+      shown_lights: None,
+      stop: StopSignal::default(),
+      estop_latched: false,
+      active_scene: None,
+      jets_limit_secs: 20 * 60,
+      jets_remaining_secs: None,
+      jets_override: false,
+      aux: AuxCircuits::default(),
+      pool_light: PoolLight::default(),
+      chlorinator: Chlorinator::default(),
+      chemistry: Chemistry::default(),
+      history: History::default(),
+      maintenance: Maintenance::default(),
+      pressure: FilterPressure::default(),
+      backwash: Backwash::default(),
     }
   }
 
   // Jets
   pub fn toggle_jets(&mut self) -> bool {
+    if !self.jets_on && self.aborted() {
+      return false;
+    }
+    if !self.jets_on && self.main_valve_orientation != PoolOrSpa::Spa && !self.jets_override {
+      log_msg!(
+        self.message_queue,
+        "-Protect- Jets need the valves in SPA mode"
+      );
+      return false;
+    }
     if self.jets_on {
       log_msg!(self.message_queue, "Jets OFF");
     } else {
@@ -41,22 +100,192 @@ impl<M: Mech, L: Lights> System<M, L> {
 
     let new = !self.jets_on;
 
-    self.mech.jets_on_toggle(new);
-    self.lights.jets_on(new);
+    if !self.mech.jets_on_toggle(new) {
+      self.record_error(ErrorCode::Jets);
+      return false;
+    }
     self.jets_on = new;
+    self.jets_remaining_secs = if new && self.jets_limit_secs > 0 {
+      Some(self.jets_limit_secs)
+    } else {
+      None
+    };
+    self.render_lights();
+
+    true
+  }
+
+  /// Marks `r` as done and starts its interval again
+  pub fn acknowledge_reminder(&mut self, r: Reminder) -> bool {
+    if !self.maintenance.is_due(r) {
+      return false;
+    }
+    self.maintenance.acknowledge(r);
+    log_msg!(self.message_queue, "-Service- {} done", r);
+    self.render_lights();
+    true
+  }
+
+  pub fn set_jets_override(&mut self, b: bool) {
+    self.jets_override = b;
+    if b {
+      log_msg!(self.message_queue, "Jets override ON, allowed in POOL mode");
+    } else {
+      log_msg!(self.message_queue, "Jets override OFF");
+    }
+  }
+
+  /// Advance timers and run counters by `dt_secs` of wall-clock time
+  pub fn tick(&mut self, dt_secs: u64) {
+    let pump_on = self.pump_running();
+    let m = &mut self.maintenance;
+    if pump_on {
+      m.pump_secs += dt_secs;
+    }
+    if self.heater_relay {
+      m.heater_secs += dt_secs;
+    }
+    if self.jets_on {
+      m.jets_secs += dt_secs;
+    }
+    for r in Reminder::ALL {
+      if self.maintenance.newly_due(r) {
+        log_msg!(
+          self.message_queue,
+          "-Service- {}, {} {} since last",
+          r,
+          self.maintenance.since_service(r),
+          r.unit()
+        );
+        self.render_lights();
+      }
+    }
+
+    if let (true, Some(left)) = (self.jets_on, self.jets_remaining_secs) {
+      let left = left.saturating_sub(dt_secs);
+      self.jets_remaining_secs = Some(left);
+      if left == 0 {
+        // Tried once; if the jets don't answer, the fault stays up rather
+        // than a retry every tick
+        self.jets_remaining_secs = None;
+        log_msg!(
+          self.message_queue,
+          "Jets auto OFF after {} min",
+          self.jets_limit_secs / 60
+        );
+        self.toggle_jets();
+      }
+    }
+  }
+
+  // Pool light
+  pub fn set_pool_light(&mut self, on: bool) -> bool {
+    if self.pool_light.on == on || (on && self.aborted()) {
+      return false;
+    }
+    if !self.mech.mech_pool_light(on) {
+      self.record_error(ErrorCode::PoolLight);
+      return false;
+    }
+    self.pool_light.on = on;
+
+    if on {
+      log_msg!(
+        self.message_queue,
+        "Pool light ON, show {}",
+        self.pool_light.show_name()
+      );
+    } else {
+      log_msg!(self.message_queue, "Pool light OFF");
+    }
+    true
+  }
+
+  pub fn toggle_pool_light(&mut self) -> bool {
+    self.set_pool_light(!self.pool_light.on)
+  }
+
+  /// Steps the light round to `show` (an index into `SHOWS`) one quick power
+  /// cycle at a time, resyncing first if where it is now isn't known
+  pub fn select_light_show(&mut self, show: usize) -> bool {
+    if show >= SHOWS.len() || self.aborted() {
+      return false;
+    }
+    if self.pool_light.show.is_none() && !self.resync_pool_light() {
+      return false;
+    }
+    if !self.pool_light.on && !self.set_pool_light(true) {
+      return false;
+    }
+
+    log_msg!(self.message_queue, "Start: Pool light to {}", SHOWS[show]);
+    let cycles = self.pool_light.cycles_to(show).unwrap_or(0);
+    for _ in 0..cycles {
+      if !self.pool_light_cycle(CYCLE_OFF_SECS) {
+        return false;
+      }
+      self.pool_light.show = self.pool_light.show.map(|s| (s + 1) % SHOWS.len());
+      if !self.hold_secs(CYCLE_ON_SECS) {
+        return false;
+      }
+    }
+
+    log_msg!(self.message_queue, "Finish: Pool light on {}", SHOWS[show]);
+    true
+  }
+
+  /// Off long enough for the light to reset to its first show, then on
+  pub fn resync_pool_light(&mut self) -> bool {
+    if self.aborted() {
+      return false;
+    }
+    log_msg!(self.message_queue, "Start: Pool light resync");
+    if !self.pool_light_cycle(RESYNC_OFF_SECS) {
+      return false;
+    }
+    self.pool_light.show = Some(0);
 
+    log_msg!(self.message_queue, "Finish: Pool light on {}", SHOWS[0]);
+    true
+  }
+
+  // Off for `off_secs`, then back on. Anything cut short leaves the show
+  // unknown until the next resync.
+  fn pool_light_cycle(&mut self, off_secs: u64) -> bool {
+    if !self.mech.mech_pool_light(false) {
+      self.pool_light.show = None;
+      self.record_error(ErrorCode::PoolLight);
+      return false;
+    }
+    self.pool_light.on = false;
+
+    if !self.hold_secs(off_secs) {
+      self.pool_light.show = None;
+      return false;
+    }
+    if !self.mech.mech_pool_light(true) {
+      self.pool_light.show = None;
+      self.record_error(ErrorCode::PoolLight);
+      return false;
+    }
+    self.pool_light.on = true;
     true
   }
 
   // Filter
-  pub fn filter_delay(&mut self) -> bool {
+  /// Waits out priming. `pressure_tick` keeps watching for pressure for a
+  /// while after and stops the pump if none comes.
+  pub fn filter_delay(&mut self, l: Light) -> bool {
     log_msg!(self.message_queue, "Running: Filter ON");
+    self.pressure.priming_left_secs = Some(self.pressure.prime_secs);
 
-    self.with_progress_light(|s| {
-      if !s.internal_test {
-        s.mech.delay_secs(10);
-      }
+    let mut primed = true;
+    self.with_transition(Transition::Priming(l), |s| {
+      primed = s.wait_secs(10);
     });
+    if !primed {
+      return false;
+    }
 
     log_msg!(self.message_queue, "Finish: Filter primed and running");
     true
@@ -65,9 +294,7 @@ impl<M: Mech, L: Lights> System<M, L> {
   pub fn toggle_quick_clean(&mut self) -> bool {
     if self.filter.quick_clean {
       log_msg!(self.message_queue, "Quick clean OFF");
-      self.lights.quick_clean(false);
-      self.mech.set_quick_clean(false);
-      self.filter.quick_clean = false;
+      return self.end_quick_clean();
     } else {
       log_msg!(self.message_queue, "Quick Clean ON");
       self.start_quick_clean();
@@ -75,18 +302,56 @@ impl<M: Mech, L: Lights> System<M, L> {
     true
   }
 
+  fn end_quick_clean(&mut self) -> bool {
+    if !self.sync_heater_relay(self.filter.running_schedule) {
+      return false;
+    }
+    if !self.mech.set_quick_clean(false) {
+      self.record_error(ErrorCode::Filter);
+      return false;
+    }
+    self.filter.quick_clean = false;
+    self.render_lights();
+    true
+  }
+
+  /// Call after cleaning or backwashing; the next run learns how a clean
+  /// filter reads
+  pub fn relearn_filter_pressure(&mut self) {
+    self.pressure.relearn();
+    log_msg!(self.message_queue, "Filter pressure baseline cleared");
+    self.render_lights();
+  }
+
+  pub fn pump_running(&self) -> bool {
+    self.filter.quick_clean || self.filter.running_schedule
+  }
+
   pub fn toggle_filter_schedule(&mut self) -> bool {
     let new = !self.filter.running_schedule;
-    self.lights.filter_schedule(new);
+    if new && self.aborted() {
+      return false;
+    }
+    if !new && !self.sync_heater_relay(self.filter.quick_clean) {
+      return false;
+    }
+    if !self.mech.mech_set_filter_sched(new) {
+      self.record_error(ErrorCode::Filter);
+      return false;
+    }
     self.filter.running_schedule = new;
 
     if new {
       log_msg!(self.message_queue, "-Protect-");
       log_msg!(self.message_queue, "Filter schedule is ON");
-      self.filter_delay();
+      if !self.filter_delay(Light::FilterSchedule) {
+        return false;
+      }
+      self.sync_heater_relay(true);
     } else {
       log_msg!(self.message_queue, "Filter schedule is OFF")
     }
+    self.render_lights();
 
     true
   }
@@ -97,34 +362,58 @@ impl<M: Mech, L: Lights> System<M, L> {
     }
     log_msg!(self.message_queue, "Running: Turning filter OFF");
 
-    self.with_progress_light(|s| {
-      s.mech.set_quick_clean(false);
-      s.mech.mech_set_filter_sched(false);
+    if !self.sync_heater_relay(false) {
+      return false;
+    }
 
-      if !s.internal_test {
-        s.mech.delay_secs(5);
+    let mut stopped = true;
+    self.with_transition(Transition::StoppingFilter, |s| {
+      if s.filter.quick_clean {
+        if s.mech.set_quick_clean(false) {
+          s.filter.quick_clean = false;
+        } else {
+          stopped = false;
+        }
+      }
+      if s.filter.running_schedule {
+        if s.mech.mech_set_filter_sched(false) {
+          s.filter.running_schedule = false;
+        } else {
+          stopped = false;
+        }
       }
 
-      s.filter.quick_clean = false;
-      s.filter.running_schedule = false;
+      // The pump is already off if this is cut short
+      s.wait_secs(5);
     });
 
+    if !stopped {
+      self.record_error(ErrorCode::Filter);
+      return false;
+    }
+
     log_msg!(self.message_queue, "Finish: Filter is OFF");
 
     true
   }
 
   pub fn start_quick_clean(&mut self) -> bool {
-    if self.filter.quick_clean {
+    if self.filter.quick_clean || self.aborted() {
       return false;
     }
 
     log_msg!(self.message_queue, "Running: Turning quick clean ON");
 
-    self.lights.quick_clean(true);
-    self.mech.set_quick_clean(true);
-    self.filter_delay();
+    if !self.mech.set_quick_clean(true) {
+      self.record_error(ErrorCode::Filter);
+      return false;
+    }
+    if !self.filter_delay(Light::QuickClean) {
+      return false;
+    }
     self.filter.quick_clean = true;
+    self.sync_heater_relay(true);
+    self.render_lights();
 
     log_msg!(self.message_queue, "Complete: Quick clean is ON");
 
@@ -133,13 +422,16 @@ impl<M: Mech, L: Lights> System<M, L> {
 
   // Heater
   pub fn set_heater_on(&mut self, b: bool) -> bool {
-    if self.heater.on == b {
+    if self.heater.on == b || (b && self.aborted()) {
       return false;
     }
 
-    self.mech.heater_on_toggle(b);
-    self.lights.heater_on(b);
     self.heater.on = b;
+    if !self.sync_heater_relay(self.pump_running()) {
+      self.heater.on = !b;
+      return false;
+    }
+    self.render_lights();
 
     if b {
       log_msg!(self.message_queue, "Heater ON");
@@ -150,6 +442,21 @@ impl<M: Mech, L: Lights> System<M, L> {
     true
   }
 
+  // The heater relay only closes while the pump is moving water, and opens
+  // before the pump stops
+  fn sync_heater_relay(&mut self, pump_on: bool) -> bool {
+    let want = self.heater.on && pump_on;
+    if want == self.heater_relay {
+      return true;
+    }
+    if !self.mech.heater_on_toggle(want) {
+      self.record_error(ErrorCode::Heater);
+      return false;
+    }
+    self.heater_relay = want;
+    true
+  }
+
   pub fn toggle_heater_on(&mut self) {
     if self.heater.on {
       self.set_heater_on(false);
@@ -173,46 +480,180 @@ impl<M: Mech, L: Lights> System<M, L> {
       return false;
     }
 
+    if !self.mech.heater_mode_toggle(m) {
+      self.record_error(ErrorCode::HeaterMode);
+      return false;
+    }
+
     log_msg!(self.message_queue, "Heat mode set to {}", m);
     self.heater.mode = m;
-    self.lights.heater_mode(m);
-    self.mech.heater_mode_toggle(m);
-    return true;
+    self.render_lights();
+    true
   }
 
   // Valves
+  pub fn valve_mode(&self) -> ValveMode {
+    ValveMode::of(self.main_valve_orientation, self.return_valve_orientation)
+  }
+
+  /// Suction and return both to `m`
   pub fn set_main_valves(&mut self, m: PoolOrSpa) -> bool {
-    if self.main_valve_orientation == m {
+    if self.valve_mode() == ValveMode::from(m) || self.aborted() {
       return false;
     }
 
-    self.lights.main_valve_orientation(m);
     log_msg!(
       self.message_queue,
       "Start: Changing main valve orientation to {}",
       m
     );
 
-    if self.filter.quick_clean || self.filter.running_schedule {
-      log_msg!(self.message_queue, "-Protect- Turning filter OFF");
-      self.stop_filter();
+    if !self.protect_stop_filter() {
+      return false;
     }
 
-    self.with_progress_light(|s| {
-      s.mech.mech_main_valve_to(m);
-      if !s.internal_test {
-        s.mech.delay_secs(10);
+    let mut moved = false;
+    self.with_transition(Transition::Valves(m.into()), |s| {
+      if !s.mech.mech_main_valve_to(m) {
+        return;
       }
-
+      // Once commanded the actuator finishes travelling on its own
       s.main_valve_orientation = m;
-      log_msg!(s.message_queue, "Finish: Valves changed to {} mode", m);
+      s.return_valve_orientation = m;
+      s.maintenance.valve_cycles += 1;
+      moved = true;
+      if s.wait_secs(10) {
+        log_msg!(s.message_queue, "Finish: Valves changed to {} mode", m);
+      }
     });
 
+    if self.aborted() {
+      return false;
+    }
+    if !moved {
+      self.record_error(ErrorCode::MainValve);
+    } else if m == PoolOrSpa::Pool && self.jets_on && !self.jets_override {
+      log_msg!(
+        self.message_queue,
+        "-Protect- Jets OFF, valves left SPA mode"
+      );
+      self.toggle_jets();
+    }
+
+    moved
+  }
+
+  pub fn set_valve_mode(&mut self, mode: ValveMode) -> bool {
+    match mode {
+      ValveMode::Pool | ValveMode::Spa => self.set_main_valves(mode.suction()),
+      ValveMode::Spillover => {
+        if self.valve_mode() == mode {
+          return false;
+        }
+        if self.main_valve_orientation != PoolOrSpa::Pool && !self.set_main_valves(PoolOrSpa::Pool)
+        {
+          return false;
+        }
+        self.set_return_valve(PoolOrSpa::Spa)
+      }
+    }
+  }
+
+  fn set_return_valve(&mut self, m: PoolOrSpa) -> bool {
+    if self.return_valve_orientation == m || self.aborted() {
+      return false;
+    }
+
+    log_msg!(self.message_queue, "Start: Changing return valve to {}", m);
+
+    if !self.protect_stop_filter() {
+      return false;
+    }
+
+    let mode = ValveMode::of(self.main_valve_orientation, m);
+    let mut moved = false;
+    self.with_transition(Transition::Valves(mode), |s| {
+      if !s.mech.mech_return_valve_to(m) {
+        return;
+      }
+      s.return_valve_orientation = m;
+      s.maintenance.valve_cycles += 1;
+      moved = true;
+      if s.wait_secs(10) {
+        log_msg!(s.message_queue, "Finish: Valves changed to {} mode", mode);
+      }
+    });
+
+    if self.aborted() {
+      return false;
+    }
+    if !moved {
+      self.record_error(ErrorCode::MainValve);
+    }
+    moved
+  }
+
+  // Valves never move under pump load
+  fn protect_stop_filter(&mut self) -> bool {
+    if !self.pump_running() {
+      return true;
+    }
+    log_msg!(self.message_queue, "-Protect- Turning filter OFF");
+    if !self.stop_filter() {
+      log_msg!(
+        self.message_queue,
+        "-Protect- Filter still running, valves not moved"
+      );
+      return false;
+    }
+    true
+  }
+
+  pub fn set_pool_valve(&mut self, p: PoolValve) -> bool {
+    if self.pool_valve_orientation == p || self.aborted() {
+      return false;
+    }
+
+    log_msg!(self.message_queue, "Start: Changing pool valve to {}", p);
+
+    // Suction side, so the same rule as the main valves
+    if !self.protect_stop_filter() {
+      return false;
+    }
+
+    if !self.mech.mech_pool_valve_to(p) {
+      self.record_error(ErrorCode::PoolValve);
+      return false;
+    }
+    self.pool_valve_orientation = p;
+    self.maintenance.valve_cycles += 1;
+    self.render_lights();
+
+    log_msg!(self.message_queue, "Finish: Pool valve set to {}", p);
     true
   }
 
   pub fn toggle_main_valves(&mut self) {
-    let prev_state = Some(PrevState {
+    match self.main_valve_orientation {
+      PoolOrSpa::Pool => self.move_valves_keeping_filter(ValveMode::Spa),
+      PoolOrSpa::Spa => self.move_valves_keeping_filter(ValveMode::Pool),
+    }
+  }
+
+  pub fn toggle_spillover(&mut self) {
+    if self.valve_mode() == ValveMode::Spillover {
+      self.move_valves_keeping_filter(ValveMode::Pool);
+    } else {
+      self.move_valves_keeping_filter(ValveMode::Spillover);
+    }
+  }
+
+  // The filter schedule and heater come back after the move, quick clean
+  // doesn't
+  fn move_valves_keeping_filter(&mut self, mode: ValveMode) {
+    // Held on self while the valves move so the lights can show what is
+    // waiting to come back
+    self.prev_state = Some(PrevState {
       heater: Heater {
         mode: self.heater.mode,
         on: self.heater.on,
@@ -224,17 +665,15 @@ impl<M: Mech, L: Lights> System<M, L> {
       main_valve_orientation: None,
     });
 
-    match self.main_valve_orientation {
-      PoolOrSpa::Pool => self.set_main_valves(PoolOrSpa::Spa),
-      PoolOrSpa::Spa => self.set_main_valves(PoolOrSpa::Pool),
-    };
+    self.set_valve_mode(mode);
 
+    let prev_state = self.prev_state.take();
     self.restore_previous_state(prev_state);
   }
 
   // Routines
   pub fn auto_spa(&mut self, ignore: Option<bool>) -> (bool, bool, bool) {
-    let prev_state = Some(PrevState {
+    let prev_state = PrevState {
       heater: Heater {
         mode: self.heater.mode,
         on: self.heater.on,
@@ -243,8 +682,15 @@ impl<M: Mech, L: Lights> System<M, L> {
         running_schedule: self.filter.running_schedule,
         quick_clean: self.filter.quick_clean,
       },
-      main_valve_orientation: Some(self.main_valve_orientation),
-    });
+      main_valve_orientation: Some(self.valve_mode()),
+    };
+    if self.aborted() {
+      return (false, false, false);
+    }
+    let restore = !ignore.unwrap_or(false);
+    if restore {
+      self.prev_state = Some(prev_state);
+    }
 
     log_msg!(self.message_queue, "Starting Spa... Enjoy! xo");
 
@@ -253,35 +699,446 @@ impl<M: Mech, L: Lights> System<M, L> {
     let op2 = self.set_heater_on(true);
     let op1 = self.set_heat_mode(PoolOrSpa::Spa);
     let op3 = self.start_quick_clean();
+    if self.aborted() {
+      return (op1, op2, op3);
+    }
 
     self.auto_spa_mode = true;
+    self.render_lights();
 
-    if !self.internal_test {
-      self.mech.delay_secs(10);
+    if !self.wait_secs(10) {
+      return (op1, op2, op3);
     }
 
     log_msg!(self.message_queue, "Delay of 3 hours");
-    self.filter.quick_clean = false;
 
-    if !ignore.unwrap_or(false) {
+    if restore {
+      self.end_quick_clean();
+      let prev_state = self.prev_state.take();
       self.restore_previous_state(prev_state);
     }
 
     self.auto_spa_mode = false;
+    self.render_lights();
 
     log_msg!(self.message_queue, "Complete: Spa Mode");
-    return (op1, op2, op3);
+    (op1, op2, op3)
+  }
+
+  /// Backwash then rinse the filter, stopping the pump for every turn of
+  /// the multiport, then puts things back as they were. Restarts the
+  /// backwash reminder and the clean pressure baseline.
+  pub fn backwash(&mut self) -> bool {
+    if self.backwash.running() || self.aborted() {
+      return false;
+    }
+    log_msg!(self.message_queue, "Start: Backwash");
+    let quick_clean = self.filter.quick_clean;
+    self.prev_state = Some(PrevState {
+      heater: Heater {
+        mode: self.heater.mode,
+        on: self.heater.on,
+      },
+      filter: Filter {
+        running_schedule: self.filter.running_schedule,
+        quick_clean,
+      },
+      main_valve_orientation: None,
+    });
+    self.set_heater_on(false);
+
+    let steps = [
+      (Multiport::Backwash, self.backwash.backwash_secs),
+      (Multiport::Rinse, self.backwash.rinse_secs),
+    ];
+    for (p, secs) in steps {
+      if !self.multiport_to(p) || !self.start_quick_clean() {
+        self.prev_state = None;
+        return false;
+      }
+      log_msg!(self.message_queue, "Running: {} for {} s", p, secs);
+      if !self.wait_secs(secs) {
+        self.prev_state = None;
+        return false;
+      }
+    }
+    if !self.multiport_to(Multiport::Filter) {
+      self.prev_state = None;
+      return false;
+    }
+
+    let prev_state = self.prev_state.take();
+    self.restore_previous_state(prev_state);
+    if quick_clean {
+      self.start_quick_clean();
+    }
+    self.backwash.last_history_secs = Some(self.history.now_secs());
+    self.maintenance.acknowledge(Reminder::Backwash);
+    self.relearn_filter_pressure();
+
+    log_msg!(self.message_queue, "Finish: Backwash complete");
+    true
+  }
+
+  // Pump off, then the valve turned by its actuator or by hand
+  fn multiport_to(&mut self, p: Multiport) -> bool {
+    if self.pump_running() && !self.stop_filter() {
+      return false;
+    }
+    if self.backwash.actuated {
+      if !self.mech.mech_multiport_to(p) {
+        self.record_error(ErrorCode::Multiport);
+        return false;
+      }
+    } else {
+      log_msg!(self.message_queue, "-Service- Turn the multiport to {}", p);
+      if !self.wait_secs(self.backwash.handle_secs) {
+        return false;
+      }
+    }
+    self.backwash.position = p;
+    self.render_lights();
+    true
+  }
+
+  pub fn set_filter_run(&mut self, f: FilterRun) -> bool {
+    match f {
+      FilterRun::Off => !self.pump_running() || self.stop_filter(),
+      FilterRun::Schedule => {
+        if self.filter.quick_clean && !self.end_quick_clean() {
+          return false;
+        }
+        self.filter.running_schedule || self.toggle_filter_schedule()
+      }
+      FilterRun::QuickClean => self.filter.quick_clean || self.start_quick_clean(),
+    }
+  }
+
+  /// Moves everything to `scene` in an order that never runs the heater
+  /// without flow or moves a valve under pump load. False if it was stopped.
+  pub fn apply_scene(&mut self, scene: &Scene) -> bool {
+    if self.aborted() {
+      return false;
+    }
+    log_msg!(self.message_queue, "Start: Scene {}", scene.name);
+    let before = scene.duration_secs.map(|_| Scene::snapshot(self));
+    // Moving valves stops the pump, so an unset filter means "as it is now"
+    let filter = scene.filter.unwrap_or(FilterRun::of(self));
+    self.active_scene = Some(scene.name);
+
+    // Loads that are going off go first
+    if scene.jets == Some(false) && self.jets_on {
+      self.toggle_jets();
+    }
+    if scene.heater_on == Some(false) {
+      self.set_heater_on(false);
+    }
+
+    if let Some(m) = scene.main_valves {
+      self.set_valve_mode(m);
+    }
+    if let Some(p) = scene.pool_valve {
+      self.set_pool_valve(p);
+    }
+    self.set_filter_run(filter);
+
+    if let Some(m) = scene.heat_mode {
+      self.set_heat_mode(m);
+    }
+    if scene.heater_on == Some(true) {
+      self.set_heater_on(true);
+    }
+    if scene.jets == Some(true) && !self.jets_on {
+      self.toggle_jets();
+    }
+    if self.aborted() {
+      self.active_scene = None;
+      return false;
+    }
+
+    if let (Some(secs), Some(before)) = (scene.duration_secs, before) {
+      log_msg!(
+        self.message_queue,
+        "Scene {} for {} min",
+        scene.name,
+        secs / 60
+      );
+      if !self.wait_secs(secs) {
+        self.active_scene = None;
+        return false;
+      }
+      self.active_scene = None;
+      return self.apply_scene(&before);
+    }
+
+    self.active_scene = None;
+    log_msg!(self.message_queue, "Complete: Scene {}", scene.name);
+    true
+  }
+
+  pub fn cancel_routine(&mut self) -> bool {
+    log_msg!(
+      self.message_queue,
+      "Cancel: Stopping jets, heater and filter"
+    );
+
+    if self.jets_on {
+      self.toggle_jets();
+    }
+    self.set_heater_on(false);
+    self.stop_filter();
+    self.auto_spa_mode = false;
+    self.render_lights();
+
+    log_msg!(self.message_queue, "Finish: Routine cancelled");
+    true
+  }
+
+  /// De-energises heater, jets and pump straight away, whatever the
+  /// system thinks they are doing, then every aux circuit, the salt cell
+  /// and the dosing pumps, and latches until `acknowledge_emergency_stop`.
+  /// Still switches things off when a routine already latched the stop;
+  /// false if it was latched before.
+  pub fn emergency_stop(&mut self) -> bool {
+    let latched = self.latch_emergency_stop();
+    for i in 0..self.aux.len() {
+      if !self.aux[i].on {
+        continue;
+      }
+      if self.mech.mech_aux_off(i) {
+        self.aux_switched(i, false);
+      } else {
+        self.record_error(ErrorCode::Aux);
+      }
+    }
+    if self.chlorinator.output != 0 {
+      if self.mech.mech_cell_off() {
+        self.chlorinator.output = 0;
+        self.chlorinator.held_off = Some("emergency stop");
+        log_msg!(self.message_queue, "Chlorinator output 0%");
+      } else {
+        self.record_error(ErrorCode::Chlorinator);
+      }
+    }
+    for pump in [DosePump::Acid, DosePump::Chlorine] {
+      if !self.chemistry.doser_mut(pump).on {
+        continue;
+      }
+      if self.mech.mech_dose_pump_off(pump) {
+        self.doser_switched(pump, false);
+      } else {
+        self.record_error(ErrorCode::Dosing);
+      }
+    }
+    latched
+  }
+
+  // Heater, jets and pump; false if already latched
+  fn latch_emergency_stop(&mut self) -> bool {
+    if self.estop_latched {
+      return false;
+    }
+    self.stop.raise();
+    self.estop_latched = true;
+    log_msg!(self.message_queue, "-E-Stop- Heater, jets and pump OFF");
+
+    // Heater first so it never runs on without flow
+    if self.mech.heater_on_toggle(false) {
+      self.heater_relay = false;
+    } else {
+      self.record_error(ErrorCode::Heater);
+    }
+    self.heater.on = false;
+
+    if self.mech.jets_on_toggle(false) {
+      self.jets_on = false;
+      self.jets_remaining_secs = None;
+    } else {
+      self.record_error(ErrorCode::Jets);
+    }
+
+    let qc = self.mech.set_quick_clean(false);
+    let sched = self.mech.mech_set_filter_sched(false);
+    if qc {
+      self.filter.quick_clean = false;
+    }
+    if sched {
+      self.filter.running_schedule = false;
+    }
+    if !qc || !sched {
+      self.record_error(ErrorCode::Filter);
+    }
+
+    // Nothing half-done gets resumed
+    self.auto_spa_mode = false;
+    self.prev_state = None;
+    self.transition = None;
+    self.in_progress = false;
+    self.record_error(ErrorCode::EmergencyStop);
+    true
+  }
+
+  pub fn acknowledge_emergency_stop(&mut self) -> bool {
+    if !self.estop_latched {
+      return false;
+    }
+    self.estop_latched = false;
+    self.stop.clear();
+    self.clear_error(ErrorCode::EmergencyStop);
+    log_msg!(self.message_queue, "-E-Stop- Acknowledged");
+    self.render_lights();
+    true
+  }
+
+  // Checked before anything starts and between routine steps. If the stop
+  // was raised while a routine held the system, the stop runs from here.
+  fn aborted(&mut self) -> bool {
+    if !self.stop.is_raised() {
+      return false;
+    }
+    if self.estop_latched {
+      log_msg!(self.message_queue, "-E-Stop- Latched, acknowledge first");
+    } else {
+      self.emergency_stop();
+    }
+    true
+  }
+
+  // What `set_aux` and the e-stop note once the relay has switched
+  fn aux_switched(&mut self, i: usize, on: bool) {
+    let c = &mut self.aux[i];
+    c.on = on;
+    c.remaining_secs = if on { c.limit_secs } else { None };
+
+    if on {
+      log_msg!(self.message_queue, "{} ON", self.aux[i].name);
+    } else {
+      log_msg!(self.message_queue, "{} OFF", self.aux[i].name);
+    }
+  }
+
+  // What `set_doser` and the e-stop note once the pump has switched
+  fn doser_switched(&mut self, pump: DosePump, on: bool) {
+    let (dose_secs, mix_secs) = (self.chemistry.dose_secs, self.chemistry.mix_secs);
+    let d = self.chemistry.doser_mut(pump);
+    d.on = on;
+    if on {
+      d.dose_left_secs = dose_secs;
+      log_msg!(self.message_queue, "Dose: {} ON", pump);
+    } else {
+      d.dose_left_secs = 0;
+      d.rest_left_secs = mix_secs;
+      let today = d.dosed_ml_today;
+      log_msg!(
+        self.message_queue,
+        "Dose: {} OFF, {:.0} ml today",
+        pump,
+        today
+      );
+    }
+  }
+
+  // Waits in one second steps so a stop is noticed; false if it was
+  fn wait_secs(&mut self, secs: u64) -> bool {
+    if self.internal_test {
+      return !self.aborted();
+    }
+    self.hold_secs(secs)
+  }
+
+  // Like `wait_secs`, for timing the hardware itself depends on, so it
+  // still runs under `internal_test`
+  fn hold_secs(&mut self, secs: u64) -> bool {
+    for _ in 0..secs {
+      if self.stop.is_raised() {
+        break;
+      }
+      self.mech.delay_secs(1);
+    }
+    !self.aborted()
+  }
+
+  // Errors
+  fn record_error(&mut self, code: ErrorCode) {
+    log_msg!(self.message_queue, "-Fault- {}", code);
+
+    if self.has_error(code) {
+      return;
+    }
+    if let Some(slot) = self.errors.iter_mut().find(|e| e.is_none()) {
+      *slot = Some(code as u32);
+    }
+    // A lights failure came from render_lights itself
+    if code != ErrorCode::Lights {
+      self.render_lights();
+    }
+  }
+
+  fn clear_error(&mut self, code: ErrorCode) {
+    for e in self.errors.iter_mut() {
+      if *e == Some(code as u32) {
+        *e = None;
+      }
+    }
+  }
+
+  pub fn has_error(&self, code: ErrorCode) -> bool {
+    self.errors.contains(&Some(code as u32))
+  }
+
+  /// Everything except a latched emergency stop
+  pub fn clear_errors(&mut self) {
+    self.errors = [None; ErrorCode::COUNT];
+    if self.estop_latched {
+      self.errors[0] = Some(ErrorCode::EmergencyStop as u32);
+    }
+    self.render_lights();
+  }
+
+  // Inputs
+  pub fn perform(&mut self, a: Action) {
+    match a {
+      Action::AutoSpa => {
+        self.auto_spa(None);
+      }
+      Action::CancelRoutine => {
+        self.cancel_routine();
+      }
+      Action::ToggleJets => {
+        self.toggle_jets();
+      }
+      Action::ToggleFilterSchedule => {
+        self.toggle_filter_schedule();
+      }
+      Action::ToggleQuickClean => {
+        self.toggle_quick_clean();
+      }
+      Action::StopFilter => {
+        self.stop_filter();
+      }
+      Action::ToggleMainValves => self.toggle_main_valves(),
+      Action::ToggleSpillover => self.toggle_spillover(),
+      Action::ToggleHeater => self.toggle_heater_on(),
+      Action::ToggleHeatMode => {
+        self.toggle_heat_mode();
+      }
+      Action::DisplayStatus => self.display_status(),
+    }
   }
 
   fn restore_previous_state(&mut self, o: Option<PrevState>) -> bool {
     log_msg!(self.message_queue, "Start: Restoring previous state");
+    if self.aborted() {
+      return false;
+    }
     if let Some(n) = o {
-      if n.filter.running_schedule != self.filter.running_schedule {
-        self.toggle_filter_schedule();
+      // Valves first, moving them stops the filter
+      if let Some(mode) = n.main_valve_orientation {
+        self.set_valve_mode(mode);
       }
 
-      if let Some(orientation) = n.main_valve_orientation {
-        self.set_main_valves(orientation);
+      if n.filter.running_schedule != self.filter.running_schedule {
+        self.toggle_filter_schedule();
       }
 
       self.set_heat_mode(n.heater.mode);
@@ -317,47 +1174,634 @@ impl<M: Mech, L: Lights> System<M, L> {
     log_msg!(
       self.message_queue,
       "Main Valve Position: {}",
-      self.main_valve_orientation
+      self.valve_mode()
     );
 
     log_msg!(self.message_queue, "Heater on: {}", self.heater.on);
     log_msg!(self.message_queue, "Heater Mode: {}", self.heater.mode);
-    log_msg!(self.message_queue, "Jets on: {}", self.jets_on);
+    match self.jets_remaining_secs {
+      Some(left) if self.jets_on => {
+        log_msg!(
+          self.message_queue,
+          "Jets on: true ({} min left)",
+          left.div_ceil(60)
+        )
+      }
+      _ => log_msg!(self.message_queue, "Jets on: {}", self.jets_on),
+    }
+
+    for code in self
+      .errors
+      .iter()
+      .flatten()
+      .filter_map(|e| ErrorCode::from_u32(*e))
+    {
+      log_msg!(self.message_queue, "Error: {}", code);
+    }
+
+    for c in &self.aux {
+      log_msg!(self.message_queue, "{} on: {}", c.name, c.on);
+    }
+
+    log_msg!(
+      self.message_queue,
+      "Pool light on: {} ({})",
+      self.pool_light.on,
+      self.pool_light.show_name()
+    );
+
+    let c = &self.chlorinator;
+    match c.held_off {
+      Some(why) => log_msg!(self.message_queue, "Chlorinator: off, {}", why),
+      None => log_msg!(
+        self.message_queue,
+        "Chlorinator: {}% (pool {}%, spa {}%)",
+        c.output,
+        c.pool_percent,
+        c.spa_percent
+      ),
+    }
+    if let Some(salt) = c.salt_ppm {
+      let note = if c.salt_low() {
+        " LOW"
+      } else if c.salt_high() {
+        " HIGH"
+      } else {
+        ""
+      };
+      log_msg!(self.message_queue, "Salt: {} ppm{}", salt, note);
+    }
+    if let Some(t) = c.cell_temp_f {
+      log_msg!(self.message_queue, "Cell temp: {:.0}F", t);
+    }
+    if let Some(left) = c.super_remaining_secs {
+      log_msg!(
+        self.message_queue,
+        "Super chlorinate: {} min left",
+        left.div_ceil(60)
+      );
+    }
+
+    let m = &self.maintenance;
+    log_msg!(
+      self.message_queue,
+      "Run hours: pump {}, heater {}, jets {}; valve cycles {}",
+      m.pump_secs / 3600,
+      m.heater_secs / 3600,
+      m.jets_secs / 3600,
+      m.valve_cycles
+    );
+    for r in Reminder::ALL.into_iter().filter(|r| m.is_due(*r)) {
+      log_msg!(self.message_queue, "Service due: {}", r);
+    }
+    log_msg!(self.message_queue, "Multiport: {}", self.backwash.position);
+    let p = &self.pressure;
+    if let Some(psi) = p.psi {
+      match p.baseline_psi {
+        Some(b) => log_msg!(
+          self.message_queue,
+          "Filter pressure: {:.1} psi, clean {:.1}",
+          psi,
+          b
+        ),
+        None => log_msg!(
+          self.message_queue,
+          "Filter pressure: {:.1} psi, learning",
+          psi
+        ),
+      }
+    }
+    if p.backwash_due {
+      log_msg!(self.message_queue, "Service due: Backwash the filter");
+    }
+
+    let chem = &self.chemistry;
+    if let Some(ph) = chem.ph {
+      log_msg!(
+        self.message_queue,
+        "pH: {:.1} (target {:.1})",
+        ph,
+        chem.ph_target
+      );
+    }
+    if let Some(orp) = chem.orp_mv {
+      log_msg!(
+        self.message_queue,
+        "ORP: {:.0} mV (target {:.0})",
+        orp,
+        chem.orp_target_mv
+      );
+    }
+    for pump in [DosePump::Acid, DosePump::Chlorine] {
+      let d = chem.doser(pump);
+      log_msg!(
+        self.message_queue,
+        "{} pump on: {}, {:.0} of {:.0} ml today",
+        pump,
+        d.on,
+        d.dosed_ml_today,
+        d.max_ml_per_day
+      );
+    }
   }
 
   // Lights
-  fn with_progress_light<F>(&mut self, f: F)
+  fn with_transition<F>(&mut self, t: Transition, f: F)
   where
     F: FnOnce(&mut Self),
   {
     self.in_progress = true;
-    self.lights.in_progress(true);
+    self.transition = Some(t);
+    self.render_lights();
     f(self);
-    self.lights.in_progress(false);
+    self.transition = None;
     self.in_progress = false;
+    self.render_lights();
   }
 
-  pub fn get_light_status(&mut self) -> [bool; 12] {
-    let mut arr: [bool; 12] = [false; 12];
-    arr[0] = self.auto_spa_mode;
-    arr[1] = self.jets_on;
-    arr[2] = self.filter.running_schedule;
-    arr[3] = self.filter.quick_clean;
-    arr[4] = self.main_valve_orientation == PoolOrSpa::Spa;
-    arr[5] = self.main_valve_orientation == PoolOrSpa::Pool;
-    arr[9] = self.heater.on;
-    arr[10] = self.heater.mode == PoolOrSpa::Spa;
-    arr[11] = self.heater.mode == PoolOrSpa::Pool;
+  /// What every light should show for the current state
+  pub fn light_frame(&self) -> LightFrame {
+    let mut frame = LightFrame::default();
+    frame.set(Light::AutoSpa, self.auto_spa_mode);
+    frame.set(Light::Jets, self.jets_on);
+    frame.set(Light::FilterSchedule, self.filter.running_schedule);
+    frame.set(Light::QuickClean, self.filter.quick_clean);
+    // Both lit in spillover
+    let mode = self.valve_mode();
+    frame.set(Light::MainValveSpa, mode.return_side() == PoolOrSpa::Spa);
+    frame.set(Light::MainValvePool, mode.suction() == PoolOrSpa::Pool);
+    frame.set(
+      Light::PoolValveBlend,
+      self.pool_valve_orientation == PoolValve::Blend,
+    );
+    frame.set(
+      Light::PoolValveSkimmer,
+      self.pool_valve_orientation == PoolValve::Skimmer,
+    );
+    frame.set(
+      Light::PoolValveVacuum,
+      self.pool_valve_orientation == PoolValve::Vacuum,
+    );
+    frame.set(Light::HeaterOn, self.heater.on);
+    frame.set(Light::HeatModeSpa, self.heater.mode == PoolOrSpa::Spa);
+    frame.set(Light::HeatModePool, self.heater.mode == PoolOrSpa::Pool);
+
+    if let Some(p) = &self.prev_state {
+      if p.filter.running_schedule && !self.filter.running_schedule {
+        frame.set_pattern(Light::FilterSchedule, LightPattern::Pulse);
+      }
+    }
+
+    match self.transition {
+      Some(Transition::Valves(m)) => {
+        frame.set(Light::MainValveSpa, false);
+        frame.set(Light::MainValvePool, false);
+        if m.return_side() == PoolOrSpa::Spa {
+          frame.set_pattern(Light::MainValveSpa, LightPattern::SlowBlink);
+        }
+        if m.suction() == PoolOrSpa::Pool {
+          frame.set_pattern(Light::MainValvePool, LightPattern::SlowBlink);
+        }
+      }
+      Some(Transition::Priming(l)) => frame.set_pattern(l, LightPattern::SlowBlink),
+      Some(Transition::StoppingFilter) => {
+        for l in [Light::FilterSchedule, Light::QuickClean] {
+          if frame.pattern(l) == LightPattern::Steady {
+            frame.set_pattern(l, LightPattern::SlowBlink);
+          }
+        }
+      }
+      None => {}
+    }
+    if self.transition.is_some() || self.backwash.running() {
+      frame.set_pattern(Light::InProgress, LightPattern::SlowBlink);
+    }
+    if self.errors.iter().any(|e| e.is_some()) {
+      frame.set_pattern(Light::Fault, LightPattern::FastBlink);
+    }
+    frame.set(
+      Light::Service,
+      self.maintenance.any_due() || self.pressure.backwash_due,
+    );
+
+    frame
+  }
+
+  /// Push the derived light frame out if it changed since last time
+  pub fn render_lights(&mut self) {
+    let frame = self.light_frame();
+    if self.shown_lights == Some(frame) {
+      return;
+    }
+    if self.lights.show(&frame) {
+      self.shown_lights = Some(frame);
+    } else {
+      self.record_error(ErrorCode::Lights);
+    }
+  }
+
+  /// Which panel lights are lit, in any pattern, indexed by [`Light`]
+  pub fn get_light_status(&mut self) -> [bool; Light::COUNT] {
+    let frame = self.light_frame();
+    let mut arr = [false; Light::COUNT];
+    for (i, on) in arr.iter_mut().enumerate() {
+      *on = frame.patterns[i] != LightPattern::Off;
+    }
 
     arr
   }
 }
 
+impl<M: Mech + Sensors, L: Lights> System<M, L> {
+  pub fn readings(&self) -> Readings {
+    Readings {
+      pool_temp_f: self.mech.pool_temp_f(),
+      spa_temp_f: self.mech.spa_temp_f(),
+      air_temp_f: self.mech.air_temp_f(),
+      flow_gpm: self.mech.flow_gpm(),
+      filter_psi: self.mech.filter_psi(),
+    }
+  }
+
+  /// Reads the filter gauge: stops a pump that never primes, learns the
+  /// clean baseline, then warns once the filter needs a backwash
+  pub fn pressure_tick(&mut self, dt_secs: u64) {
+    let psi = self.mech.filter_psi();
+    self.pressure.psi = psi;
+    if !self.pump_running() {
+      self.pressure.priming_left_secs = None;
+      return;
+    }
+    let Some(psi) = psi else {
+      return;
+    };
+
+    if let Some(left) = self.pressure.priming_left_secs {
+      if psi >= DRY_PSI {
+        self.pressure.priming_left_secs = None;
+      } else if left > dt_secs {
+        self.pressure.priming_left_secs = Some(left - dt_secs);
+        return;
+      } else {
+        self.pressure.priming_left_secs = None;
+        log_msg!(
+          self.message_queue,
+          "-Protect- Filter never built pressure, {:.1} psi",
+          psi
+        );
+        self.record_error(ErrorCode::LostPrime);
+        self.stop_filter();
+        return;
+      }
+    }
+
+    if self.pressure.learn(psi, dt_secs) {
+      log_msg!(
+        self.message_queue,
+        "Filter clean pressure learnt: {:.1} psi",
+        self.pressure.baseline_psi.unwrap_or(psi)
+      );
+    }
+    if !self.pressure.backwash_due && self.pressure.clogged(psi) {
+      self.pressure.backwash_due = true;
+      log_msg!(
+        self.message_queue,
+        "-Service- Backwash the filter, {:.1} psi is {:.1} over clean",
+        psi,
+        self.pressure.rise(psi).unwrap_or(0.0)
+      );
+      self.render_lights();
+    }
+  }
+
+  /// Feeds `history` with how the last `dt_secs` looked. Chemistry comes
+  /// from the readings `chemistry_tick` last took.
+  pub fn history_tick(&mut self, dt_secs: u64) {
+    let obs = Observation {
+      pool_temp_f: self.mech.pool_temp_f(),
+      spa_temp_f: self.mech.spa_temp_f(),
+      ph: self.chemistry.ph,
+      orp_mv: self.chemistry.orp_mv,
+      pump_on: self.pump_running(),
+      heater_on: self.heater_relay,
+      valves: self.valve_mode(),
+    };
+    self.history.record(dt_secs, &obs);
+  }
+
+  /// Temperature of the body currently being circulated
+  pub fn water_temp_f(&self) -> Option<f32> {
+    match self.main_valve_orientation {
+      PoolOrSpa::Pool => self.mech.pool_temp_f(),
+      PoolOrSpa::Spa => self.mech.spa_temp_f(),
+    }
+  }
+}
+
+impl<M: AuxMech, L: Lights> System<M, L> {
+  /// Its index, or `None` once all `MAX_AUX` are taken
+  pub fn add_aux(&mut self, c: AuxCircuit) -> Option<usize> {
+    let i = self.aux.push(c);
+    if i.is_none() {
+      log_msg!(self.message_queue, "No room for aux {}", c.name);
+    }
+    i
+  }
+
+  pub fn set_aux(&mut self, i: usize, on: bool) -> bool {
+    let (was_on, needs_pump) = match self.aux.get(i) {
+      Some(c) => (c.on, c.needs_pump),
+      None => return false,
+    };
+    if was_on == on || (on && self.aborted()) {
+      return false;
+    }
+    if on && needs_pump && !self.pump_running() {
+      log_msg!(
+        self.message_queue,
+        "-Protect- {} needs the filter running",
+        self.aux[i].name
+      );
+      return false;
+    }
+
+    if !self.mech.aux_set(i, on) {
+      self.record_error(ErrorCode::Aux);
+      return false;
+    }
+    self.aux_switched(i, on);
+    true
+  }
+
+  pub fn toggle_aux(&mut self, i: usize) -> bool {
+    match self.aux.get(i) {
+      Some(c) => self.set_aux(i, !c.on),
+      None => false,
+    }
+  }
+
+  /// Timers, daily schedules and the pump interlock. `minute_of_day` is
+  /// local time, `None` while the clock isn't set.
+  pub fn aux_tick(&mut self, dt_secs: u64, minute_of_day: Option<u32>) {
+    for i in 0..self.aux.len() {
+      if let Some(on) = minute_of_day.and_then(|m| self.aux[i].schedule_edge(m)) {
+        log_msg!(self.message_queue, "Schedule: {}", self.aux[i].name);
+        self.set_aux(i, on);
+      }
+
+      let c = &mut self.aux[i];
+      if !c.on {
+        continue;
+      }
+      let left = c.remaining_secs.map(|r| r.saturating_sub(dt_secs));
+      c.remaining_secs = left;
+
+      if self.estop_latched {
+        self.set_aux(i, false);
+      } else if self.aux[i].needs_pump && !self.pump_running() {
+        log_msg!(
+          self.message_queue,
+          "-Protect- {} OFF, filter stopped",
+          self.aux[i].name
+        );
+        self.set_aux(i, false);
+      } else if left == Some(0) {
+        log_msg!(self.message_queue, "{} auto OFF", self.aux[i].name);
+        self.set_aux(i, false);
+      }
+    }
+  }
+}
+
+impl<M: ChlorinatorMech, L: Lights> System<M, L> {
+  /// Cell output while `body` is being circulated, 0-100%
+  pub fn set_chlorinator_percent(&mut self, body: PoolOrSpa, percent: u8) -> bool {
+    if percent > 100 {
+      return false;
+    }
+    match body {
+      PoolOrSpa::Pool => self.chlorinator.pool_percent = percent,
+      PoolOrSpa::Spa => self.chlorinator.spa_percent = percent,
+    }
+    log_msg!(
+      self.message_queue,
+      "Chlorinator {} output {}%",
+      body,
+      percent
+    );
+    self.drive_cell()
+  }
+
+  /// 100% output for `super_limit_secs`, then back to the set percentages
+  pub fn set_super_chlorinate(&mut self, on: bool) -> bool {
+    if on == self.chlorinator.super_chlorinating() {
+      return false;
+    }
+    if on {
+      self.chlorinator.super_remaining_secs = Some(self.chlorinator.super_limit_secs);
+      log_msg!(
+        self.message_queue,
+        "Super chlorinate ON for {} hrs",
+        self.chlorinator.super_limit_secs / 3600
+      );
+    } else {
+      self.chlorinator.super_remaining_secs = None;
+      log_msg!(self.message_queue, "Super chlorinate OFF");
+    }
+    self.drive_cell();
+    true
+  }
+
+  pub fn toggle_super_chlorinate(&mut self) -> bool {
+    self.set_super_chlorinate(!self.chlorinator.super_chlorinating())
+  }
+
+  /// Refreshes the readings, counts down super-chlorinate and sets the cell
+  /// for the body being circulated. The cell only runs with the filter; its
+  /// own flow switch covers the gap until the tick after the pump stops.
+  pub fn chlorinator_tick(&mut self, dt_secs: u64) {
+    self.chlorinator.salt_ppm = self.mech.salt_ppm();
+    self.chlorinator.cell_temp_f = self.mech.cell_temp_f();
+
+    if let Some(left) = self.chlorinator.super_remaining_secs {
+      let left = left.saturating_sub(dt_secs);
+      if left == 0 {
+        log_msg!(self.message_queue, "Super chlorinate finished");
+        self.chlorinator.super_remaining_secs = None;
+      } else {
+        self.chlorinator.super_remaining_secs = Some(left);
+      }
+    }
+
+    let salt_low = self.chlorinator.salt_low();
+    if salt_low && !self.has_error(ErrorCode::SaltLow) {
+      self.record_error(ErrorCode::SaltLow);
+    } else if !salt_low && self.has_error(ErrorCode::SaltLow) {
+      log_msg!(self.message_queue, "Salt back above {} ppm", SALT_MIN_PPM);
+      self.clear_error(ErrorCode::SaltLow);
+      self.render_lights();
+    }
+
+    self.drive_cell();
+  }
+
+  // Works out what the cell should be doing now and only tells it when
+  // that changes
+  fn drive_cell(&mut self) -> bool {
+    let c = &self.chlorinator;
+    let held_off = if self.estop_latched {
+      Some("emergency stop")
+    } else if !self.pump_running() {
+      Some("no flow")
+    } else if c.salt_low() {
+      Some("salt low")
+    } else if c.too_cold() {
+      Some("water too cold")
+    } else {
+      None
+    };
+    let target = match held_off {
+      Some(_) => 0,
+      None if c.super_chlorinating() => 100,
+      None => c.percent_for(self.main_valve_orientation),
+    };
+    self.chlorinator.held_off = held_off;
+
+    if target == self.chlorinator.output {
+      return true;
+    }
+    if !self.mech.cell_output(target) {
+      self.record_error(ErrorCode::Chlorinator);
+      return false;
+    }
+    self.chlorinator.output = target;
+    log_msg!(self.message_queue, "Chlorinator output {}%", target);
+    true
+  }
+}
+
+impl<M: DosingMech + ChemSensors, L: Lights> System<M, L> {
+  pub fn set_ph_target(&mut self, ph: f32) -> bool {
+    if !(6.8..=8.0).contains(&ph) {
+      return false;
+    }
+    self.chemistry.ph_target = ph;
+    log_msg!(self.message_queue, "pH target {:.1}", ph);
+    true
+  }
+
+  pub fn set_orp_target(&mut self, mv: f32) -> bool {
+    if !(500.0..=900.0).contains(&mv) {
+      return false;
+    }
+    self.chemistry.orp_target_mv = mv;
+    log_msg!(self.message_queue, "ORP target {:.0} mV", mv);
+    true
+  }
+
+  /// Reads the probes, then starts, runs and stops doses. Doses only run
+  /// with the filter, and daily totals reset at midnight when
+  /// `minute_of_day` is known.
+  pub fn chemistry_tick(&mut self, dt_secs: u64, minute_of_day: Option<u32>) {
+    self.chemistry.ph = self.mech.ph();
+    self.chemistry.orp_mv = self.mech.orp_mv();
+
+    if let Some(m) = minute_of_day {
+      if self.chemistry.last_minute.is_some_and(|last| m < last) {
+        for pump in [DosePump::Acid, DosePump::Chlorine] {
+          let d = self.chemistry.doser_mut(pump);
+          d.dosed_ml_today = 0.0;
+          d.limit_logged = false;
+        }
+        log_msg!(self.message_queue, "Dosing totals reset for the day");
+      }
+      self.chemistry.last_minute = Some(m);
+    }
+
+    self.chemistry.since_logged_secs += dt_secs;
+    if self.chemistry.since_logged_secs >= self.chemistry.log_every_secs {
+      self.chemistry.since_logged_secs = 0;
+      self.log_chemistry();
+    }
+
+    for pump in [DosePump::Acid, DosePump::Chlorine] {
+      self.run_doser(pump, dt_secs);
+    }
+  }
+
+  fn log_chemistry(&mut self) {
+    if let (Some(ph), Some(orp)) = (self.chemistry.ph, self.chemistry.orp_mv) {
+      log_msg!(self.message_queue, "Chem: pH {:.1} ORP {:.0} mV", ph, orp);
+    }
+  }
+
+  fn run_doser(&mut self, pump: DosePump, dt_secs: u64) {
+    let flowing = self.pump_running() && !self.estop_latched;
+    let wants = self.chemistry.wants(pump);
+    let d = self.chemistry.doser_mut(pump);
+
+    if d.on {
+      let ml = d.ml_per_min * dt_secs as f32 / 60.0;
+      d.dosed_ml_today += ml;
+      self.history.note_dose(ml);
+      d.dose_left_secs = d.dose_left_secs.saturating_sub(dt_secs);
+      if !flowing {
+        log_msg!(
+          self.message_queue,
+          "-Protect- {} dose stopped, filter off",
+          pump
+        );
+        self.set_doser(pump, false);
+      } else if d.dose_left_secs == 0 || d.at_limit() {
+        self.set_doser(pump, false);
+      }
+      return;
+    }
+
+    d.rest_left_secs = d.rest_left_secs.saturating_sub(dt_secs);
+    if !flowing || d.rest_left_secs > 0 || !wants {
+      return;
+    }
+    if d.at_limit() {
+      if !d.limit_logged {
+        d.limit_logged = true;
+        let max = d.max_ml_per_day;
+        log_msg!(
+          self.message_queue,
+          "-Protect- {} daily limit of {:.0} ml reached",
+          pump,
+          max
+        );
+      }
+      return;
+    }
+    self.set_doser(pump, true);
+  }
+
+  fn set_doser(&mut self, pump: DosePump, on: bool) -> bool {
+    if !self.mech.dose_pump(pump, on) {
+      self.record_error(ErrorCode::Dosing);
+      return false;
+    }
+    self.doser_switched(pump, on);
+    if on {
+      self.log_chemistry();
+    }
+    true
+  }
+}
+
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
   use super::*;
   use crate::structs::HasOSLights;
   use crate::structs::HasOSMech;
+  use crate::testing::{Call, RecordingLights, RecordingMech};
+
+  fn recording() -> System<RecordingMech, RecordingLights> {
+    System::new(RecordingMech::default(), RecordingLights::default())
+  }
 
   #[test]
   fn filter_does_not_start_if_running() {
@@ -515,7 +1959,10 @@ mod tests {
 
   #[test]
   fn jets_behave_expected() {
-    let mut sys = System::<HasOSMech, HasOSLights>::default();
+    let mut sys = System::<HasOSMech, HasOSLights> {
+      main_valve_orientation: PoolOrSpa::Spa,
+      ..Default::default()
+    };
 
     assert_eq!(sys.jets_on, false);
     sys.toggle_jets();
@@ -668,7 +2115,7 @@ mod tests {
         running_schedule: false,
         quick_clean: false,
       },
-      main_valve_orientation: Some(PoolOrSpa::Spa),
+      main_valve_orientation: Some(ValveMode::Spa),
     });
 
     // Change to Pool
@@ -752,6 +2199,144 @@ mod tests {
       .any(|m| m.contains("-Protect- Turning filter OFF")));
   }
 
+  #[test]
+  fn valve_waits_for_filter_to_stop() {
+    let mut sys = recording();
+    sys.filter.running_schedule = true;
+    sys.filter.quick_clean = true;
+
+    sys.set_main_valves(PoolOrSpa::Spa);
+
+    let log = &sys.mech.log;
+    log.assert_before(Call::QuickClean(false), Call::MainValve(PoolOrSpa::Spa));
+    log.assert_before(Call::FilterSchedule(false), Call::MainValve(PoolOrSpa::Spa));
+    log.assert_sequence(&[Call::MainValve(PoolOrSpa::Spa), Call::Delay(1)]);
+    // Waits run a second at a time: 5 for the pump to stop, 10 of travel
+    assert_eq!(log.count(Call::Delay(1)), 15);
+  }
+
+  #[test]
+  fn auto_spa_call_order() {
+    let mut sys = recording();
+
+    sys.auto_spa(Some(true));
+
+    sys.mech.log.assert_sequence(&[
+      Call::MainValve(PoolOrSpa::Spa),
+      Call::HeaterMode(PoolOrSpa::Spa),
+      Call::QuickClean(true),
+      Call::HeaterOn(true),
+    ]);
+    sys
+      .mech
+      .log
+      .assert_not_called(Call::MainValve(PoolOrSpa::Pool));
+  }
+
+  #[test]
+  fn valve_lights_blink_target_while_moving() {
+    let mut sys = recording();
+
+    sys.set_main_valves(PoolOrSpa::Spa);
+
+    let frames = sys.lights.log.calls();
+    let moving = frames
+      .iter()
+      .find(|f| f.is_on(Light::InProgress))
+      .expect("no in-progress frame");
+    assert_eq!(moving.pattern(Light::MainValveSpa), LightPattern::SlowBlink);
+    assert!(!moving.is_on(Light::MainValvePool));
+
+    let last = frames.last().unwrap();
+    assert_eq!(*last, sys.light_frame());
+    assert_eq!(last.pattern(Light::MainValveSpa), LightPattern::Steady);
+    assert!(!last.is_on(Light::InProgress));
+    assert_eq!(sys.get_light_status()[4], true);
+    assert_eq!(sys.get_light_status()[5], false);
+  }
+
+  #[test]
+  fn default_light_status() {
+    let mut sys = recording();
+    let lit = [
+      Light::MainValvePool,
+      Light::PoolValveSkimmer,
+      Light::HeatModePool,
+    ];
+    let status = sys.get_light_status();
+    assert_eq!(status.len(), Light::COUNT);
+    for (i, on) in status.iter().enumerate() {
+      assert_eq!(*on, lit.iter().any(|l| *l as usize == i), "light-{}", i);
+    }
+    for l in lit {
+      assert_eq!(sys.light_frame().pattern(l), LightPattern::Steady);
+    }
+  }
+
+  #[test]
+  fn schedule_pulses_while_waiting_on_valves() {
+    let mut sys = recording();
+    sys.filter.running_schedule = true;
+
+    sys.toggle_main_valves();
+
+    let frames = sys.lights.log.calls();
+    let pending = frames
+      .iter()
+      .position(|f| f.pattern(Light::FilterSchedule) == LightPattern::Pulse)
+      .expect("schedule never pulsed");
+    assert!(frames[pending..]
+      .iter()
+      .any(|f| f.pattern(Light::MainValveSpa) == LightPattern::SlowBlink));
+    assert_eq!(
+      sys.light_frame().pattern(Light::FilterSchedule),
+      LightPattern::Steady
+    );
+  }
+
+  #[test]
+  fn heater_relay_follows_pump() {
+    let mut sys = recording();
+
+    sys.set_heater_on(true);
+    assert_eq!(sys.heater_relay, false);
+    sys.mech.log.assert_not_called(Call::HeaterOn(true));
+
+    sys.start_quick_clean();
+    assert_eq!(sys.heater_relay, true);
+
+    sys.stop_filter();
+    assert_eq!(sys.heater_relay, false);
+    sys
+      .mech
+      .log
+      .assert_before(Call::HeaterOn(false), Call::QuickClean(false));
+  }
+
+  #[test]
+  fn auto_spa_stops_quick_clean_before_restoring_valves() {
+    let mut sys = recording();
+
+    sys.auto_spa(None);
+
+    assert_eq!(sys.pump_running(), false);
+    sys
+      .mech
+      .log
+      .assert_before(Call::QuickClean(false), Call::MainValve(PoolOrSpa::Pool));
+  }
+
+  #[test]
+  fn auto_spa_restores_running_schedule() {
+    let mut sys = recording();
+    sys.toggle_filter_schedule();
+
+    sys.auto_spa(None);
+
+    assert_eq!(sys.main_valve_orientation, PoolOrSpa::Pool);
+    assert_eq!(sys.filter.running_schedule, true);
+  }
+
   #[test]
   fn valve_change_stops_both_filter_types() {
     let mut sys = System::<HasOSMech, HasOSLights> {
@@ -773,7 +2358,10 @@ mod tests {
   // Message queue tests
   #[test]
   fn jets_toggle_logs_correct_messages() {
-    let mut sys = System::<HasOSMech, HasOSLights>::default();
+    let mut sys = System::<HasOSMech, HasOSLights> {
+      main_valve_orientation: PoolOrSpa::Spa,
+      ..Default::default()
+    };
 
     sys.toggle_jets();
     let mut messages = Vec::new();
@@ -809,6 +2397,19 @@ mod tests {
     assert!(messages.iter().any(|m| m.contains("Heater OFF")));
   }
 
+  #[test]
+  fn cancel_routine_ends_spa_session() {
+    let mut sys = System::<HasOSMech, HasOSLights>::default();
+
+    sys.auto_spa(Some(true));
+    sys.toggle_jets();
+    sys.perform(Action::CancelRoutine);
+
+    assert_eq!(sys.jets_on, false);
+    assert_eq!(sys.heater.on, false);
+    assert_eq!(sys.filter.quick_clean, false);
+  }
+
   #[test]
   fn auto_spa_logs_start_and_complete_messages() {
     let mut sys = System::<HasOSMech, HasOSLights>::default();
@@ -825,4 +2426,646 @@ mod tests {
       .any(|m| m.contains("Starting Spa... Enjoy! xo")));
     assert!(messages.iter().any(|m| m.contains("Complete: Spa Mode")));
   }
+
+  // Raises the stop on the first delay, as if the button was hit while a
+  // routine was waiting
+  struct StopMidway {
+    inner: RecordingMech,
+    stop: StopSignal,
+  }
+
+  impl Mech for StopMidway {
+    fn delay_secs(&self, secs: u64) {
+      self.inner.delay_secs(secs);
+      self.stop.raise();
+    }
+    fn set_quick_clean(&self, v: bool) -> bool {
+      self.inner.set_quick_clean(v)
+    }
+    fn mech_set_filter_sched(&self, v: bool) -> bool {
+      self.inner.mech_set_filter_sched(v)
+    }
+    fn mech_pool_valve_to(&self, p: PoolValve) -> bool {
+      self.inner.mech_pool_valve_to(p)
+    }
+    fn mech_main_valve_to(&self, m: PoolOrSpa) -> bool {
+      self.inner.mech_main_valve_to(m)
+    }
+    fn heater_on_toggle(&self, b: bool) -> bool {
+      self.inner.heater_on_toggle(b)
+    }
+    fn heater_mode_toggle(&self, m: PoolOrSpa) -> bool {
+      self.inner.heater_mode_toggle(m)
+    }
+    fn jets_on_toggle(&self, b: bool) -> bool {
+      self.inner.jets_on_toggle(b)
+    }
+  }
+
+  #[test]
+  fn emergency_stop_deenergises_and_latches() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.auto_spa(Some(true));
+    sys.toggle_jets();
+    let blower = sys.add_aux(AuxCircuit::new("Blower")).unwrap();
+    sys.set_aux(blower, true);
+    sys.set_super_chlorinate(true);
+    sys.set_doser(DosePump::Acid, true);
+    sys.mech.log.clear();
+
+    assert_eq!(sys.emergency_stop(), true);
+    sys.mech.log.assert_exact(&[
+      Call::HeaterOn(false),
+      Call::Jets(false),
+      Call::QuickClean(false),
+      Call::FilterSchedule(false),
+      Call::Aux(blower, false),
+      Call::Cell(0),
+      Call::Dose(DosePump::Acid, false),
+    ]);
+    assert_eq!(sys.heater_relay, false);
+    assert_eq!(sys.jets_on, false);
+    assert_eq!(sys.pump_running(), false);
+    assert_eq!(sys.aux[blower].on, false);
+    assert_eq!(sys.chlorinator.output, 0);
+    assert_eq!(sys.chemistry.doser_mut(DosePump::Acid).on, false);
+    assert_eq!(
+      sys.light_frame().pattern(Light::Fault),
+      LightPattern::FastBlink
+    );
+
+    // Nothing starts, and clearing errors doesn't unlatch
+    assert_eq!(sys.toggle_jets(), false);
+    assert_eq!(sys.start_quick_clean(), false);
+    sys.clear_errors();
+    assert!(sys.has_error(ErrorCode::EmergencyStop));
+    sys.mech.log.assert_not_called(Call::Jets(true));
+
+    assert_eq!(sys.acknowledge_emergency_stop(), true);
+    assert!(!sys.has_error(ErrorCode::EmergencyStop));
+    assert_eq!(sys.toggle_jets(), true);
+  }
+
+  #[test]
+  fn every_error_code_fits() {
+    let mut sys = recording();
+    for code in ErrorCode::ALL {
+      sys.record_error(code);
+    }
+    for code in ErrorCode::ALL {
+      assert!(sys.has_error(code), "{} dropped", code);
+    }
+  }
+
+  #[test]
+  fn stop_aborts_auto_spa_while_valves_move() {
+    let stop = StopSignal::default();
+    let mech = StopMidway {
+      inner: RecordingMech::default(),
+      stop: stop.clone(),
+    };
+    let mut sys = System::new(mech, RecordingLights::default());
+    sys.stop = stop;
+
+    sys.auto_spa(None);
+
+    assert_eq!(sys.estop_latched, true);
+    assert_eq!(sys.mech.inner.log.count(Call::Delay(1)), 1);
+    sys.mech.inner.log.assert_not_called(Call::HeaterOn(true));
+    sys.mech.inner.log.assert_not_called(Call::QuickClean(true));
+    assert_eq!(sys.main_valve_orientation, PoolOrSpa::Spa);
+    assert_eq!(sys.heater.on, false);
+    assert_eq!(sys.auto_spa_mode, false);
+    assert!(sys.prev_state.is_none());
+    assert_eq!(sys.in_progress, false);
+  }
+
+  #[test]
+  fn emergency_stop_needs_only_a_plain_mech() {
+    // StopMidway has no aux, cell or dosing outputs
+    let mut sys = System::new(
+      StopMidway {
+        inner: RecordingMech::default(),
+        stop: StopSignal::default(),
+      },
+      RecordingLights::default(),
+    );
+    sys.internal_test = true;
+    sys.toggle_filter_schedule();
+    sys.mech.inner.log.clear();
+
+    assert_eq!(sys.emergency_stop(), true);
+    assert_eq!(sys.estop_latched, true);
+    assert_eq!(sys.pump_running(), false);
+    sys.mech.inner.log.assert_exact(&[
+      Call::HeaterOn(false),
+      Call::Jets(false),
+      Call::QuickClean(false),
+      Call::FilterSchedule(false),
+    ]);
+    assert_eq!(sys.emergency_stop(), false);
+  }
+
+  #[test]
+  fn vacuum_scene_stops_heat_and_pump_before_moving_valves() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.toggle_filter_schedule();
+    sys.set_heater_on(true);
+    sys.mech.log.clear();
+
+    assert!(sys.apply_scene(&Scene::builtin("vacuum").unwrap()));
+
+    let log = &sys.mech.log;
+    log.assert_sequence(&[
+      Call::HeaterOn(false),
+      Call::FilterSchedule(false),
+      Call::PoolValve(PoolValve::Vacuum),
+      Call::QuickClean(true),
+      // Duration over, back to how it was
+      Call::QuickClean(false),
+      Call::PoolValve(PoolValve::Skimmer),
+      Call::FilterSchedule(true),
+      Call::HeaterOn(true),
+    ]);
+    assert_eq!(sys.pool_valve_orientation, PoolValve::Skimmer);
+    assert_eq!(sys.filter.running_schedule, true);
+    assert_eq!(sys.heater.on, true);
+    assert_eq!(sys.active_scene, None);
+  }
+
+  #[test]
+  fn scene_without_filter_keeps_pump_running_across_valve_move() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.toggle_filter_schedule();
+    let mut scene = Scene::named("spa side");
+    scene.set("main_valves", "spa");
+
+    assert!(sys.apply_scene(&scene));
+
+    sys.mech.log.assert_sequence(&[
+      Call::FilterSchedule(false),
+      Call::MainValve(PoolOrSpa::Spa),
+      Call::FilterSchedule(true),
+    ]);
+    assert_eq!(sys.main_valve_orientation, PoolOrSpa::Spa);
+    assert_eq!(sys.filter.running_schedule, true);
+  }
+
+  #[test]
+  fn jets_need_spa_valves_unless_overridden() {
+    let mut sys = recording();
+    sys.internal_test = true;
+
+    assert_eq!(sys.toggle_jets(), false);
+    sys.mech.log.assert_not_called(Call::Jets(true));
+
+    sys.jets_override = true;
+    assert_eq!(sys.toggle_jets(), true);
+    assert_eq!(sys.jets_on, true);
+  }
+
+  #[test]
+  fn jets_stop_when_valves_leave_spa() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.set_main_valves(PoolOrSpa::Spa);
+    sys.toggle_jets();
+
+    sys.toggle_main_valves();
+
+    assert_eq!(sys.main_valve_orientation, PoolOrSpa::Pool);
+    assert_eq!(sys.jets_on, false);
+    sys
+      .mech
+      .log
+      .assert_before(Call::MainValve(PoolOrSpa::Pool), Call::Jets(false));
+  }
+
+  #[test]
+  fn jets_shut_off_after_limit() {
+    let mut sys = recording();
+    sys.main_valve_orientation = PoolOrSpa::Spa;
+    sys.jets_limit_secs = 600;
+    sys.toggle_jets();
+
+    sys.tick(599);
+    assert_eq!(sys.jets_remaining_secs, Some(1));
+    assert_eq!(sys.jets_on, true);
+
+    sys.tick(5);
+    assert_eq!(sys.jets_on, false);
+    assert_eq!(sys.jets_remaining_secs, None);
+    let messages: Vec<String> = core::iter::from_fn(|| sys.pop_message()).collect();
+    assert!(messages.iter().any(|m| m == "Jets auto OFF after 10 min"));
+  }
+
+  #[test]
+  fn spillover_moves_return_valve_with_pump_stopped() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.set_main_valves(PoolOrSpa::Spa);
+    sys.toggle_filter_schedule();
+    sys.mech.log.clear();
+
+    sys.toggle_spillover();
+
+    sys.mech.log.assert_exact(&[
+      Call::FilterSchedule(false),
+      Call::MainValve(PoolOrSpa::Pool),
+      Call::ReturnValve(PoolOrSpa::Spa),
+      Call::FilterSchedule(true),
+    ]);
+    assert_eq!(sys.valve_mode(), ValveMode::Spillover);
+    let frame = sys.light_frame();
+    assert!(frame.is_on(Light::MainValveSpa) && frame.is_on(Light::MainValvePool));
+
+    sys.toggle_spillover();
+    assert_eq!(sys.valve_mode(), ValveMode::Pool);
+    assert_eq!(sys.filter.running_schedule, true);
+  }
+
+  #[test]
+  fn spillover_needs_a_separate_return_actuator() {
+    let mut sys = System::new(fault::FaultyMech::new(HasOSMech), HasOSLights);
+    sys.internal_test = true;
+    sys.mech.faults.apply("fail return_valve");
+
+    assert_eq!(sys.set_valve_mode(ValveMode::Spillover), false);
+    assert_eq!(sys.valve_mode(), ValveMode::Pool);
+    assert!(sys.has_error(ErrorCode::MainValve));
+  }
+
+  #[test]
+  fn aux_circuit_times_out() {
+    let mut sys = recording();
+    let blower = sys
+      .add_aux(AuxCircuit::new("Blower").limit_mins(30))
+      .unwrap();
+
+    assert_eq!(sys.toggle_aux(blower), true);
+    sys.aux_tick(29 * 60, None);
+    assert_eq!(sys.aux[blower].on, true);
+    assert_eq!(sys.aux[blower].remaining_secs, Some(60));
+
+    sys.aux_tick(60, None);
+    assert_eq!(sys.aux[blower].on, false);
+    sys
+      .mech
+      .log
+      .assert_sequence(&[Call::Aux(0, true), Call::Aux(0, false)]);
+    assert_eq!(sys.toggle_aux(7), false);
+  }
+
+  #[test]
+  fn aux_circuit_needing_pump_follows_filter() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    let cleaner = sys
+      .add_aux(
+        AuxCircuit::new("Cleaner")
+          .needs_pump()
+          .daily(9 * 60, 12 * 60),
+      )
+      .unwrap();
+
+    // Window opens with the pump off, nothing happens
+    sys.aux_tick(60, Some(9 * 60));
+    assert_eq!(sys.aux[cleaner].on, false);
+
+    sys.toggle_filter_schedule();
+    assert_eq!(sys.set_aux(cleaner, true), true);
+    sys.stop_filter();
+    sys.aux_tick(60, Some(10 * 60));
+
+    assert_eq!(sys.aux[cleaner].on, false);
+    sys
+      .mech
+      .log
+      .assert_before(Call::FilterSchedule(false), Call::Aux(0, false));
+  }
+
+  #[test]
+  fn light_show_resyncs_then_steps_round() {
+    let mut sys = recording();
+    sys.internal_test = true;
+
+    // Unknown at power-up, so reset to the first show before counting.
+    // The light goes by timing, so it's kept even in test mode.
+    assert_eq!(sys.select_light_show(2), true);
+    sys.mech.log.assert_sequence(&[
+      Call::PoolLight(false),
+      Call::Delay(1),
+      Call::PoolLight(true),
+      Call::PoolLight(false),
+      Call::PoolLight(true),
+      Call::PoolLight(false),
+      Call::PoolLight(true),
+    ]);
+    assert_eq!(sys.mech.log.count(Call::PoolLight(false)), 3);
+    assert_eq!(
+      sys.mech.log.count(Call::Delay(1)) as u64,
+      RESYNC_OFF_SECS + 2 * (CYCLE_OFF_SECS + CYCLE_ON_SECS)
+    );
+    assert_eq!(sys.pool_light.show, Some(2));
+
+    // Wraps past the last show
+    sys.mech.log.clear();
+    assert_eq!(sys.select_light_show(1), true);
+    assert_eq!(sys.mech.log.count(Call::PoolLight(false)), SHOWS.len() - 1);
+    assert_eq!(sys.pool_light.show, Some(1));
+    assert_eq!(sys.pool_light.on, true);
+  }
+
+  #[test]
+  fn failed_light_cycle_loses_track_of_show() {
+    let mut sys = System::new(
+      fault::FaultyMech::new(RecordingMech::default()),
+      RecordingLights::default(),
+    );
+    sys.internal_test = true;
+    assert_eq!(sys.resync_pool_light(), true);
+
+    // Power doesn't come back on the second cycle
+    sys.mech.faults.apply("nth 4");
+    assert_eq!(sys.select_light_show(3), false);
+    assert_eq!(sys.pool_light.show, None);
+    assert_eq!(sys.pool_light.on, false);
+    assert!(sys.has_error(ErrorCode::PoolLight));
+
+    sys.mech.faults.apply("clear");
+    assert_eq!(sys.select_light_show(3), true);
+    assert_eq!(sys.pool_light.show, Some(3));
+  }
+
+  #[test]
+  fn chlorinator_follows_body_and_flow() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.mech.salt_ppm.set(Some(3200));
+
+    // No flow, nothing to do
+    sys.chlorinator_tick(1);
+    sys.mech.log.assert_not_called(Call::Cell(50));
+    assert_eq!(sys.chlorinator.held_off, Some("no flow"));
+
+    sys.toggle_filter_schedule();
+    sys.chlorinator_tick(1);
+    assert_eq!(sys.chlorinator.output, 50);
+
+    sys.toggle_main_valves();
+    sys.chlorinator_tick(1);
+    assert_eq!(sys.chlorinator.output, 20);
+
+    sys.stop_filter();
+    sys.chlorinator_tick(1);
+    sys.mech.log.assert_sequence(&[
+      Call::FilterSchedule(true),
+      Call::Cell(50),
+      Call::MainValve(PoolOrSpa::Spa),
+      Call::Cell(20),
+      Call::FilterSchedule(false),
+      Call::Cell(0),
+    ]);
+  }
+
+  #[test]
+  fn super_chlorinate_times_out_and_low_salt_faults() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.mech.salt_ppm.set(Some(3200));
+    sys.toggle_filter_schedule();
+
+    sys.set_super_chlorinate(true);
+    sys.chlorinator_tick(60);
+    assert_eq!(sys.chlorinator.output, 100);
+    sys.chlorinator_tick(24 * 60 * 60);
+    assert_eq!(sys.chlorinator.super_chlorinating(), false);
+    assert_eq!(sys.chlorinator.output, 50);
+
+    sys.mech.salt_ppm.set(Some(2000));
+    sys.chlorinator_tick(60);
+    assert_eq!(sys.chlorinator.output, 0);
+    assert!(sys.has_error(ErrorCode::SaltLow));
+
+    sys.mech.salt_ppm.set(Some(3000));
+    sys.chlorinator_tick(60);
+    assert_eq!(sys.chlorinator.output, 50);
+    assert!(!sys.has_error(ErrorCode::SaltLow));
+  }
+
+  #[test]
+  fn acid_doses_only_with_filter_then_waits_to_mix() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.mech.ph.set(Some(7.9));
+
+    sys.chemistry_tick(60, None);
+    sys
+      .mech
+      .log
+      .assert_not_called(Call::Dose(DosePump::Acid, true));
+
+    sys.toggle_filter_schedule();
+    sys.chemistry_tick(1, None);
+    sys.chemistry_tick(60, None);
+    assert_eq!(sys.chemistry.acid.on, false);
+    assert_eq!(sys.chemistry.acid.dosed_ml_today, 50.0);
+
+    // Still high, but the last dose hasn't mixed in yet
+    sys.chemistry_tick(60, None);
+    assert_eq!(sys.mech.log.count(Call::Dose(DosePump::Acid, true)), 1);
+    sys.chemistry_tick(15 * 60, None);
+    assert_eq!(sys.chemistry.acid.on, true);
+
+    sys.stop_filter();
+    sys.chemistry_tick(1, None);
+    assert_eq!(sys.chemistry.acid.on, false);
+    sys.mech.log.assert_sequence(&[
+      Call::Dose(DosePump::Acid, true),
+      Call::FilterSchedule(false),
+      Call::Dose(DosePump::Acid, false),
+    ]);
+    sys
+      .mech
+      .log
+      .assert_not_called(Call::Dose(DosePump::Chlorine, true));
+  }
+
+  #[test]
+  fn chlorine_stops_at_daily_limit_until_midnight() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.mech.orp_mv.set(Some(550.0));
+    sys.chemistry.chlorine.max_ml_per_day = 150.0;
+    sys.chemistry.mix_secs = 0;
+    sys.toggle_filter_schedule();
+
+    for _ in 0..6 {
+      sys.chemistry_tick(60, Some(23 * 60));
+    }
+    assert_eq!(sys.mech.log.count(Call::Dose(DosePump::Chlorine, true)), 2);
+    assert!(sys.chemistry.chlorine.at_limit());
+
+    sys.chemistry_tick(60, Some(0));
+    assert_eq!(sys.chemistry.chlorine.on, true);
+    assert_eq!(sys.chemistry.chlorine.dosed_ml_today, 0.0);
+  }
+
+  #[test]
+  fn history_counts_runtime_and_doses() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.mech.ph.set(Some(7.9));
+    sys.toggle_filter_schedule();
+
+    for _ in 0..2 {
+      sys.chemistry_tick(30, None);
+      sys.history_tick(30);
+    }
+
+    let minute = sys.history.minutes.latest().unwrap();
+    assert_eq!(minute.pump_secs, 60);
+    assert_eq!(minute.heater_secs, 0);
+    assert_eq!(minute.dosed_ml, 25.0);
+    assert_eq!(minute.ph, Some(7.9));
+  }
+
+  #[test]
+  fn filter_reminder_comes_due_and_is_acknowledged() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.maintenance.intervals[Reminder::CleanFilter as usize] = 2;
+    sys.toggle_filter_schedule();
+
+    sys.tick(3600);
+    assert_eq!(sys.light_frame().is_on(Light::Service), false);
+    sys.tick(3600);
+    assert_eq!(sys.maintenance.pump_secs, 7200);
+    assert_eq!(sys.light_frame().is_on(Light::Service), true);
+    assert!(sys
+      .message_queue
+      .iter()
+      .any(|m| m.get_str().starts_with("-Service- Clean the filter")));
+
+    assert_eq!(sys.acknowledge_reminder(Reminder::CleanFilter), true);
+    assert_eq!(sys.light_frame().is_on(Light::Service), false);
+    assert_eq!(sys.acknowledge_reminder(Reminder::CleanFilter), false);
+  }
+
+  #[test]
+  fn valve_moves_are_counted() {
+    let mut sys = recording();
+    sys.internal_test = true;
+
+    sys.set_valve_mode(ValveMode::Spillover);
+    sys.set_valve_mode(ValveMode::Spa);
+    sys.set_pool_valve(PoolValve::Vacuum);
+    assert_eq!(sys.maintenance.valve_cycles, 3);
+  }
+
+  #[test]
+  fn filter_pressure_learns_baseline_then_warns() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    let psi = |v| Readings {
+      filter_psi: Some(v),
+      ..Default::default()
+    };
+    sys.toggle_filter_schedule();
+
+    sys.mech.readings.set(psi(12.0));
+    sys.pressure_tick(60);
+    sys.pressure_tick(240);
+    assert_eq!(sys.pressure.baseline_psi, Some(12.0));
+
+    sys.mech.readings.set(psi(19.0));
+    sys.pressure_tick(60);
+    assert_eq!(sys.pressure.backwash_due, false);
+    sys.mech.readings.set(psi(21.0));
+    sys.pressure_tick(60);
+    assert_eq!(sys.pressure.backwash_due, true);
+    assert_eq!(sys.light_frame().is_on(Light::Service), true);
+
+    sys.relearn_filter_pressure();
+    assert_eq!(sys.light_frame().is_on(Light::Service), false);
+    assert_eq!(sys.pump_running(), true);
+  }
+
+  #[test]
+  fn filter_stops_when_it_never_primes() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.mech.readings.set(Readings {
+      filter_psi: Some(0.5),
+      ..Default::default()
+    });
+    sys.toggle_filter_schedule();
+
+    sys.pressure_tick(60);
+    assert_eq!(sys.pump_running(), true);
+    sys.pressure_tick(60);
+    assert_eq!(sys.pump_running(), false);
+    assert!(sys.has_error(ErrorCode::LostPrime));
+    sys
+      .mech
+      .log
+      .assert_sequence(&[Call::FilterSchedule(true), Call::FilterSchedule(false)]);
+  }
+
+  #[test]
+  fn backwash_turns_multiport_with_pump_off_and_restores() {
+    let mut sys = recording();
+    sys.internal_test = true;
+    sys.backwash.actuated = true;
+    sys.backwash.backwash_secs = 3;
+    sys.backwash.rinse_secs = 2;
+    sys.toggle_filter_schedule();
+    sys.set_heater_on(true);
+    sys.maintenance.pump_secs = 200 * 3600;
+    sys.pressure.baseline_psi = Some(12.0);
+    sys.mech.log.clear();
+
+    assert!(sys.backwash());
+    sys.mech.log.assert_sequence(&[
+      Call::HeaterOn(false),
+      Call::FilterSchedule(false),
+      Call::Multiport(Multiport::Backwash),
+      Call::QuickClean(true),
+      Call::QuickClean(false),
+      Call::Multiport(Multiport::Rinse),
+      Call::QuickClean(true),
+      Call::QuickClean(false),
+      Call::Multiport(Multiport::Filter),
+      Call::FilterSchedule(true),
+      Call::HeaterOn(true),
+    ]);
+    // Run times are skipped like every other wait in tests
+    sys.mech.log.assert_not_called(Call::Delay(1));
+    assert_eq!(sys.filter.running_schedule, true);
+    assert_eq!(sys.heater.on, true);
+    assert_eq!(sys.backwash.running(), false);
+    assert_eq!(sys.maintenance.is_due(Reminder::Backwash), false);
+    assert_eq!(sys.pressure.baseline_psi, None);
+    assert_eq!(sys.backwash.last_history_secs, Some(sys.history.now_secs()));
+  }
+
+  #[test]
+  fn stuck_multiport_leaves_pump_off() {
+    let mut sys = System::new(
+      fault::FaultyMech::new(RecordingMech::default()),
+      RecordingLights::default(),
+    );
+    sys.internal_test = true;
+    sys.backwash.actuated = true;
+    sys.toggle_filter_schedule();
+    sys.mech.faults.apply("fail multiport");
+
+    assert!(!sys.backwash());
+    assert!(sys.has_error(ErrorCode::Multiport));
+    assert_eq!(sys.pump_running(), false);
+    assert_eq!(sys.backwash.position, Multiport::Filter);
+  }
 }
@@ -0,0 +1,196 @@
+// maintenance.rs - Lifetime run counters and service reminders
+use core::fmt;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Reminder {
+  CleanFilter = 0,
+  ServiceHeater = 1,
+  ServiceValves = 2,
+  Backwash = 3,
+}
+
+impl Reminder {
+  pub const COUNT: usize = 4;
+  pub const ALL: [Reminder; Reminder::COUNT] = [
+    Reminder::CleanFilter,
+    Reminder::ServiceHeater,
+    Reminder::ServiceValves,
+    Reminder::Backwash,
+  ];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Reminder::CleanFilter => "clean_filter",
+      Reminder::ServiceHeater => "service_heater",
+      Reminder::ServiceValves => "service_valves",
+      Reminder::Backwash => "backwash",
+    }
+  }
+
+  pub fn from_name(s: &str) -> Option<Reminder> {
+    Reminder::ALL.iter().copied().find(|r| r.name() == s)
+  }
+
+  /// What the interval is counted in
+  pub fn unit(self) -> &'static str {
+    match self {
+      Reminder::CleanFilter | Reminder::Backwash => "pump hours",
+      Reminder::ServiceHeater => "heater hours",
+      Reminder::ServiceValves => "valve cycles",
+    }
+  }
+}
+
+impl fmt::Display for Reminder {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Reminder::CleanFilter => write!(f, "Clean the filter"),
+      Reminder::ServiceHeater => write!(f, "Service the heater"),
+      Reminder::ServiceValves => write!(f, "Service the valve actuators"),
+      Reminder::Backwash => write!(f, "Backwash the filter"),
+    }
+  }
+}
+
+/// Counted up by `System::tick` and the valve moves; meant to outlive the
+/// process, see `fields` and `set`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Maintenance {
+  pub pump_secs: u64,
+  pub heater_secs: u64,
+  pub jets_secs: u64,
+  pub valve_cycles: u64,
+  /// Per `Reminder`, in its `unit`; 0 never reminds
+  pub intervals: [u64; Reminder::COUNT],
+  /// Per `Reminder`, the counter when it was last acknowledged
+  pub serviced_at: [u64; Reminder::COUNT],
+  // Already logged, so a due reminder is announced once
+  announced: [bool; Reminder::COUNT],
+}
+
+impl Default for Maintenance {
+  fn default() -> Self {
+    Maintenance {
+      pump_secs: 0,
+      heater_secs: 0,
+      jets_secs: 0,
+      valve_cycles: 0,
+      intervals: [500, 2000, 5000, 150],
+      serviced_at: [0; Reminder::COUNT],
+      announced: [false; Reminder::COUNT],
+    }
+  }
+}
+
+impl Maintenance {
+  /// The counter `r` goes by, in its `unit`
+  pub fn counter(&self, r: Reminder) -> u64 {
+    match r {
+      Reminder::CleanFilter | Reminder::Backwash => self.pump_secs / 3600,
+      Reminder::ServiceHeater => self.heater_secs / 3600,
+      Reminder::ServiceValves => self.valve_cycles,
+    }
+  }
+
+  pub fn since_service(&self, r: Reminder) -> u64 {
+    self.counter(r).saturating_sub(self.serviced_at[r as usize])
+  }
+
+  pub fn is_due(&self, r: Reminder) -> bool {
+    let every = self.intervals[r as usize];
+    every > 0 && self.since_service(r) >= every
+  }
+
+  pub fn any_due(&self) -> bool {
+    Reminder::ALL.iter().any(|r| self.is_due(*r))
+  }
+
+  /// True the first time `r` is seen due since it was last acknowledged
+  pub(crate) fn newly_due(&mut self, r: Reminder) -> bool {
+    let due = self.is_due(r);
+    let first = due && !self.announced[r as usize];
+    self.announced[r as usize] = due;
+    first
+  }
+
+  /// Starts `r`'s interval again from now
+  pub fn acknowledge(&mut self, r: Reminder) {
+    self.serviced_at[r as usize] = self.counter(r);
+    self.announced[r as usize] = false;
+  }
+
+  /// Everything worth persisting, read back with `set`
+  pub fn fields(&self) -> [(&'static str, u64); 12] {
+    [
+      ("pump_secs", self.pump_secs),
+      ("heater_secs", self.heater_secs),
+      ("jets_secs", self.jets_secs),
+      ("valve_cycles", self.valve_cycles),
+      ("clean_filter_every", self.intervals[0]),
+      ("service_heater_every", self.intervals[1]),
+      ("service_valves_every", self.intervals[2]),
+      ("backwash_every", self.intervals[3]),
+      ("clean_filter_at", self.serviced_at[0]),
+      ("service_heater_at", self.serviced_at[1]),
+      ("service_valves_at", self.serviced_at[2]),
+      ("backwash_at", self.serviced_at[3]),
+    ]
+  }
+
+  pub fn set(&mut self, key: &str, value: u64) -> bool {
+    let slot = match key {
+      "pump_secs" => &mut self.pump_secs,
+      "heater_secs" => &mut self.heater_secs,
+      "jets_secs" => &mut self.jets_secs,
+      "valve_cycles" => &mut self.valve_cycles,
+      "clean_filter_every" => &mut self.intervals[0],
+      "service_heater_every" => &mut self.intervals[1],
+      "service_valves_every" => &mut self.intervals[2],
+      "backwash_every" => &mut self.intervals[3],
+      "clean_filter_at" => &mut self.serviced_at[0],
+      "service_heater_at" => &mut self.serviced_at[1],
+      "service_valves_at" => &mut self.serviced_at[2],
+      "backwash_at" => &mut self.serviced_at[3],
+      _ => return false,
+    };
+    *slot = value;
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fields_round_trip() {
+    let mut m = Maintenance {
+      pump_secs: 7200,
+      valve_cycles: 42,
+      ..Default::default()
+    };
+    m.acknowledge(Reminder::ServiceValves);
+
+    let mut back = Maintenance::default();
+    for (k, v) in m.fields() {
+      assert!(back.set(k, v));
+    }
+    assert_eq!(back, m);
+    assert!(!back.set("bogus", 1));
+  }
+
+  #[test]
+  fn due_is_announced_once_per_interval() {
+    let mut m = Maintenance::default();
+    m.intervals[Reminder::ServiceValves as usize] = 2;
+
+    m.valve_cycles = 2;
+    assert!(m.newly_due(Reminder::ServiceValves));
+    assert!(!m.newly_due(Reminder::ServiceValves));
+
+    m.acknowledge(Reminder::ServiceValves);
+    assert!(!m.is_due(Reminder::ServiceValves));
+    m.valve_cycles = 4;
+    assert!(m.newly_due(Reminder::ServiceValves));
+  }
+}
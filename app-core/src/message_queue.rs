@@ -28,6 +28,12 @@ impl Message {
   }
 }
 
+impl Default for Message {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 impl FmtWrite for Message {
   fn write_str(&mut self, s: &str) -> core::fmt::Result {
     let bytes = s.as_bytes();
@@ -83,6 +89,11 @@ impl<const N: usize> MessageQueue<N> {
     }
   }
 
+  /// Oldest to newest, without removing anything
+  pub fn iter(&self) -> impl Iterator<Item = &Message> + '_ {
+    (0..self.count).filter_map(move |i| self.messages[(self.tail + i) % N].as_ref())
+  }
+
   pub fn len(&self) -> usize {
     self.count
   }
@@ -103,6 +114,12 @@ impl<const N: usize> MessageQueue<N> {
   }
 }
 
+impl<const N: usize> Default for MessageQueue<N> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 #[macro_export]
 macro_rules! log_msg {
     ($queue:expr, $($arg:tt)*) => {{
@@ -146,6 +163,9 @@ mod tests {
 
     // Should only have last 3 messages
     assert_eq!(queue.len(), 3);
+    let kept: Vec<&str> = queue.iter().map(|m| m.get_str()).collect();
+    assert_eq!(kept, ["Message 2", "Message 3", "Message 4"]);
+
     assert_eq!(queue.pop().unwrap().get_str(), "Message 2");
     assert_eq!(queue.pop().unwrap().get_str(), "Message 3");
     assert_eq!(queue.pop().unwrap().get_str(), "Message 4");
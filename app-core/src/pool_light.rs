@@ -0,0 +1,90 @@
+// pool_light.rs - Color LED pool light driven by power-cycling its supply
+//
+// The light steps to its next show every time power comes back within a
+// couple of seconds. Off for 11-14 seconds and back on resets it to the
+// first show; anything longer and it remembers where it was.
+
+/// Shows in the order the light steps through them
+pub const SHOWS: [&str; 17] = [
+  "Voodoo Lounge",
+  "Deep Blue Sea",
+  "Royal Blue",
+  "Afternoon Skies",
+  "Aqua Green",
+  "Emerald",
+  "Cloud White",
+  "Warm Red",
+  "Flamingo",
+  "Vivid Violet",
+  "Sangria",
+  "Twilight",
+  "Tranquility",
+  "Gemstone",
+  "USA",
+  "Mardi Gras",
+  "Cool Cabaret",
+];
+
+/// Off time that steps to the next show
+pub const CYCLE_OFF_SECS: u64 = 1;
+/// Time to wait after power comes back before the next cycle
+pub const CYCLE_ON_SECS: u64 = 1;
+/// Off time that resets to the first show
+pub const RESYNC_OFF_SECS: u64 = 12;
+
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct PoolLight {
+  pub on: bool,
+  /// Index into `SHOWS`, `None` once a sequence was cut short and the light
+  /// could be anywhere until resynced
+  pub show: Option<usize>,
+}
+
+impl PoolLight {
+  pub fn show_name(&self) -> &'static str {
+    match self.show {
+      Some(i) => SHOWS[i],
+      None => "unknown",
+    }
+  }
+
+  /// Power cycles needed to get from the current show to `target`
+  pub fn cycles_to(&self, target: usize) -> Option<usize> {
+    let from = self.show?;
+    Some((target + SHOWS.len() - from) % SHOWS.len())
+  }
+}
+
+/// Show index from a name (any case) or a number
+pub fn find_show(s: &str) -> Option<usize> {
+  let s = s.trim();
+  if let Ok(i) = s.parse::<usize>() {
+    return (i < SHOWS.len()).then_some(i);
+  }
+  SHOWS.iter().position(|n| n.eq_ignore_ascii_case(s))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cycles_wrap_around() {
+    let light = PoolLight {
+      on: true,
+      show: Some(15),
+    };
+    assert_eq!(light.cycles_to(16), Some(1));
+    assert_eq!(light.cycles_to(1), Some(3));
+    assert_eq!(light.cycles_to(15), Some(0));
+    assert_eq!(PoolLight::default().cycles_to(3), None);
+  }
+
+  #[test]
+  fn shows_by_name_or_number() {
+    assert_eq!(find_show("emerald"), Some(5));
+    assert_eq!(find_show("16"), Some(16));
+    assert_eq!(find_show("17"), None);
+    assert_eq!(find_show("Disco"), None);
+  }
+}
@@ -0,0 +1,94 @@
+// pressure.rs - Filter pressure: clean baseline, clogging and loss of prime
+
+/// Below this with the pump on there is no water in it
+pub const DRY_PSI: f32 = 3.0;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct FilterPressure {
+  /// Last reading, refreshed by `pressure_tick`
+  pub psi: Option<f32>,
+  /// Running pressure with a clean filter, learnt after each cleaning
+  pub baseline_psi: Option<f32>,
+  /// Backwash is due this far over the baseline
+  pub rise_psi: f32,
+  /// Readings are averaged this long into a new baseline
+  pub learn_secs: u64,
+  /// How long the pump gets to build pressure after `filter_delay`
+  pub prime_secs: u64,
+  /// Latched once pressure passes `rise_psi`, cleared by relearning
+  pub backwash_due: bool,
+  /// Counted down by `pressure_tick` from pump start until it sees pressure
+  pub(crate) priming_left_secs: Option<u64>,
+  learnt_sum: f32,
+  learnt_secs: u64,
+}
+
+impl Default for FilterPressure {
+  fn default() -> Self {
+    FilterPressure {
+      psi: None,
+      baseline_psi: None,
+      rise_psi: 8.0,
+      learn_secs: 5 * 60,
+      prime_secs: 2 * 60,
+      backwash_due: false,
+      priming_left_secs: None,
+      learnt_sum: 0.0,
+      learnt_secs: 0,
+    }
+  }
+}
+
+impl FilterPressure {
+  /// How far over the clean baseline `psi` is
+  pub fn rise(&self, psi: f32) -> Option<f32> {
+    self.baseline_psi.map(|b| psi - b)
+  }
+
+  pub fn clogged(&self, psi: f32) -> bool {
+    self.rise(psi).is_some_and(|r| r >= self.rise_psi)
+  }
+
+  /// Forget the baseline so the next run learns a new one
+  pub fn relearn(&mut self) {
+    self.baseline_psi = None;
+    self.backwash_due = false;
+    self.learnt_sum = 0.0;
+    self.learnt_secs = 0;
+  }
+
+  /// Fold in `dt_secs` at `psi` while there's no baseline. True once
+  /// enough has been seen to set one.
+  pub(crate) fn learn(&mut self, psi: f32, dt_secs: u64) -> bool {
+    if self.baseline_psi.is_some() {
+      return false;
+    }
+    self.learnt_sum += psi * dt_secs as f32;
+    self.learnt_secs += dt_secs;
+    if self.learnt_secs < self.learn_secs {
+      return false;
+    }
+    self.baseline_psi = Some(self.learnt_sum / self.learnt_secs as f32);
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn baseline_is_the_average_over_the_window() {
+    let mut p = FilterPressure::default();
+    assert!(!p.learn(10.0, 150));
+    assert!(p.learn(12.0, 150));
+    assert_eq!(p.baseline_psi, Some(11.0));
+    assert!(!p.learn(30.0, 600));
+
+    assert!(!p.clogged(18.9));
+    assert!(p.clogged(19.0));
+
+    p.relearn();
+    assert_eq!(p.rise(20.0), None);
+  }
+}
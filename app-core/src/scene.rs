@@ -0,0 +1,208 @@
+// scene.rs - Declarative target states applied by System::apply_scene
+use crate::structs::{Lights, Mech, Name, PoolOrSpa, PoolValve, System, ValveMode};
+
+/// What the filter pump should be doing
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FilterRun {
+  Off,
+  Schedule,
+  QuickClean,
+}
+
+impl FilterRun {
+  pub fn of<M: Mech, L: Lights>(sys: &System<M, L>) -> FilterRun {
+    if sys.filter.quick_clean {
+      FilterRun::QuickClean
+    } else if sys.filter.running_schedule {
+      FilterRun::Schedule
+    } else {
+      FilterRun::Off
+    }
+  }
+}
+
+/// Target states; `None` leaves that part of the system as it is. With a
+/// duration, everything the scene touched goes back afterwards.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Scene {
+  pub name: Name,
+  pub main_valves: Option<ValveMode>,
+  pub pool_valve: Option<PoolValve>,
+  pub filter: Option<FilterRun>,
+  pub heat_mode: Option<PoolOrSpa>,
+  pub heater_on: Option<bool>,
+  pub jets: Option<bool>,
+  pub duration_secs: Option<u64>,
+}
+
+const HOUR: u64 = 60 * 60;
+
+impl Scene {
+  pub const BUILTIN: [&'static str; 4] = ["spa", "party", "vacuum", "winterize"];
+
+  pub fn named(name: &str) -> Scene {
+    Scene {
+      name: Name::new(name),
+      ..Default::default()
+    }
+  }
+
+  pub fn builtin(name: &str) -> Option<Scene> {
+    let mut s = Scene::named(name);
+    match name {
+      "spa" => {
+        s.main_valves = Some(ValveMode::Spa);
+        s.filter = Some(FilterRun::QuickClean);
+        s.heat_mode = Some(PoolOrSpa::Spa);
+        s.heater_on = Some(true);
+        s.duration_secs = Some(3 * HOUR);
+      }
+      "party" => {
+        s.main_valves = Some(ValveMode::Spa);
+        s.filter = Some(FilterRun::QuickClean);
+        s.heat_mode = Some(PoolOrSpa::Spa);
+        s.heater_on = Some(true);
+        s.jets = Some(true);
+        s.duration_secs = Some(4 * HOUR);
+      }
+      "vacuum" => {
+        s.main_valves = Some(ValveMode::Pool);
+        s.pool_valve = Some(PoolValve::Vacuum);
+        s.filter = Some(FilterRun::QuickClean);
+        s.heater_on = Some(false);
+        s.jets = Some(false);
+        s.duration_secs = Some(2 * HOUR);
+      }
+      "winterize" => {
+        // Keep water moving through everything so nothing freezes
+        s.main_valves = Some(ValveMode::Pool);
+        s.pool_valve = Some(PoolValve::Blend);
+        s.filter = Some(FilterRun::Schedule);
+        s.heater_on = Some(false);
+        s.jets = Some(false);
+      }
+      _ => return None,
+    }
+    Some(s)
+  }
+
+  /// Everything a scene can set, as it is now, so it can be put back
+  pub fn snapshot<M: Mech, L: Lights>(sys: &System<M, L>) -> Scene {
+    Scene {
+      name: Name::new("previous state"),
+      main_valves: Some(sys.valve_mode()),
+      pool_valve: Some(sys.pool_valve_orientation),
+      filter: Some(FilterRun::of(sys)),
+      heat_mode: Some(sys.heater.mode),
+      heater_on: Some(sys.heater.on),
+      jets: Some(sys.jets_on),
+      duration_secs: None,
+    }
+  }
+
+  /// Set one target from config text: `main_valves` = pool|spa|spillover,
+  /// `heat_mode` = pool|spa, `pool_valve` = blend|skimmer|vacuum, `filter` =
+  /// off|schedule|quick_clean, `heater`/`jets` = on|off, `duration_mins` = n
+  pub fn set(&mut self, key: &str, value: &str) -> bool {
+    let value = value.trim();
+    match key {
+      "main_valves" => valve_mode(value)
+        .map(|v| self.main_valves = Some(v))
+        .is_some(),
+      "heat_mode" => pool_or_spa(value)
+        .map(|v| self.heat_mode = Some(v))
+        .is_some(),
+      "pool_valve" => pool_valve(value)
+        .map(|v| self.pool_valve = Some(v))
+        .is_some(),
+      "filter" => filter_run(value).map(|v| self.filter = Some(v)).is_some(),
+      "heater" => on_off(value).map(|v| self.heater_on = Some(v)).is_some(),
+      "jets" => on_off(value).map(|v| self.jets = Some(v)).is_some(),
+      "duration_mins" => value
+        .parse::<u64>()
+        .map(|m| self.duration_secs = Some(m * 60))
+        .is_ok(),
+      _ => false,
+    }
+  }
+}
+
+fn pool_or_spa(v: &str) -> Option<PoolOrSpa> {
+  match v {
+    "pool" => Some(PoolOrSpa::Pool),
+    "spa" => Some(PoolOrSpa::Spa),
+    _ => None,
+  }
+}
+
+fn valve_mode(v: &str) -> Option<ValveMode> {
+  match v {
+    "spillover" => Some(ValveMode::Spillover),
+    _ => pool_or_spa(v).map(ValveMode::from),
+  }
+}
+
+fn pool_valve(v: &str) -> Option<PoolValve> {
+  match v {
+    "blend" => Some(PoolValve::Blend),
+    "skimmer" => Some(PoolValve::Skimmer),
+    "vacuum" => Some(PoolValve::Vacuum),
+    _ => None,
+  }
+}
+
+fn filter_run(v: &str) -> Option<FilterRun> {
+  match v {
+    "off" => Some(FilterRun::Off),
+    "schedule" => Some(FilterRun::Schedule),
+    "quick_clean" => Some(FilterRun::QuickClean),
+    _ => None,
+  }
+}
+
+fn on_off(v: &str) -> Option<bool> {
+  match v {
+    "on" | "true" => Some(true),
+    "off" | "false" => Some(false),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn config_text_sets_targets() {
+    let mut s = Scene::named("laps");
+    assert!(s.set("main_valves", "pool"));
+    assert!(s.set("filter", "quick_clean"));
+    assert!(s.set("heater", "true"));
+    assert!(s.set("duration_mins", "45"));
+    assert!(!s.set("jets", "maybe"));
+    assert!(!s.set("lights", "on"));
+
+    assert_eq!(s.main_valves, Some(ValveMode::Pool));
+    assert_eq!(s.filter, Some(FilterRun::QuickClean));
+    assert_eq!(s.heater_on, Some(true));
+    assert_eq!(s.jets, None);
+    assert_eq!(s.duration_secs, Some(45 * 60));
+  }
+
+  #[test]
+  fn every_builtin_exists() {
+    for name in Scene::BUILTIN {
+      assert_eq!(Scene::builtin(name).map(|s| s.name), Some(name.into()));
+    }
+    assert_eq!(Scene::builtin("disco"), None);
+  }
+
+  #[test]
+  fn long_names_are_cut_on_a_char_boundary() {
+    let s = Scene::named("Sunday afternoon with the whole family 🎉");
+    assert_eq!(s.name, "Sunday afternoon with the whole ");
+    // 29 bytes, then a 4 byte character that would end past 32
+    let s = Scene::named("Just under the limit, then a 🎉");
+    assert_eq!(s.name, "Just under the limit, then a ");
+  }
+}
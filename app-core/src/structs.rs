@@ -1,6 +1,17 @@
+use crate::backwash::{Backwash, Multiport};
+use crate::chemistry::{ChemSensors, Chemistry, DosePump, DosingMech};
+use crate::chlorinator::{Chlorinator, ChlorinatorMech};
+use crate::circuits::{AuxCircuits, AuxMech};
+use crate::history::History;
+use crate::maintenance::Maintenance;
 use crate::message_queue::MessageQueue;
+use crate::pool_light::PoolLight;
+use crate::pressure::FilterPressure;
 use core::fmt;
+use core::ops::Deref;
 
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
 #[cfg(not(target_os = "none"))]
 use std::thread;
 #[cfg(not(target_os = "none"))]
@@ -14,6 +25,15 @@ impl fmt::Display for PoolOrSpa {
     }
   }
 }
+impl fmt::Display for ValveMode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ValveMode::Pool => write!(f, "POOL"),
+      ValveMode::Spa => write!(f, "SPA"),
+      ValveMode::Spillover => write!(f, "SPILLOVER"),
+    }
+  }
+}
 impl fmt::Display for PoolValve {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
@@ -24,18 +44,128 @@ impl fmt::Display for PoolValve {
   }
 }
 
+impl fmt::Display for ErrorCode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ErrorCode::MainValve => write!(f, "Main valve did not respond"),
+      ErrorCode::Filter => write!(f, "Filter pump did not respond"),
+      ErrorCode::Heater => write!(f, "Heater relay did not respond"),
+      ErrorCode::HeaterMode => write!(f, "Heater mode did not respond"),
+      ErrorCode::Jets => write!(f, "Jets did not respond"),
+      ErrorCode::Lights => write!(f, "Panel lights did not respond"),
+      ErrorCode::EmergencyStop => write!(f, "Emergency stop, acknowledge to reset"),
+      ErrorCode::PoolValve => write!(f, "Pool valve did not respond"),
+      ErrorCode::Aux => write!(f, "Aux circuit did not respond"),
+      ErrorCode::PoolLight => write!(f, "Pool light did not respond"),
+      ErrorCode::Chlorinator => write!(f, "Chlorinator did not respond"),
+      ErrorCode::SaltLow => write!(f, "Salt low, chlorinator off"),
+      ErrorCode::Dosing => write!(f, "Dosing pump did not respond"),
+      ErrorCode::LostPrime => write!(f, "Filter pump lost prime"),
+      ErrorCode::Multiport => write!(f, "Multiport valve did not respond"),
+    }
+  }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum PoolOrSpa {
   Pool,
   Spa,
 }
-#[derive(Copy, Clone, PartialEq)]
+/// Suction and return valves together
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ValveMode {
+  Pool,
+  Spa,
+  /// Suction from the pool, return to the spa so it spills over into the pool
+  Spillover,
+}
+
+impl ValveMode {
+  pub fn of(suction: PoolOrSpa, ret: PoolOrSpa) -> ValveMode {
+    match (suction, ret) {
+      (PoolOrSpa::Spa, _) => ValveMode::Spa,
+      (PoolOrSpa::Pool, PoolOrSpa::Pool) => ValveMode::Pool,
+      (PoolOrSpa::Pool, PoolOrSpa::Spa) => ValveMode::Spillover,
+    }
+  }
+
+  pub fn suction(self) -> PoolOrSpa {
+    match self {
+      ValveMode::Spa => PoolOrSpa::Spa,
+      ValveMode::Pool | ValveMode::Spillover => PoolOrSpa::Pool,
+    }
+  }
+
+  pub fn return_side(self) -> PoolOrSpa {
+    match self {
+      ValveMode::Pool => PoolOrSpa::Pool,
+      ValveMode::Spa | ValveMode::Spillover => PoolOrSpa::Spa,
+    }
+  }
+}
+
+impl From<PoolOrSpa> for ValveMode {
+  fn from(m: PoolOrSpa) -> ValveMode {
+    ValveMode::of(m, m)
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum PoolValve {
   Vacuum,
   Skimmer,
   Blend,
 }
 
+/// Stored in `System::errors` as `code as u32`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ErrorCode {
+  MainValve = 1,
+  Filter = 2,
+  Heater = 3,
+  HeaterMode = 4,
+  Jets = 5,
+  Lights = 6,
+  /// Latched by `emergency_stop` until `acknowledge_emergency_stop`
+  EmergencyStop = 7,
+  PoolValve = 8,
+  Aux = 9,
+  PoolLight = 10,
+  Chlorinator = 11,
+  /// Raised and cleared by `chlorinator_tick` as the salt reading moves
+  SaltLow = 12,
+  Dosing = 13,
+  /// The pump never built pressure and was stopped
+  LostPrime = 14,
+  Multiport = 15,
+}
+
+impl ErrorCode {
+  pub const COUNT: usize = 15;
+
+  pub const ALL: [ErrorCode; ErrorCode::COUNT] = [
+    ErrorCode::MainValve,
+    ErrorCode::Filter,
+    ErrorCode::Heater,
+    ErrorCode::HeaterMode,
+    ErrorCode::Jets,
+    ErrorCode::Lights,
+    ErrorCode::EmergencyStop,
+    ErrorCode::PoolValve,
+    ErrorCode::Aux,
+    ErrorCode::PoolLight,
+    ErrorCode::Chlorinator,
+    ErrorCode::SaltLow,
+    ErrorCode::Dosing,
+    ErrorCode::LostPrime,
+    ErrorCode::Multiport,
+  ];
+
+  pub fn from_u32(v: u32) -> Option<ErrorCode> {
+    ErrorCode::ALL.iter().copied().find(|c| *c as u32 == v)
+  }
+}
+
 pub struct Filter {
   pub running_schedule: bool,
   pub quick_clean: bool,
@@ -48,7 +178,7 @@ pub struct Heater {
 pub struct PrevState {
   pub filter: Filter,
   pub heater: Heater,
-  pub main_valve_orientation: Option<PoolOrSpa>,
+  pub main_valve_orientation: Option<ValveMode>,
 }
 
 pub trait Mech {
@@ -60,7 +190,25 @@ pub trait Mech {
 
   // Valves
   fn mech_pool_valve_to(&self, p: PoolValve) -> bool;
+  /// Suction and return together
   fn mech_main_valve_to(&self, m: PoolOrSpa) -> bool;
+  /// Return side on its own, for spillover. Plumbing with one actuator for
+  /// both sides can't, hence the default.
+  fn mech_return_valve_to(&self, _m: PoolOrSpa) -> bool {
+    false
+  }
+
+  /// Supply to the color pool light, switched in timed sequences to pick a
+  /// show. Not every install has one, hence the default.
+  fn mech_pool_light(&self, _on: bool) -> bool {
+    false
+  }
+
+  /// Actuator on a sand/DE filter's multiport valve. Most are turned by
+  /// hand, see `Backwash::actuated`.
+  fn mech_multiport_to(&self, _p: Multiport) -> bool {
+    false
+  }
 
   // Heater
   fn heater_on_toggle(&self, b: bool) -> bool;
@@ -68,17 +216,232 @@ pub trait Mech {
 
   // Jets
   fn jets_on_toggle(&self, b: bool) -> bool;
+
+  // Outputs an emergency stop drops besides the fixed set. A mech with
+  // `AuxMech`, `ChlorinatorMech` or `DosingMech` outputs reaches them here;
+  // one without has nothing more to switch off, hence the defaults.
+  fn mech_aux_off(&self, _index: usize) -> bool {
+    true
+  }
+  fn mech_cell_off(&self) -> bool {
+    true
+  }
+  fn mech_dose_pump_off(&self, _pump: DosePump) -> bool {
+    true
+  }
+}
+
+/// Panel lights, indexed to match the web UI `light-N` ids
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Light {
+  AutoSpa = 0,
+  Jets = 1,
+  FilterSchedule = 2,
+  QuickClean = 3,
+  MainValveSpa = 4,
+  MainValvePool = 5,
+  PoolValveBlend = 6,
+  PoolValveSkimmer = 7,
+  PoolValveVacuum = 8,
+  HeaterOn = 9,
+  HeatModeSpa = 10,
+  HeatModePool = 11,
+  InProgress = 12,
+  Fault = 13,
+  /// A maintenance reminder is due
+  Service = 14,
+}
+
+impl Light {
+  pub const COUNT: usize = 15;
+}
+
+/// How a light is driven. Values are the codes served to the web UI.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum LightPattern {
+  #[default]
+  Off = 0,
+  Steady = 1,
+  /// Something is starting or moving, e.g. priming or valve travel
+  SlowBlink = 2,
+  /// Needs attention, e.g. an active fault
+  FastBlink = 3,
+  /// Waiting to happen, e.g. a schedule start held back by a routine
+  Pulse = 4,
+}
+
+impl LightPattern {
+  /// Brightness 0-255 at `t_ms` for drivers that animate patterns themselves
+  pub fn level(self, t_ms: u64) -> u8 {
+    match self {
+      LightPattern::Off => 0,
+      LightPattern::Steady => 255,
+      LightPattern::SlowBlink => {
+        if t_ms % 1000 < 500 {
+          255
+        } else {
+          0
+        }
+      }
+      LightPattern::FastBlink => {
+        if t_ms % 250 < 125 {
+          255
+        } else {
+          0
+        }
+      }
+      LightPattern::Pulse => {
+        // Triangle wave, 2 second period
+        let phase = t_ms % 2000;
+        let ramp = if phase < 1000 { phase } else { 2000 - phase };
+        (ramp * 255 / 1000) as u8
+      }
+    }
+  }
+}
+
+/// Every light at once, computed from `System` state by `render_lights`
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct LightFrame {
+  pub patterns: [LightPattern; Light::COUNT],
+}
+
+impl LightFrame {
+  pub fn set(&mut self, l: Light, on: bool) {
+    self.patterns[l as usize] = if on {
+      LightPattern::Steady
+    } else {
+      LightPattern::Off
+    };
+  }
+
+  pub fn set_pattern(&mut self, l: Light, p: LightPattern) {
+    self.patterns[l as usize] = p;
+  }
+
+  pub fn pattern(&self, l: Light) -> LightPattern {
+    self.patterns[l as usize]
+  }
+
+  pub fn is_on(&self, l: Light) -> bool {
+    self.pattern(l) != LightPattern::Off
+  }
+}
+
+/// Something `System` is in the middle of doing
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Transition {
+  /// Main valves travelling to the given mode
+  Valves(ValveMode),
+  /// Filter pump priming for quick clean or the schedule
+  Priming(Light),
+  StoppingFilter,
 }
 
 pub trait Lights {
-  fn in_progress(&self, b: bool) -> bool;
-  fn filter_schedule(&self, b: bool) -> bool;
-  fn heater_on(&self, b: bool) -> bool;
-  fn jets_on(&self, b: bool) -> bool;
-  fn auto_spa(&self, b: bool) -> bool;
-  fn heater_mode(&self, m: PoolOrSpa) -> bool;
-  fn quick_clean(&self, b: bool) -> bool;
-  fn main_valve_orientation(&self, b: PoolOrSpa) -> bool;
+  fn show(&self, frame: &LightFrame) -> bool;
+}
+
+pub trait Sensors {
+  fn pool_temp_f(&self) -> Option<f32>;
+  fn spa_temp_f(&self) -> Option<f32>;
+  fn air_temp_f(&self) -> Option<f32>;
+  fn flow_gpm(&self) -> Option<f32>;
+  /// Gauge on the filter housing
+  fn filter_psi(&self) -> Option<f32>;
+}
+
+/// Snapshot of every sensor, `None` where a sensor is missing or failed
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Readings {
+  pub pool_temp_f: Option<f32>,
+  pub spa_temp_f: Option<f32>,
+  pub air_temp_f: Option<f32>,
+  pub flow_gpm: Option<f32>,
+  pub filter_psi: Option<f32>,
+}
+
+/// A short name held inline, for aux circuits and scenes. Anything past
+/// `Name::MAX` bytes is cut at a character boundary.
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
+pub struct Name {
+  buffer: [u8; Name::MAX],
+  len: usize,
+}
+
+impl Name {
+  pub const MAX: usize = 32;
+
+  pub fn new(s: &str) -> Name {
+    let mut len = s.len().min(Name::MAX);
+    while !s.is_char_boundary(len) {
+      len -= 1;
+    }
+    let mut buffer = [0; Name::MAX];
+    buffer[..len].copy_from_slice(&s.as_bytes()[..len]);
+    Name { buffer, len }
+  }
+
+  pub fn as_str(&self) -> &str {
+    core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+  }
+}
+
+impl Deref for Name {
+  type Target = str;
+
+  fn deref(&self) -> &str {
+    self.as_str()
+  }
+}
+
+impl From<&str> for Name {
+  fn from(s: &str) -> Name {
+    Name::new(s)
+  }
+}
+
+impl PartialEq<str> for Name {
+  fn eq(&self, other: &str) -> bool {
+    self.as_str() == other
+  }
+}
+
+impl PartialEq<&str> for Name {
+  fn eq(&self, other: &&str) -> bool {
+    self.as_str() == *other
+  }
+}
+
+impl fmt::Display for Name {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+impl fmt::Debug for Name {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Debug::fmt(self.as_str(), f)
+  }
+}
+
+/// Raised from outside `System`, e.g. another thread or an interrupt, to
+/// abort whatever routine is running. Clones share the same flag.
+#[derive(Clone, Default, Debug)]
+pub struct StopSignal(Arc<AtomicBool>);
+
+impl StopSignal {
+  pub fn raise(&self) {
+    self.0.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_raised(&self) -> bool {
+    self.0.load(Ordering::SeqCst)
+  }
+
+  pub fn clear(&self) {
+    self.0.store(false, Ordering::SeqCst);
+  }
 }
 
 pub struct HasOSMech;
@@ -92,6 +455,12 @@ impl Mech for HasOSMech {
   fn mech_main_valve_to(&self, _: PoolOrSpa) -> bool {
     true
   }
+  fn mech_return_valve_to(&self, _: PoolOrSpa) -> bool {
+    true
+  }
+  fn mech_pool_light(&self, _: bool) -> bool {
+    true
+  }
 
   fn set_quick_clean(&self, _: bool) -> bool {
     true
@@ -116,30 +485,59 @@ impl Mech for HasOSMech {
     true
   }
 }
-impl Lights for HasOSLights {
-  fn in_progress(&self, _: bool) -> bool {
+impl AuxMech for HasOSMech {
+  fn aux_set(&self, _: usize, _: bool) -> bool {
     true
   }
-  fn filter_schedule(&self, _: bool) -> bool {
+}
+
+impl ChlorinatorMech for HasOSMech {
+  fn cell_output(&self, _: u8) -> bool {
     true
   }
-  fn heater_on(&self, _: bool) -> bool {
-    true
+  fn salt_ppm(&self) -> Option<u32> {
+    None
   }
-  fn jets_on(&self, _: bool) -> bool {
-    true
+  fn cell_temp_f(&self) -> Option<f32> {
+    None
   }
-  fn auto_spa(&self, _: bool) -> bool {
+}
+
+impl DosingMech for HasOSMech {
+  fn dose_pump(&self, _: DosePump, _: bool) -> bool {
     true
   }
-  fn heater_mode(&self, _: PoolOrSpa) -> bool {
-    true
+}
+
+impl ChemSensors for HasOSMech {
+  fn ph(&self) -> Option<f32> {
+    None
+  }
+  fn orp_mv(&self) -> Option<f32> {
+    None
   }
+}
 
-  fn quick_clean(&self, _: bool) -> bool {
-    true
+impl Sensors for HasOSMech {
+  fn pool_temp_f(&self) -> Option<f32> {
+    None
+  }
+  fn spa_temp_f(&self) -> Option<f32> {
+    None
+  }
+  fn air_temp_f(&self) -> Option<f32> {
+    None
+  }
+  fn flow_gpm(&self) -> Option<f32> {
+    None
   }
-  fn main_valve_orientation(&self, _: PoolOrSpa) -> bool {
+  fn filter_psi(&self) -> Option<f32> {
+    None
+  }
+}
+
+impl Lights for HasOSLights {
+  fn show(&self, _: &LightFrame) -> bool {
     true
   }
 }
@@ -148,6 +546,7 @@ impl Default for System<HasOSMech, HasOSLights> {
   fn default() -> Self {
     Self {
       main_valve_orientation: PoolOrSpa::Pool,
+      return_valve_orientation: PoolOrSpa::Pool,
       pool_valve_orientation: PoolValve::Skimmer,
       filter: Filter {
         running_schedule: false,
@@ -157,31 +556,81 @@ impl Default for System<HasOSMech, HasOSLights> {
         mode: PoolOrSpa::Pool,
         on: false,
       },
+      heater_relay: false,
       jets_on: false,
-      errors: [None; 10],
+      errors: [None; ErrorCode::COUNT],
       in_progress: false,
+      transition: None,
       mech: HasOSMech,
       lights: HasOSLights,
       internal_test: true,
       prev_state: None,
       message_queue: MessageQueue::new(),
       auto_spa_mode: false,
+      shown_lights: None,
+      stop: StopSignal::default(),
+      estop_latched: false,
+      active_scene: None,
+      jets_limit_secs: 20 * 60,
+      jets_remaining_secs: None,
+      jets_override: false,
+      aux: AuxCircuits::default(),
+      pool_light: PoolLight::default(),
+      chlorinator: Chlorinator::default(),
+      chemistry: Chemistry::default(),
+      history: History::default(),
+      maintenance: Maintenance::default(),
+      pressure: FilterPressure::default(),
+      backwash: Backwash::default(),
     }
   }
 }
 
 pub struct System<M: Mech, L: Lights> {
+  /// Suction side of the main valves
   pub main_valve_orientation: PoolOrSpa,
+  pub return_valve_orientation: PoolOrSpa,
   pub pool_valve_orientation: PoolValve,
   pub filter: Filter,
   pub heater: Heater,
+  /// Whether the heater is actually energized; `heater.on` is only the request
+  pub heater_relay: bool,
   pub jets_on: bool,
-  pub errors: [Option<u32>; 10],
+  /// One slot per code, so a new fault never pushes another out
+  pub errors: [Option<u32>; ErrorCode::COUNT],
   pub in_progress: bool,
+  pub transition: Option<Transition>,
   pub mech: M,
   pub lights: L,
   pub internal_test: bool,
   pub prev_state: Option<PrevState>,
   pub message_queue: MessageQueue<48>,
   pub auto_spa_mode: bool,
+  /// Last frame sent to `lights`, so unchanged frames aren't resent
+  pub shown_lights: Option<LightFrame>,
+  /// Clone this before handing `System` to a mutex, so a stop can be raised
+  /// while a routine holds the lock
+  pub stop: StopSignal,
+  /// Set by `emergency_stop`; nothing starts again until acknowledged
+  pub estop_latched: bool,
+  /// Name of the scene `apply_scene` is running
+  pub active_scene: Option<Name>,
+  /// Jets switch themselves off after this long; 0 for no limit
+  pub jets_limit_secs: u64,
+  /// Counted down by `tick` while the jets run
+  pub jets_remaining_secs: Option<u64>,
+  /// Allow jets with the valves in pool mode
+  pub jets_override: bool,
+  /// Added with `add_aux`; the index is what `AuxMech` sees
+  pub aux: AuxCircuits,
+  pub pool_light: PoolLight,
+  pub chlorinator: Chlorinator,
+  pub chemistry: Chemistry,
+  /// Fed by `history_tick`
+  pub history: History,
+  /// Run counters, fed by `tick` and the valve moves
+  pub maintenance: Maintenance,
+  /// Fed by `pressure_tick`
+  pub pressure: FilterPressure,
+  pub backwash: Backwash,
 }
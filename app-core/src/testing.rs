@@ -0,0 +1,271 @@
+// testing.rs - Recording Mech/Lights mocks for call-order assertions
+use crate::backwash::Multiport;
+use crate::chemistry::{ChemSensors, DosePump, DosingMech};
+use crate::chlorinator::ChlorinatorMech;
+use crate::circuits::AuxMech;
+use crate::structs::{LightFrame, Lights, Mech, PoolOrSpa, PoolValve, Readings, Sensors};
+use core::cell::{Cell, RefCell};
+use core::fmt::Debug;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Call {
+  Delay(u64),
+  QuickClean(bool),
+  FilterSchedule(bool),
+  PoolValve(PoolValve),
+  MainValve(PoolOrSpa),
+  ReturnValve(PoolOrSpa),
+  HeaterOn(bool),
+  HeaterMode(PoolOrSpa),
+  Jets(bool),
+  Aux(usize, bool),
+  PoolLight(bool),
+  Cell(u8),
+  Dose(DosePump, bool),
+  Multiport(Multiport),
+}
+
+/// Ordered record of calls with assertion helpers
+pub struct CallLog<T> {
+  calls: RefCell<Vec<T>>,
+}
+
+impl<T> Default for CallLog<T> {
+  fn default() -> Self {
+    CallLog {
+      calls: RefCell::new(Vec::new()),
+    }
+  }
+}
+
+impl<T: Copy + PartialEq + Debug> CallLog<T> {
+  pub fn push(&self, c: T) {
+    self.calls.borrow_mut().push(c);
+  }
+
+  pub fn calls(&self) -> Vec<T> {
+    self.calls.borrow().clone()
+  }
+
+  pub fn clear(&self) {
+    self.calls.borrow_mut().clear();
+  }
+
+  pub fn count(&self, c: T) -> usize {
+    self.calls.borrow().iter().filter(|x| **x == c).count()
+  }
+
+  pub fn first(&self, c: T) -> Option<usize> {
+    self.calls.borrow().iter().position(|x| *x == c)
+  }
+
+  pub fn last(&self, c: T) -> Option<usize> {
+    self.calls.borrow().iter().rposition(|x| *x == c)
+  }
+
+  pub fn assert_called(&self, c: T) {
+    assert!(
+      self.first(c).is_some(),
+      "expected {:?} in {:?}",
+      c,
+      self.calls.borrow()
+    );
+  }
+
+  pub fn assert_not_called(&self, c: T) {
+    assert!(
+      self.first(c).is_none(),
+      "unexpected {:?} in {:?}",
+      c,
+      self.calls.borrow()
+    );
+  }
+
+  /// Every `a` happened before the first `b`, and both happened
+  pub fn assert_before(&self, a: T, b: T) {
+    match (self.last(a), self.first(b)) {
+      (Some(ia), Some(ib)) if ia < ib => {}
+      _ => panic!(
+        "expected {:?} before {:?} in {:?}",
+        a,
+        b,
+        self.calls.borrow()
+      ),
+    }
+  }
+
+  /// `seq` appears in order, not necessarily adjacent
+  pub fn assert_sequence(&self, seq: &[T]) {
+    let calls = self.calls.borrow();
+    let mut it = calls.iter();
+    for c in seq {
+      assert!(
+        it.any(|x| x == c),
+        "expected sequence {:?} in {:?}",
+        seq,
+        calls
+      );
+    }
+  }
+
+  /// Recorded calls are exactly `seq`
+  pub fn assert_exact(&self, seq: &[T]) {
+    assert_eq!(self.calls.borrow().as_slice(), seq);
+  }
+}
+
+/// Always succeeds and records every call; sensor values are whatever
+/// `readings` is set to
+#[derive(Default)]
+pub struct RecordingMech {
+  pub log: CallLog<Call>,
+  pub readings: Cell<Readings>,
+  pub salt_ppm: Cell<Option<u32>>,
+  pub cell_temp_f: Cell<Option<f32>>,
+  pub ph: Cell<Option<f32>>,
+  pub orp_mv: Cell<Option<f32>>,
+}
+
+impl Mech for RecordingMech {
+  fn delay_secs(&self, secs: u64) {
+    self.log.push(Call::Delay(secs));
+  }
+
+  fn set_quick_clean(&self, v: bool) -> bool {
+    self.log.push(Call::QuickClean(v));
+    true
+  }
+  fn mech_set_filter_sched(&self, v: bool) -> bool {
+    self.log.push(Call::FilterSchedule(v));
+    true
+  }
+
+  fn mech_pool_valve_to(&self, p: PoolValve) -> bool {
+    self.log.push(Call::PoolValve(p));
+    true
+  }
+  fn mech_main_valve_to(&self, m: PoolOrSpa) -> bool {
+    self.log.push(Call::MainValve(m));
+    true
+  }
+  fn mech_return_valve_to(&self, m: PoolOrSpa) -> bool {
+    self.log.push(Call::ReturnValve(m));
+    true
+  }
+  fn mech_pool_light(&self, on: bool) -> bool {
+    self.log.push(Call::PoolLight(on));
+    true
+  }
+  fn mech_multiport_to(&self, p: Multiport) -> bool {
+    self.log.push(Call::Multiport(p));
+    true
+  }
+
+  fn heater_on_toggle(&self, b: bool) -> bool {
+    self.log.push(Call::HeaterOn(b));
+    true
+  }
+  fn heater_mode_toggle(&self, m: PoolOrSpa) -> bool {
+    self.log.push(Call::HeaterMode(m));
+    true
+  }
+
+  fn jets_on_toggle(&self, b: bool) -> bool {
+    self.log.push(Call::Jets(b));
+    true
+  }
+
+  fn mech_aux_off(&self, index: usize) -> bool {
+    self.aux_set(index, false)
+  }
+  fn mech_cell_off(&self) -> bool {
+    self.cell_output(0)
+  }
+  fn mech_dose_pump_off(&self, pump: DosePump) -> bool {
+    self.dose_pump(pump, false)
+  }
+}
+
+impl AuxMech for RecordingMech {
+  fn aux_set(&self, index: usize, on: bool) -> bool {
+    self.log.push(Call::Aux(index, on));
+    true
+  }
+}
+
+impl ChlorinatorMech for RecordingMech {
+  fn cell_output(&self, percent: u8) -> bool {
+    self.log.push(Call::Cell(percent));
+    true
+  }
+  fn salt_ppm(&self) -> Option<u32> {
+    self.salt_ppm.get()
+  }
+  fn cell_temp_f(&self) -> Option<f32> {
+    self.cell_temp_f.get()
+  }
+}
+
+impl DosingMech for RecordingMech {
+  fn dose_pump(&self, pump: DosePump, on: bool) -> bool {
+    self.log.push(Call::Dose(pump, on));
+    true
+  }
+}
+
+impl ChemSensors for RecordingMech {
+  fn ph(&self) -> Option<f32> {
+    self.ph.get()
+  }
+  fn orp_mv(&self) -> Option<f32> {
+    self.orp_mv.get()
+  }
+}
+
+impl Sensors for RecordingMech {
+  fn pool_temp_f(&self) -> Option<f32> {
+    self.readings.get().pool_temp_f
+  }
+  fn spa_temp_f(&self) -> Option<f32> {
+    self.readings.get().spa_temp_f
+  }
+  fn air_temp_f(&self) -> Option<f32> {
+    self.readings.get().air_temp_f
+  }
+  fn flow_gpm(&self) -> Option<f32> {
+    self.readings.get().flow_gpm
+  }
+  fn filter_psi(&self) -> Option<f32> {
+    self.readings.get().filter_psi
+  }
+}
+
+/// Records every frame shown
+#[derive(Default)]
+pub struct RecordingLights {
+  pub log: CallLog<LightFrame>,
+}
+
+impl Lights for RecordingLights {
+  fn show(&self, frame: &LightFrame) -> bool {
+    self.log.push(*frame);
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  #[should_panic(expected = "expected Jets(true) before HeaterOn(true)")]
+  fn assert_before_reports_order() {
+    let m = RecordingMech::default();
+    m.heater_on_toggle(true);
+    m.jets_on_toggle(true);
+
+    m.log
+      .assert_sequence(&[Call::HeaterOn(true), Call::Jets(true)]);
+    m.log.assert_before(Call::Jets(true), Call::HeaterOn(true));
+  }
+}
@@ -0,0 +1,272 @@
+// assets.rs - Web UI files compiled into the binary, or read from a
+// directory while working on them
+use crate::auth::header;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::borrow::Cow;
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+use tiny_http::{Header, Request, Response};
+
+const EMBEDDED: [(&str, &[u8]); 1] = [("index.html", include_bytes!("../../index.html"))];
+
+/// A file ready to serve in either encoding
+#[derive(Clone)]
+pub struct Asset {
+  pub content_type: &'static str,
+  pub etag: String,
+  pub body: Vec<u8>,
+  pub gzipped: Vec<u8>,
+}
+
+impl Asset {
+  fn new(name: &str, body: Vec<u8>) -> Self {
+    let mut gz = GzEncoder::new(Vec::new(), Compression::best());
+    gz.write_all(&body).ok();
+    Asset {
+      content_type: content_type(name),
+      etag: etag(&body),
+      gzipped: gz.finish().unwrap_or_default(),
+      body,
+    }
+  }
+}
+
+pub struct Assets {
+  /// Read from here on every request when set, so edits show on reload
+  dir: Option<PathBuf>,
+  embedded: Vec<(&'static str, Asset)>,
+}
+
+impl Assets {
+  pub fn new(dir: Option<PathBuf>) -> Self {
+    // Compressed once up front rather than per request
+    let embedded = match dir {
+      Some(_) => Vec::new(),
+      None => EMBEDDED
+        .iter()
+        .map(|(name, body)| (*name, Asset::new(name, body.to_vec())))
+        .collect(),
+    };
+    Assets { dir, embedded }
+  }
+
+  pub fn has(&self, path: &str) -> bool {
+    match (asset_name(path), &self.dir) {
+      (Some(name), Some(dir)) => dir.join(name).is_file(),
+      (Some(name), None) => self.embedded.iter().any(|(n, _)| *n == name),
+      (None, _) => false,
+    }
+  }
+
+  /// `path` as requested, "/" meaning index.html
+  pub fn get(&self, path: &str) -> Option<Cow<'_, Asset>> {
+    let name = asset_name(path)?;
+    match &self.dir {
+      Some(dir) => {
+        let body = std::fs::read(dir.join(name)).ok()?;
+        Some(Cow::Owned(Asset::new(name, body)))
+      }
+      None => self
+        .embedded
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, a)| Cow::Borrowed(a)),
+    }
+  }
+}
+
+/// 304 if the browser has it already, else gzipped when it can take it
+pub fn response(request: &Request, asset: &Asset) -> Response<Cursor<Vec<u8>>> {
+  let fresh = header(request, "If-None-Match").is_some_and(|tags| {
+    tags
+      .split(',')
+      .any(|t| t.trim() == asset.etag || t.trim() == "*")
+  });
+  let gzip = header(request, "Accept-Encoding").is_some_and(accepts_gzip);
+
+  let mut response = if fresh {
+    Response::from_data(Vec::new()).with_status_code(304)
+  } else if gzip {
+    Response::from_data(asset.gzipped.clone())
+      .with_header(Header::from_bytes("Content-Encoding", "gzip").unwrap())
+  } else {
+    Response::from_data(asset.body.clone())
+  };
+  for (name, value) in [
+    ("Content-Type", asset.content_type),
+    ("ETag", asset.etag.as_str()),
+    ("Cache-Control", "no-cache"),
+    ("Vary", "Accept-Encoding"),
+  ] {
+    response.add_header(Header::from_bytes(name, value).unwrap());
+  }
+  response
+}
+
+// gzip named with a q above 0, or failing that a `*` that is. "gzip;q=0"
+// is a refusal, not a match.
+fn accepts_gzip(accept_encoding: &str) -> bool {
+  let mut star = false;
+  for item in accept_encoding.split(',') {
+    let mut parts = item.split(';');
+    let coding = parts.next().unwrap_or_default().trim();
+    let q = parts
+      .find_map(|p| p.trim().strip_prefix("q="))
+      .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+    let wanted = q.is_some_and(|q| q > 0.0);
+    if coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip") {
+      return wanted;
+    }
+    if coding == "*" {
+      star = wanted;
+    }
+  }
+  star
+}
+
+// Nothing outside the directory, nothing hidden. Any query is dropped
+// first. The path arrives still
+// percent-encoded and no file here needs escaping, so anything escaped or
+// with a Windows separator is refused rather than decoded.
+fn asset_name(path: &str) -> Option<&str> {
+  let path = path.split_once('?').map_or(path, |(p, _)| p);
+  let name = match path.trim_start_matches('/') {
+    "" => "index.html",
+    n => n,
+  };
+  let ok = !name.contains(['%', '\\', ':'])
+    && !name
+      .split('/')
+      .any(|part| part.is_empty() || part.starts_with('.'));
+  ok.then_some(name)
+}
+
+fn content_type(name: &str) -> &'static str {
+  match name.rsplit_once('.').map(|(_, ext)| ext) {
+    Some("html") => "text/html; charset=utf-8",
+    Some("css") => "text/css; charset=utf-8",
+    Some("js") => "text/javascript; charset=utf-8",
+    Some("json") => "application/json",
+    Some("svg") => "image/svg+xml",
+    Some("png") => "image/png",
+    Some("ico") => "image/x-icon",
+    Some("txt") => "text/plain; charset=utf-8",
+    _ => "application/octet-stream",
+  }
+}
+
+// FNV-1a, enough to tell one version of a file from the next
+fn etag(body: &[u8]) -> String {
+  let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+  for b in body {
+    h ^= *b as u64;
+    h = h.wrapping_mul(0x0100_0000_01b3);
+  }
+  format!("\"{:016x}\"", h)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tiny_http::TestRequest;
+
+  fn index() -> Asset {
+    Assets::new(None).get("/").unwrap().into_owned()
+  }
+
+  fn get(headers: &[(&str, &str)]) -> Request {
+    let mut r = TestRequest::new().with_path("/");
+    for (name, value) in headers {
+      r = r.with_header(Header::from_bytes(*name, *value).unwrap());
+    }
+    r.into()
+  }
+
+  fn header(response: &Response<Cursor<Vec<u8>>>, name: &'static str) -> Option<String> {
+    response
+      .headers()
+      .iter()
+      .find(|h| h.field.equiv(name))
+      .map(|h| h.value.to_string())
+  }
+
+  #[test]
+  fn names_stay_inside_the_directory() {
+    assert_eq!(asset_name("/"), Some("index.html"));
+    assert_eq!(asset_name("/css/site.css"), Some("css/site.css"));
+    assert_eq!(asset_name("/?x"), Some("index.html"));
+    assert_eq!(asset_name("/index.html?v=2"), Some("index.html"));
+    for path in [
+      "/..",
+      "/../Cargo.toml",
+      "/css/../../Cargo.toml",
+      "/%2e%2e/Cargo.toml",
+      "/%2E%2E%2FCargo.toml",
+      "/css//site.css",
+      "/css/",
+      "/.git/config",
+      "/css/.hidden",
+      "/css\\..\\..\\Cargo.toml",
+      "/C:/Windows/win.ini",
+      "/..?v=2",
+    ] {
+      assert_eq!(asset_name(path), None, "{}", path);
+    }
+    let assets = Assets::new(None);
+    assert!(assets.has("/index.html"));
+    assert!(!assets.has("/../index.html"));
+    assert!(assets.get("/%2e%2e/index.html").is_none());
+    assert!(assets.has("/index.html?v=2"));
+    assert!(assets.get("/?x").is_some());
+  }
+
+  #[test]
+  fn matching_etag_is_not_modified() {
+    let asset = index();
+    let r = response(&get(&[("If-None-Match", &asset.etag)]), &asset);
+    assert_eq!(r.status_code().0, 304);
+    assert_eq!(r.data_length(), Some(0));
+    assert_eq!(header(&r, "ETag"), Some(asset.etag.clone()));
+
+    let r = response(&get(&[("If-None-Match", "\"stale\"")]), &asset);
+    assert_eq!(r.status_code().0, 200);
+    assert_eq!(r.data_length(), Some(asset.body.len()));
+  }
+
+  #[test]
+  fn gzip_only_when_accepted() {
+    let asset = index();
+    assert!(asset.gzipped.len() < asset.body.len());
+
+    let r = response(&get(&[("Accept-Encoding", "gzip, deflate")]), &asset);
+    assert_eq!(header(&r, "Content-Encoding").as_deref(), Some("gzip"));
+    assert_eq!(r.data_length(), Some(asset.gzipped.len()));
+
+    for headers in [&[][..], &[("Accept-Encoding", "deflate, br")][..]] {
+      let r = response(&get(headers), &asset);
+      assert_eq!(header(&r, "Content-Encoding"), None);
+      assert_eq!(r.data_length(), Some(asset.body.len()));
+    }
+  }
+
+  #[test]
+  fn gzip_weights_are_honoured() {
+    for (value, gzip) in [
+      ("gzip", true),
+      ("GZIP;q=0.5", true),
+      ("br;q=1.0, gzip;q=0.1", true),
+      ("*", true),
+      ("gzip;q=0", false),
+      ("gzip; q=0.000", false),
+      ("gzip;q=0, *", false),
+      ("*;q=0", false),
+      ("br, *;q=0", false),
+      ("gzip;q=high", false),
+      ("identity", false),
+      ("", false),
+    ] {
+      assert_eq!(accepts_gzip(value), gzip, "{}", value);
+    }
+  }
+}
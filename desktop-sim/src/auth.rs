@@ -0,0 +1,326 @@
+// auth.rs - API tokens, login sessions and who may change what
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tiny_http::{Method, Request};
+
+/// Sessions last this long from login
+const SESSION_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Wrong passwords from one address before it has to wait
+const MAX_FAILURES: u32 = 5;
+/// How long since the last wrong password before that address may try again
+const LOCKOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Ordered, so an operator can do anything a viewer can
+#[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
+pub enum Role {
+  /// Reads status, changes nothing
+  Viewer,
+  Operator,
+}
+
+impl Role {
+  pub fn from_name(s: &str) -> Option<Role> {
+    match s {
+      "viewer" => Some(Role::Viewer),
+      "operator" => Some(Role::Operator),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for Role {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Role::Viewer => write!(f, "viewer"),
+      Role::Operator => write!(f, "operator"),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct User {
+  pub name: String,
+  pub password: String,
+  pub role: Role,
+}
+
+struct Session {
+  user: String,
+  role: Role,
+  expires: Instant,
+}
+
+struct Failures {
+  count: u32,
+  last: Instant,
+}
+
+/// Why `Auth::login` gave no session
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LoginError {
+  Wrong,
+  /// `MAX_FAILURES` wrong in a row from this address; right or not, nothing
+  /// is checked until `LOCKOUT` has passed
+  TooMany,
+}
+
+pub struct Auth {
+  users: Vec<User>,
+  /// For scripts, sent as `Authorization: Bearer <token>`
+  tokens: Vec<(String, Role)>,
+  sessions: HashMap<String, Session>,
+  failures: HashMap<IpAddr, Failures>,
+}
+
+impl Auth {
+  pub fn new(users: Vec<User>, tokens: Vec<(String, Role)>) -> Self {
+    Auth {
+      users,
+      tokens,
+      sessions: HashMap::new(),
+      failures: HashMap::new(),
+    }
+  }
+
+  /// The role needed for a request, `None` for what anyone may fetch: the
+  /// web UI itself and the login
+  pub fn required(method: &Method, path: &str, is_asset: bool) -> Option<Role> {
+    match (method, path) {
+      (Method::Post, "/login") | (Method::Post, "/logout") => None,
+      // Anyone who can see the panel can stop it
+      (Method::Post, "/emergency-stop") => Some(Role::Viewer),
+      (Method::Get, _) if is_asset => None,
+      (Method::Get, _) => Some(Role::Viewer),
+      _ => Some(Role::Operator),
+    }
+  }
+
+  /// Who sent `request`, from a bearer token or the session cookie
+  pub fn identify(&self, request: &Request) -> Option<(String, Role)> {
+    if let Some(token) = header(request, "Authorization").and_then(|v| v.strip_prefix("Bearer ")) {
+      return self
+        .tokens
+        .iter()
+        .find(|(t, _)| same(t, token.trim()))
+        .map(|(_, role)| ("token".to_string(), *role));
+    }
+    let id = session_id(request)?;
+    let s = self.sessions.get(id)?;
+    (s.expires > Instant::now()).then(|| (s.user.clone(), s.role))
+  }
+
+  /// A new session id if `name` and `password` match, unless `from` has
+  /// been getting them wrong
+  pub fn login(&mut self, from: IpAddr, name: &str, password: &str) -> Result<String, LoginError> {
+    let now = Instant::now();
+    self.failures.retain(|_, f| now < f.last + LOCKOUT);
+    if self
+      .failures
+      .get(&from)
+      .is_some_and(|f| f.count >= MAX_FAILURES)
+    {
+      return Err(LoginError::TooMany);
+    }
+    let Some(user) = self
+      .users
+      .iter()
+      .find(|u| u.name == name && same(&u.password, password))
+    else {
+      let f = self.failures.entry(from).or_insert(Failures {
+        count: 0,
+        last: now,
+      });
+      f.count += 1;
+      f.last = now;
+      return Err(LoginError::Wrong);
+    };
+    self.failures.remove(&from);
+    self.sessions.retain(|_, s| s.expires > now);
+
+    let id = random_hex();
+    self.sessions.insert(
+      id.clone(),
+      Session {
+        user: user.name.clone(),
+        role: user.role,
+        expires: now + SESSION_TTL,
+      },
+    );
+    Ok(id)
+  }
+
+  pub fn logout(&mut self, request: &Request) {
+    if let Some(id) = session_id(request) {
+      self.sessions.remove(id);
+    }
+  }
+}
+
+pub fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+  request
+    .headers()
+    .iter()
+    .find(|h| h.field.equiv(name))
+    .map(|h| h.value.as_str())
+}
+
+fn session_id(request: &Request) -> Option<&str> {
+  header(request, "Cookie")?
+    .split(';')
+    .find_map(|c| c.trim().strip_prefix("session="))
+}
+
+// Compares every byte whatever matches, so timing doesn't give away how
+// much of a secret was right
+fn same(a: &str, b: &str) -> bool {
+  a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |d, (x, y)| d | (x ^ y)) == 0
+}
+
+/// 128 bits from the OS's secure random source, as hex
+pub fn random_hex() -> String {
+  let mut bytes = [0u8; 16];
+  SystemRandom::new()
+    .fill(&mut bytes)
+    .expect("no secure randomness from the OS");
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tiny_http::{Header, TestRequest};
+
+  const HOME: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 20));
+  const AWAY: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 113, 9));
+
+  fn auth() -> Auth {
+    let user = |name: &str, role| User {
+      name: name.to_string(),
+      password: format!("{}-secret", name),
+      role,
+    };
+    Auth::new(
+      vec![user("ann", Role::Operator), user("bob", Role::Viewer)],
+      vec![("t0ken".to_string(), Role::Viewer)],
+    )
+  }
+
+  fn with(name: &'static str, value: &str) -> Request {
+    TestRequest::new()
+      .with_header(Header::from_bytes(name, value).unwrap())
+      .into()
+  }
+
+  #[test]
+  fn required_roles() {
+    let cases = [
+      (Method::Post, "/login", false, None),
+      (Method::Post, "/logout", false, None),
+      (Method::Get, "/", true, None),
+      (Method::Get, "/index.html", true, None),
+      (Method::Get, "/lights-patterns", false, Some(Role::Viewer)),
+      (Method::Post, "/emergency-stop", false, Some(Role::Viewer)),
+      (Method::Post, "/toggle-button", false, Some(Role::Operator)),
+      (Method::Post, "/acknowledge", false, Some(Role::Operator)),
+      (Method::Post, "/index.html", true, Some(Role::Operator)),
+    ];
+    for (method, path, asset, role) in cases {
+      assert_eq!(
+        Auth::required(&method, path, asset),
+        role,
+        "{} {}",
+        method,
+        path
+      );
+    }
+  }
+
+  #[test]
+  fn identify_by_token_or_session() {
+    let mut auth = auth();
+    assert_eq!(
+      auth.identify(&with("Authorization", "Bearer t0ken")),
+      Some(("token".to_string(), Role::Viewer))
+    );
+    assert_eq!(auth.identify(&with("Authorization", "Bearer t0ke")), None);
+    assert_eq!(auth.identify(&TestRequest::new().into()), None);
+
+    let id = auth.login(HOME, "ann", "ann-secret").unwrap();
+    let cookie = format!("theme=dark; session={}", id);
+    assert_eq!(
+      auth.identify(&with("Cookie", &cookie)),
+      Some(("ann".to_string(), Role::Operator))
+    );
+    assert_eq!(auth.identify(&with("Cookie", "session=0123")), None);
+
+    auth.sessions.get_mut(&id).unwrap().expires = Instant::now();
+    assert_eq!(auth.identify(&with("Cookie", &cookie)), None);
+
+    let id = auth.login(HOME, "bob", "bob-secret").unwrap();
+    let cookie = format!("session={}", id);
+    auth.logout(&with("Cookie", &cookie));
+    assert_eq!(auth.identify(&with("Cookie", &cookie)), None);
+  }
+
+  #[test]
+  fn login_needs_the_right_password() {
+    let mut auth = auth();
+    let a = auth.login(HOME, "ann", "ann-secret").unwrap();
+    let b = auth.login(HOME, "ann", "ann-secret").unwrap();
+    assert_ne!(a, b);
+    assert_eq!(
+      auth.login(HOME, "ann", "bob-secret"),
+      Err(LoginError::Wrong)
+    );
+    assert_eq!(auth.login(HOME, "ann", "ann-secre"), Err(LoginError::Wrong));
+    assert_eq!(
+      auth.login(HOME, "eve", "ann-secret"),
+      Err(LoginError::Wrong)
+    );
+    assert_eq!(auth.login(HOME, "", ""), Err(LoginError::Wrong));
+  }
+
+  #[test]
+  fn too_many_wrong_passwords_lock_the_address_out() {
+    let mut auth = auth();
+    for _ in 0..MAX_FAILURES - 1 {
+      assert_eq!(auth.login(AWAY, "ann", "guess"), Err(LoginError::Wrong));
+    }
+    // A success in time starts the count again
+    assert!(auth.login(AWAY, "ann", "ann-secret").is_ok());
+
+    for _ in 0..MAX_FAILURES {
+      assert_eq!(auth.login(AWAY, "ann", "guess"), Err(LoginError::Wrong));
+    }
+    assert_eq!(
+      auth.login(AWAY, "ann", "ann-secret"),
+      Err(LoginError::TooMany)
+    );
+    // Only that address
+    assert!(auth.login(HOME, "ann", "ann-secret").is_ok());
+
+    auth.failures.get_mut(&AWAY).unwrap().last = Instant::now() - LOCKOUT;
+    assert!(auth.login(AWAY, "ann", "ann-secret").is_ok());
+  }
+
+  #[test]
+  fn same_compares_whole_strings() {
+    assert!(same("secret", "secret"));
+    assert!(same("", ""));
+    assert!(!same("secret", "secreT"));
+    assert!(!same("secret", "secre"));
+    assert!(!same("secret", "secrets"));
+    assert!(!same("", "x"));
+  }
+
+  #[test]
+  fn random_hex_is_128_bits() {
+    let a = random_hex();
+    assert_eq!(a.len(), 32);
+    assert!(a.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_ne!(a, random_hex());
+  }
+}
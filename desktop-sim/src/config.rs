@@ -0,0 +1,427 @@
+// config.rs - Settings from desktop-sim.toml, overridden by command-line flags
+use crate::auth::{Role, User};
+use std::fs::{File, OpenOptions};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: desktop-sim [options]
+
+  --config <file>     Settings file (default desktop-sim.toml, optional)
+  --bind <addr:port>  Web server address (default 127.0.0.1:3000)
+  --assets <dir>      Serve the web UI from here instead of the built-in copy
+  --speed <x>         Simulated seconds per real second (default 60)
+  --log <file>        Also append every message to this file
+  --jets-limit <secs> Jets switch themselves off after this long
+                      (default 1200, 0 for no limit)
+  --real-delays       Honour routine delays instead of skipping them
+  --no-real-delays    Skip them, the default
+  --tls               Serve HTTPS, making a self-signed certificate if
+                      there is none yet
+  --no-tls            Serve plain HTTP, the default
+  --tls-cert <file>   Certificate PEM (default desktop-sim-cert.pem)
+  --tls-key <file>    Private key PEM (default desktop-sim-key.pem)
+  --mqtt <host[:port]>  Bridge to this MQTT broker (port 1883 if left off);
+                        IPv6 as [addr]:port
+  --mqtt-prefix <name>  Topic prefix and Home Assistant device id
+                        (default poolmax)
+  -h, --help          Show this and exit
+
+Flags win over the settings file, which uses the same names:
+bind, assets, speed, log_file, jets_limit_secs, real_delays, tls,
+tls_cert, tls_key, mqtt, mqtt_prefix, mqtt_user, mqtt_password. Logins,
+API tokens, maintenance_file and scenes_file are only set there, logins
+and tokens as [users.<name>] and [tokens] tables.";
+
+const DEFAULT_FILE: &str = "desktop-sim.toml";
+
+pub struct Config {
+  pub bind: SocketAddr,
+  /// Web UI override; the copy built into the binary when unset
+  pub assets: Option<PathBuf>,
+  pub speed: f64,
+  pub log_file: Option<PathBuf>,
+  /// Run counters, kept across restarts
+  pub maintenance_file: PathBuf,
+  /// User scenes on top of the built-ins
+  pub scenes_file: PathBuf,
+  /// Jets run-time limit in simulated seconds, 0 for none
+  pub jets_limit_secs: u64,
+  /// False runs with `internal_test`, skipping routine delays
+  pub real_delays: bool,
+  /// Web UI logins
+  pub users: Vec<User>,
+  /// API tokens and the role each one gets
+  pub tokens: Vec<(String, Role)>,
+  /// Serve HTTPS so passwords don't cross the network in the clear
+  pub tls: bool,
+  pub tls_cert: PathBuf,
+  pub tls_key: PathBuf,
+  /// Broker host and port; no MQTT bridge when unset
+  pub mqtt: Option<(String, u16)>,
+  pub mqtt_prefix: String,
+  pub mqtt_user: Option<String>,
+  pub mqtt_password: Option<String>,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      bind: SocketAddr::from(([127, 0, 0, 1], 3000)),
+      assets: None,
+      speed: 60.0,
+      log_file: None,
+      maintenance_file: PathBuf::from("maintenance.toml"),
+      scenes_file: PathBuf::from("scenes.toml"),
+      jets_limit_secs: 20 * 60,
+      real_delays: false,
+      users: Vec::new(),
+      tokens: Vec::new(),
+      tls: false,
+      tls_cert: PathBuf::from("desktop-sim-cert.pem"),
+      tls_key: PathBuf::from("desktop-sim-key.pem"),
+      mqtt: None,
+      mqtt_prefix: "poolmax".to_string(),
+      mqtt_user: None,
+      mqtt_password: None,
+    }
+  }
+}
+
+/// What `main` should do with the command line
+pub enum Start {
+  Run(Box<Config>),
+  Help,
+}
+
+impl Config {
+  /// The settings file, then `args` (without the program name) on top
+  pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Start, String> {
+    let args: Vec<String> = args.into_iter().collect();
+
+    // The file comes first so flags can override it
+    let mut config = Config::default();
+    let mut file = None;
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+      match a.as_str() {
+        "-h" | "--help" => return Ok(Start::Help),
+        "--config" => file = Some(value(&mut it, a)?.to_string()),
+        _ => {}
+      }
+    }
+    match &file {
+      Some(path) => config.load(path, true)?,
+      None => config.load(DEFAULT_FILE, false)?,
+    }
+
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+      match a.as_str() {
+        "--config" => {
+          it.next();
+        }
+        "--bind" => config.set("bind", value(&mut it, a)?)?,
+        "--assets" => config.set("assets", value(&mut it, a)?)?,
+        "--speed" => config.set("speed", value(&mut it, a)?)?,
+        "--log" => config.set("log_file", value(&mut it, a)?)?,
+        "--jets-limit" => config.set("jets_limit_secs", value(&mut it, a)?)?,
+        "--real-delays" => config.real_delays = true,
+        "--no-real-delays" => config.real_delays = false,
+        "--tls" => config.tls = true,
+        "--no-tls" => config.tls = false,
+        "--tls-cert" => config.set("tls_cert", value(&mut it, a)?)?,
+        "--tls-key" => config.set("tls_key", value(&mut it, a)?)?,
+        "--mqtt" => config.set("mqtt", value(&mut it, a)?)?,
+        "--mqtt-prefix" => config.set("mqtt_prefix", value(&mut it, a)?)?,
+        _ => return Err(format!("unknown option {}, see --help", a)),
+      }
+    }
+
+    config.validate()?;
+    Ok(Start::Run(Box::new(config)))
+  }
+
+  /// Apply `path`. A missing file is only an error if it was asked for.
+  fn load(&mut self, path: &str, required: bool) -> Result<(), String> {
+    let text = match std::fs::read_to_string(path) {
+      Ok(t) => t,
+      Err(e) if required => return Err(format!("{}: {}", path, e)),
+      Err(_) => return Ok(()),
+    };
+    let table: toml::Table = text.parse().map_err(|e| format!("{}: {}", path, e))?;
+
+    for (key, v) in table {
+      let text = match (&v, key.as_str()) {
+        (toml::Value::Boolean(b), "real_delays") => {
+          self.real_delays = *b;
+          continue;
+        }
+        (toml::Value::Boolean(b), "tls") => {
+          self.tls = *b;
+          continue;
+        }
+        (_, "real_delays" | "tls") => {
+          return Err(format!("{}: {} = {} is not true or false", path, key, v))
+        }
+        (toml::Value::String(s), _) => s.clone(),
+        (toml::Value::Integer(_) | toml::Value::Float(_), "speed") => v.to_string(),
+        (toml::Value::Integer(_), "jets_limit_secs") => v.to_string(),
+        (toml::Value::Table(t), "users") => {
+          for (name, u) in t {
+            let user = user(name, u).map_err(|e| format!("{}: {}", path, e))?;
+            self.users.push(user);
+          }
+          continue;
+        }
+        (toml::Value::Table(t), "tokens") => {
+          for (token, role) in t {
+            let role = role
+              .as_str()
+              .and_then(Role::from_name)
+              .ok_or_else(|| format!("{}: token role {} is not viewer or operator", path, role))?;
+            self.tokens.push((token.clone(), role));
+          }
+          continue;
+        }
+        _ => return Err(format!("{}: bad {} = {}", path, key, v)),
+      };
+      self
+        .set(&key, &text)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(())
+  }
+
+  fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+    match key {
+      "bind" => {
+        self.bind = value
+          .parse()
+          .map_err(|_| format!("bind {} is not an address like 127.0.0.1:3000", value))?
+      }
+      "assets" => self.assets = Some(PathBuf::from(value)),
+      "speed" => {
+        self.speed = value
+          .parse()
+          .map_err(|_| format!("speed {} is not a number", value))?
+      }
+      "log_file" => self.log_file = Some(PathBuf::from(value)),
+      "maintenance_file" => self.maintenance_file = PathBuf::from(value),
+      "scenes_file" => self.scenes_file = PathBuf::from(value),
+      "jets_limit_secs" => {
+        self.jets_limit_secs = value
+          .parse()
+          .map_err(|_| format!("jets_limit_secs {} is not a whole number of seconds", value))?
+      }
+      "tls_cert" => self.tls_cert = PathBuf::from(value),
+      "tls_key" => self.tls_key = PathBuf::from(value),
+      "mqtt" => self.mqtt = Some(host_port(value, 1883)?),
+      "mqtt_prefix" => self.mqtt_prefix = value.to_string(),
+      "mqtt_user" => self.mqtt_user = Some(value.to_string()),
+      "mqtt_password" => self.mqtt_password = Some(value.to_string()),
+      _ => return Err(format!("unknown setting {}", key)),
+    }
+    Ok(())
+  }
+
+  fn validate(&self) -> Result<(), String> {
+    if !(self.speed.is_finite() && self.speed > 0.0) {
+      return Err(format!("speed {} must be above 0", self.speed));
+    }
+    // Also the Home Assistant node id, which allows no more than this
+    let prefix_ok = !self.mqtt_prefix.is_empty()
+      && self
+        .mqtt_prefix
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !prefix_ok {
+      return Err(format!(
+        "mqtt_prefix {} must be letters, digits, _ or -",
+        self.mqtt_prefix
+      ));
+    }
+    if let Some(dir) = &self.assets {
+      if !dir.join("index.html").is_file() {
+        return Err(format!("assets: no index.html in {}", dir.display()));
+      }
+    }
+    Ok(())
+  }
+
+  /// Opened for appending, so a bad path fails at startup rather than on
+  /// the first message
+  pub fn open_log(&self) -> Result<Option<File>, String> {
+    let Some(path) = &self.log_file else {
+      return Ok(None);
+    };
+    OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .map(Some)
+      .map_err(|e| format!("log file {}: {}", path.display(), e))
+  }
+}
+
+// host, host:port, [v6 addr] or [v6 addr]:port; a bare v6 address gets
+// the default port
+fn host_port(value: &str, default_port: u16) -> Result<(String, u16), String> {
+  let (host, port) = match value.strip_prefix('[') {
+    Some(rest) => {
+      let (host, after) = rest
+        .split_once(']')
+        .ok_or_else(|| format!("mqtt {} has no closing ]", value))?;
+      match after {
+        "" => (host, None),
+        _ => match after.strip_prefix(':') {
+          Some(port) => (host, Some(port)),
+          None => return Err(format!("mqtt {} has junk after ]", value)),
+        },
+      }
+    }
+    None if value.matches(':').count() > 1 => (value, None),
+    None => match value.rsplit_once(':') {
+      Some((host, port)) => (host, Some(port)),
+      None => (value, None),
+    },
+  };
+  if host.is_empty() {
+    return Err(format!("mqtt {} has no host", value));
+  }
+  let port = match port {
+    Some(p) => p
+      .parse()
+      .map_err(|_| format!("mqtt port {} is not a number", p))?,
+    None => default_port,
+  };
+  Ok((host.to_string(), port))
+}
+
+fn value<'a>(it: &mut std::slice::Iter<'a, String>, flag: &str) -> Result<&'a str, String> {
+  it.next()
+    .map(|s| s.as_str())
+    .ok_or_else(|| format!("{} needs a value", flag))
+}
+
+// One [users.<name>] table
+fn user(name: &str, t: &toml::Value) -> Result<User, String> {
+  let field = |key| t.get(key).and_then(|v| v.as_str());
+  let password = field("password")
+    .filter(|p| !p.is_empty())
+    .ok_or_else(|| format!("user {} needs a password", name))?;
+  let role = field("role")
+    .and_then(Role::from_name)
+    .ok_or_else(|| format!("user {} needs role = \"viewer\" or \"operator\"", name))?;
+  Ok(User {
+    name: name.to_string(),
+    password: password.to_string(),
+    role,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Written under the temp dir, named for the test so they run in parallel
+  fn settings(name: &str, text: &str) -> String {
+    let path =
+      std::env::temp_dir().join(format!("desktop-sim-{}-{}.toml", std::process::id(), name));
+    std::fs::write(&path, text).unwrap();
+    path.to_string_lossy().into_owned()
+  }
+
+  fn run(args: &[&str]) -> Result<Config, String> {
+    match Config::from_args(args.iter().map(|a| a.to_string()))? {
+      Start::Run(c) => Ok(*c),
+      Start::Help => Err("help".to_string()),
+    }
+  }
+
+  #[test]
+  fn flags_win_over_the_file_over_defaults() {
+    let file = settings(
+      "precedence",
+      "speed = 5\njets_limit_secs = 600\nscenes_file = \"/etc/poolmax/scenes.toml\"\nbind = \"0.0.0.0:8080\"\ntls = true\nmqtt = \"broker\"\n",
+    );
+    let c = run(&["--config", &file, "--speed", "10", "--no-tls"]).unwrap();
+    std::fs::remove_file(file).ok();
+
+    assert_eq!(c.speed, 10.0);
+    assert_eq!(c.jets_limit_secs, 600);
+    assert_eq!(c.scenes_file, PathBuf::from("/etc/poolmax/scenes.toml"));
+    assert_eq!(c.maintenance_file, PathBuf::from("maintenance.toml"));
+    assert!(!c.tls);
+    assert_eq!(c.bind, SocketAddr::from(([0, 0, 0, 0], 8080)));
+    assert_eq!(c.mqtt, Some(("broker".to_string(), 1883)));
+    assert_eq!(c.tls_cert, PathBuf::from("desktop-sim-cert.pem"));
+    assert_eq!(c.mqtt_prefix, "poolmax");
+    assert!(!c.real_delays);
+  }
+
+  #[test]
+  fn bad_values_are_refused() {
+    let refused = |args: &[&str], why: &str| {
+      let e = run(args).err().unwrap_or_default();
+      assert!(e.contains(why), "{:?} gave {:?}", args, e);
+    };
+    let file = settings("bad", "");
+    let with = |extra: &[&'static str]| [&["--config", file.as_str()][..], extra].concat();
+
+    refused(&with(&["--speed", "fast"]), "not a number");
+    refused(&with(&["--speed", "0"]), "must be above 0");
+    refused(&with(&["--jets-limit", "-5"]), "whole number");
+    refused(&with(&["--jets-limit", "1.5"]), "whole number");
+    refused(&with(&["--bind", "nowhere"]), "not an address");
+    refused(&with(&["--mqtt", "broker:port"]), "not a number");
+    refused(&with(&["--mqtt", ":1883"]), "no host");
+    refused(&with(&["--mqtt-prefix", "pool/max"]), "letters, digits");
+    refused(&with(&["--speed"]), "needs a value");
+    refused(&with(&["--fast"]), "unknown option");
+    refused(&["--config", "no-such-file.toml"], "no-such-file.toml");
+    std::fs::remove_file(&file).ok();
+
+    for (name, text, why) in [
+      ("key", "colour = \"blue\"\n", "unknown setting"),
+      ("type", "tls = \"yes\"\n", "not true or false"),
+      ("jets", "jets_limit_secs = 90.5\n", "bad jets_limit_secs"),
+      (
+        "user",
+        "[users.ann]\nrole = \"viewer\"\n",
+        "needs a password",
+      ),
+      (
+        "role",
+        "[users.ann]\npassword = \"x\"\nrole = \"root\"\n",
+        "needs role",
+      ),
+      ("token", "[tokens]\nabc = \"admin\"\n", "token role"),
+    ] {
+      let file = settings(name, text);
+      refused(&["--config", &file], why);
+      std::fs::remove_file(file).ok();
+    }
+  }
+
+  #[test]
+  fn mqtt_hosts_take_ipv6() {
+    for (value, host, port) in [
+      ("broker", "broker", 1883),
+      ("broker:8883", "broker", 8883),
+      ("10.0.0.5:1884", "10.0.0.5", 1884),
+      ("[::1]:8883", "::1", 8883),
+      ("[fe80::1]", "fe80::1", 1883),
+      ("fe80::1", "fe80::1", 1883),
+    ] {
+      assert_eq!(
+        host_port(value, 1883),
+        Ok((host.to_string(), port)),
+        "{}",
+        value
+      );
+    }
+    assert!(host_port("[::1", 1883).is_err());
+    assert!(host_port("[::1]8883", 1883).is_err());
+  }
+}
@@ -0,0 +1,702 @@
+// http.rs - The web UI's API, one thread per request
+use crate::assets::{self, Assets};
+use crate::auth::{Auth, LoginError};
+use crate::{maintenance, SimSystem};
+use app_core::chemistry::DosePump;
+use app_core::history::Sample;
+use app_core::log_msg;
+use app_core::maintenance::Reminder;
+use app_core::pool_light::{self, SHOWS};
+use app_core::scene::Scene;
+use app_core::structs::{PoolOrSpa, StopSignal};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+// Web panel buttons: id for /toggle-button, section, label, then the lights
+// next to it as "light index:caption". Aux circuits are appended after these.
+const PANEL: [(&str, &str, &str, &str); 8] = [
+  ("0", "", "Auto Spa", "0:on"),
+  ("1", "", "⏻ Jets", "1:on"),
+  ("2", "Filter", "Run Schedule", "2:on"),
+  ("3", "Filter", "⏻ Clean (3hrs)", "3:on"),
+  ("4", "Valves", "Main", "4:Spa,5:Pool"),
+  ("8", "Valves", "Spillover", ""),
+  ("6", "Heater", "⏻ Heater", "9:on"),
+  ("7", "Heater", "Heat Mode", "10:Spa,11:Pool"),
+];
+
+pub struct Api {
+  pub system: Arc<Mutex<SimSystem>>,
+  pub auth: Mutex<Auth>,
+  pub assets: Assets,
+  pub scenes: Vec<Scene>,
+  /// Raised before taking the lock, so a routine holding it stops
+  pub stop: StopSignal,
+  /// Appended to the session cookie
+  pub secure: &'static str,
+  /// Where acknowledged reminders are saved
+  pub maintenance_file: PathBuf,
+}
+
+/// Each request gets its own thread. A routine started over HTTP holds the
+/// system for as long as it runs, and an emergency stop must not queue
+/// behind it.
+pub fn serve(server: Server, api: Arc<Api>) {
+  for request in server.incoming_requests() {
+    let api = api.clone();
+    thread::spawn(move || api.handle(request));
+  }
+}
+
+impl Api {
+  fn handle(&self, mut request: Request) {
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    let method = request.method();
+
+    if let Some(need) = Auth::required(method, path, self.assets.has(path)) {
+      let refused = match self.auth.lock().unwrap().identify(&request) {
+        Some((_, role)) if role >= need => None,
+        Some(_) => Some(Response::from_string("Operator role needed").with_status_code(403)),
+        None => Some(Response::from_string("Log in first").with_status_code(401)),
+      };
+      if let Some(response) = refused {
+        request.respond(response).ok();
+        return;
+      }
+    }
+
+    match (method, path) {
+      (Method::Post, "/login") => {
+        // Name on the first line, password on the second
+        let mut content = String::new();
+        request.as_reader().read_to_string(&mut content).ok();
+        let (name, password) = content.split_once('\n').unwrap_or((&content, ""));
+        let from = request
+          .remote_addr()
+          .map_or(IpAddr::from([0, 0, 0, 0]), |a| a.ip());
+
+        let response = match self.auth.lock().unwrap().login(
+          from,
+          name.trim(),
+          password.trim_end_matches(['\r', '\n']),
+        ) {
+          Ok(id) => Response::from_string("OK").with_header(
+            Header::from_bytes(
+              "Set-Cookie",
+              format!(
+                "session={}; HttpOnly; SameSite=Strict; Path=/{}",
+                id, self.secure
+              ),
+            )
+            .unwrap(),
+          ),
+          Err(LoginError::Wrong) => {
+            Response::from_string("Wrong name or password").with_status_code(401)
+          }
+          Err(LoginError::TooMany) => {
+            Response::from_string("Too many wrong passwords, try again in a few minutes")
+              .with_status_code(429)
+          }
+        };
+        request.respond(response).ok();
+      }
+      (Method::Post, "/logout") => {
+        self.auth.lock().unwrap().logout(&request);
+        let response = Response::from_string("OK")
+          .with_header(Header::from_bytes("Set-Cookie", "session=; Max-Age=0; Path=/").unwrap());
+        request.respond(response).ok();
+      }
+      (Method::Get, "/whoami") => {
+        // Name then role
+        let response = match self.auth.lock().unwrap().identify(&request) {
+          Some((name, role)) => Response::from_string(format!("{}\t{}", name, role)),
+          None => Response::from_string("Log in first").with_status_code(401),
+        };
+        request.respond(response).ok();
+      }
+      (Method::Get, "/lights-status") => {
+        let mut sys = self.system.lock().unwrap();
+        // Bit N is light-N; all Light::COUNT fit in a u16
+        let lights = sys.get_light_status();
+        let mut bits: u16 = 0;
+        for (i, &light_on) in lights.iter().enumerate() {
+          if light_on {
+            bits |= 1 << i;
+          }
+        }
+        request
+          .respond(Response::from_data(bits.to_be_bytes()))
+          .ok();
+      }
+      (Method::Get, "/lights-patterns") => {
+        // One byte per light, see LightPattern for the codes
+        let sys = self.system.lock().unwrap();
+        let frame = sys.light_frame();
+        let codes: Vec<u8> = frame.patterns.iter().map(|p| *p as u8).collect();
+        request.respond(Response::from_data(codes)).ok();
+      }
+      (Method::Post, "/toggle-button") => {
+        let mut content = String::new();
+        request.as_reader().read_to_string(&mut content).ok();
+        let value: i32 = content.trim().parse().unwrap_or(-1);
+
+        let mut sys = self.system.lock().unwrap();
+        match value {
+          0 => {
+            sys.auto_spa(None);
+          }
+          1 => {
+            sys.toggle_jets();
+          }
+          2 => {
+            sys.toggle_filter_schedule();
+          }
+          3 => {
+            sys.toggle_quick_clean();
+          }
+          4 => {
+            sys.toggle_main_valves();
+          }
+          8 => {
+            sys.toggle_spillover();
+          }
+          6 => {
+            sys.toggle_heater_on();
+          }
+          7 => {
+            sys.toggle_heat_mode();
+          }
+          _ => {}
+        }
+        request.respond(Response::from_string("OK")).ok();
+      }
+      (Method::Post, "/emergency-stop") => {
+        self.stop.raise();
+        self.system.lock().unwrap().emergency_stop();
+        request.respond(Response::from_string("OK")).ok();
+      }
+      (Method::Post, "/acknowledge") => {
+        let ok = self.system.lock().unwrap().acknowledge_emergency_stop();
+        let response = if ok {
+          Response::from_string("OK")
+        } else {
+          Response::from_string("No emergency stop latched").with_status_code(409)
+        };
+        request.respond(response).ok();
+      }
+      (Method::Get, "/jets-remaining") => {
+        // Whole seconds, empty while the jets are off or unlimited
+        let sys = self.system.lock().unwrap();
+        let left = match sys.jets_remaining_secs {
+          Some(s) if sys.jets_on => s.to_string(),
+          _ => String::new(),
+        };
+        request.respond(Response::from_string(left)).ok();
+      }
+      (Method::Get, "/controls") => {
+        let sys = self.system.lock().unwrap();
+        let mut lines: Vec<String> = PANEL
+          .iter()
+          .map(|(id, section, label, lights)| format!("{}\t{}\t{}\t{}", id, section, label, lights))
+          .collect();
+        for (i, c) in sys.aux.iter().enumerate() {
+          lines.push(format!("aux-{}\tAux\t{}\taux-{}:on", i, c.name, i));
+        }
+        request
+          .respond(Response::from_string(lines.join("\n")))
+          .ok();
+      }
+      (Method::Get, "/aux") => {
+        // index, name, on (0/1), seconds left (empty if untimed)
+        let sys = self.system.lock().unwrap();
+        let lines: Vec<String> = sys
+          .aux
+          .iter()
+          .enumerate()
+          .map(|(i, c)| {
+            let left = c.remaining_secs.map(|s| s.to_string()).unwrap_or_default();
+            format!("{}\t{}\t{}\t{}", i, c.name, c.on as u8, left)
+          })
+          .collect();
+        request
+          .respond(Response::from_string(lines.join("\n")))
+          .ok();
+      }
+      (Method::Post, p) if p.starts_with("/aux/") => {
+        let index = p["/aux/".len()..].parse::<usize>().ok();
+        let mut content = String::new();
+        request.as_reader().read_to_string(&mut content).ok();
+
+        let mut sys = self.system.lock().unwrap();
+        let response = match index.filter(|&i| i < sys.aux.len()) {
+          None => Response::from_string("Unknown circuit").with_status_code(404),
+          Some(i) => {
+            match content.trim() {
+              "on" => {
+                sys.set_aux(i, true);
+              }
+              "off" => {
+                sys.set_aux(i, false);
+              }
+              _ => {
+                sys.toggle_aux(i);
+              }
+            }
+            Response::from_string("OK")
+          }
+        };
+        request.respond(response).ok();
+      }
+      (Method::Get, "/pool-light") => {
+        // on (0/1), show index (empty if unknown), show name
+        let sys = self.system.lock().unwrap();
+        let light = sys.pool_light;
+        let show = light.show.map(|s| s.to_string()).unwrap_or_default();
+        request
+          .respond(Response::from_string(format!(
+            "{}\t{}\t{}",
+            light.on as u8,
+            show,
+            light.show_name()
+          )))
+          .ok();
+      }
+      (Method::Get, "/pool-light/shows") => {
+        request
+          .respond(Response::from_string(SHOWS.join("\n")))
+          .ok();
+      }
+      (Method::Post, "/pool-light") => {
+        // on, off, toggle, resync, or a show by name or number
+        let mut content = String::new();
+        request.as_reader().read_to_string(&mut content).ok();
+
+        let mut sys = self.system.lock().unwrap();
+        let known = match content.trim() {
+          "on" => {
+            sys.set_pool_light(true);
+            true
+          }
+          "off" => {
+            sys.set_pool_light(false);
+            true
+          }
+          "toggle" => {
+            sys.toggle_pool_light();
+            true
+          }
+          "resync" => {
+            sys.resync_pool_light();
+            true
+          }
+          show => match pool_light::find_show(show) {
+            Some(i) => {
+              sys.select_light_show(i);
+              true
+            }
+            None => false,
+          },
+        };
+        let response = if known {
+          Response::from_string("OK")
+        } else {
+          Response::from_string("Unknown show").with_status_code(400)
+        };
+        request.respond(response).ok();
+      }
+      (Method::Get, "/chlorinator") => {
+        // output %, pool %, spa %, salt ppm, cell temp F, super-chlorinate
+        // seconds left, why it's held off; unknowns are empty
+        let sys = self.system.lock().unwrap();
+        let c = sys.chlorinator;
+        let opt = |v: Option<String>| v.unwrap_or_default();
+        let line = format!(
+          "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+          c.output,
+          c.pool_percent,
+          c.spa_percent,
+          opt(c.salt_ppm.map(|s| s.to_string())),
+          opt(c.cell_temp_f.map(|t| format!("{:.0}", t))),
+          opt(c.super_remaining_secs.map(|s| s.to_string())),
+          c.held_off.unwrap_or_default()
+        );
+        request.respond(Response::from_string(line)).ok();
+      }
+      (Method::Post, "/chlorinator") => {
+        // "pool <percent>", "spa <percent>" or "super on|off|toggle"
+        let mut content = String::new();
+        request.as_reader().read_to_string(&mut content).ok();
+        let mut parts = content.split_whitespace();
+
+        let mut sys = self.system.lock().unwrap();
+        let ok = match (parts.next(), parts.next()) {
+          (Some("super"), Some("on")) => {
+            sys.set_super_chlorinate(true);
+            true
+          }
+          (Some("super"), Some("off")) => {
+            sys.set_super_chlorinate(false);
+            true
+          }
+          (Some("super"), Some("toggle")) => {
+            sys.toggle_super_chlorinate();
+            true
+          }
+          (Some(body @ ("pool" | "spa")), Some(p)) => {
+            let body = if body == "pool" {
+              PoolOrSpa::Pool
+            } else {
+              PoolOrSpa::Spa
+            };
+            match p.parse::<u8>() {
+              Ok(p) if p <= 100 => {
+                sys.set_chlorinator_percent(body, p);
+                true
+              }
+              _ => false,
+            }
+          }
+          _ => false,
+        };
+        let response = if ok {
+          Response::from_string("OK")
+        } else {
+          Response::from_string("Bad chlorinator command").with_status_code(400)
+        };
+        request.respond(response).ok();
+      }
+      (Method::Get, "/chemistry") => {
+        // pH, ORP mV, pH target, ORP target, then per pump (acid,
+        // chlorine): on (0/1), ml today, max ml a day. Unknowns are empty.
+        let sys = self.system.lock().unwrap();
+        let chem = &sys.chemistry;
+        let mut fields = vec![
+          chem.ph.map(|v| format!("{:.2}", v)).unwrap_or_default(),
+          chem.orp_mv.map(|v| format!("{:.0}", v)).unwrap_or_default(),
+          format!("{:.1}", chem.ph_target),
+          format!("{:.0}", chem.orp_target_mv),
+        ];
+        for pump in [DosePump::Acid, DosePump::Chlorine] {
+          let d = chem.doser(pump);
+          fields.push((d.on as u8).to_string());
+          fields.push(format!("{:.0}", d.dosed_ml_today));
+          fields.push(format!("{:.0}", d.max_ml_per_day));
+        }
+        request
+          .respond(Response::from_string(fields.join("\t")))
+          .ok();
+      }
+      (Method::Post, "/chemistry") => {
+        // "ph <target>" or "orp <target mV>"
+        let mut content = String::new();
+        request.as_reader().read_to_string(&mut content).ok();
+        let mut parts = content.split_whitespace();
+
+        let mut sys = self.system.lock().unwrap();
+        let ok = match (parts.next(), parts.next().and_then(|v| v.parse().ok())) {
+          (Some("ph"), Some(v)) => sys.set_ph_target(v),
+          (Some("orp"), Some(v)) => sys.set_orp_target(v),
+          _ => false,
+        };
+        let response = if ok {
+          Response::from_string("OK")
+        } else {
+          Response::from_string("Bad chemistry command").with_status_code(400)
+        };
+        request.respond(response).ok();
+      }
+      (Method::Get, "/history") => {
+        // ?tier=minutes|hours|days, CSV oldest first
+        let tier = query
+          .split('&')
+          .find_map(|pair| pair.strip_prefix("tier="))
+          .unwrap_or("minutes");
+        let sys = self.system.lock().unwrap();
+        let h = &sys.history;
+        let rows: Option<Vec<String>> = match tier {
+          "minutes" => Some(h.minutes.iter().map(history_row).collect()),
+          "hours" => Some(h.hours.iter().map(history_row).collect()),
+          "days" => Some(h.days.iter().map(history_row).collect()),
+          _ => None,
+        };
+        let response = match rows {
+          Some(rows) => Response::from_string(format!(
+            "start_secs,pool_f,spa_f,ph,orp_mv,pump_secs,heater_secs,valve_changes,dosed_ml,energy_wh\n{}",
+            rows.join("\n")
+          )),
+          None => Response::from_string("Unknown tier").with_status_code(400),
+        };
+        request.respond(response).ok();
+      }
+      (Method::Get, "/pressure") => {
+        // psi, clean baseline, warn at rise, backwash due (0/1).
+        // Unknowns are empty.
+        let sys = self.system.lock().unwrap();
+        let p = &sys.pressure;
+        let fields = [
+          p.psi.map(|v| format!("{:.1}", v)).unwrap_or_default(),
+          p.baseline_psi
+            .map(|v| format!("{:.1}", v))
+            .unwrap_or_default(),
+          format!("{:.1}", p.rise_psi),
+          (p.backwash_due as u8).to_string(),
+        ];
+        request
+          .respond(Response::from_string(fields.join("\t")))
+          .ok();
+      }
+      (Method::Post, "/pressure/relearn") => {
+        self.system.lock().unwrap().relearn_filter_pressure();
+        request.respond(Response::from_string("OK")).ok();
+      }
+      (Method::Get, "/backwash") => {
+        // Multiport position, backwash secs, rinse secs, then simulated
+        // secs since the last one finished, empty if never
+        let sys = self.system.lock().unwrap();
+        let b = &sys.backwash;
+        let now = sys.history.now_secs();
+        let fields = [
+          b.position.to_string(),
+          b.backwash_secs.to_string(),
+          b.rinse_secs.to_string(),
+          b.last_history_secs
+            .map(|s| now.saturating_sub(s).to_string())
+            .unwrap_or_default(),
+        ];
+        request
+          .respond(Response::from_string(fields.join("\t")))
+          .ok();
+      }
+      (Method::Post, "/backwash") => {
+        let response = if self.system.lock().unwrap().backwash() {
+          Response::from_string("OK")
+        } else {
+          Response::from_string("Backwash did not finish").with_status_code(409)
+        };
+        request.respond(response).ok();
+      }
+      (Method::Get, "/maintenance") => {
+        // Run hours for pump, heater and jets, then valve cycles, then
+        // one line per reminder: name, due (0/1), since, every, unit
+        let sys = self.system.lock().unwrap();
+        let m = &sys.maintenance;
+        let mut lines = vec![format!(
+          "{}\t{}\t{}\t{}",
+          m.pump_secs / 3600,
+          m.heater_secs / 3600,
+          m.jets_secs / 3600,
+          m.valve_cycles
+        )];
+        for r in Reminder::ALL {
+          lines.push(format!(
+            "{}\t{}\t{}\t{}\t{}",
+            r.name(),
+            m.is_due(r) as u8,
+            m.since_service(r),
+            m.intervals[r as usize],
+            r.unit()
+          ));
+        }
+        request
+          .respond(Response::from_string(lines.join("\n")))
+          .ok();
+      }
+      (Method::Post, "/maintenance/ack") => {
+        // Body is a reminder name
+        let mut content = String::new();
+        request.as_reader().read_to_string(&mut content).ok();
+
+        let response = match Reminder::from_name(content.trim()) {
+          Some(r) => {
+            let mut sys = self.system.lock().unwrap();
+            if sys.acknowledge_reminder(r) {
+              // Done either way, but it comes back after a restart
+              match maintenance::save(&self.maintenance_file, &sys.maintenance) {
+                Ok(()) => Response::from_string("OK"),
+                Err(e) => {
+                  log_msg!(sys.message_queue, "Maintenance: {}", e);
+                  Response::from_string(format!("Acknowledged but not saved: {}", e))
+                    .with_status_code(500)
+                }
+              }
+            } else {
+              Response::from_string("Not due").with_status_code(400)
+            }
+          }
+          None => Response::from_string("Unknown reminder").with_status_code(404),
+        };
+        request.respond(response).ok();
+      }
+      (Method::Get, "/scenes") => {
+        let names: Vec<&str> = self.scenes.iter().map(|s| s.name.as_str()).collect();
+        request
+          .respond(Response::from_string(names.join("\n")))
+          .ok();
+      }
+      (Method::Post, "/scene") => {
+        let mut content = String::new();
+        request.as_reader().read_to_string(&mut content).ok();
+
+        let response = match self.scenes.iter().find(|s| s.name == content.trim()) {
+          Some(scene) => {
+            self.system.lock().unwrap().apply_scene(scene);
+            Response::from_string("OK")
+          }
+          None => Response::from_string("Unknown scene").with_status_code(404),
+        };
+        request.respond(response).ok();
+      }
+      (Method::Post, "/faults") => {
+        // Debug only: e.g. "fail main_valve", "stuck heater_on", "nth 3",
+        // "latency 500", "clear". Prefix with "lights " to target the lights.
+        let mut content = String::new();
+        request.as_reader().read_to_string(&mut content).ok();
+        let cmd = content.trim();
+
+        let sys = self.system.lock().unwrap();
+        let ok = match cmd.strip_prefix("lights ") {
+          Some(rest) => sys.lights.apply(rest),
+          None => sys.mech.faults.apply(cmd),
+        };
+        let response = if ok {
+          Response::from_string("OK")
+        } else {
+          Response::from_string("Bad fault command").with_status_code(400)
+        };
+        request.respond(response).ok();
+      }
+      (Method::Get, p) => {
+        let response = match self.assets.get(p) {
+          Some(asset) => assets::response(&request, &asset),
+          None => Response::from_string("Not Found").with_status_code(404),
+        };
+        request.respond(response).ok();
+      }
+      _ => {
+        request
+          .respond(Response::from_string("Not Found").with_status_code(404))
+          .ok();
+      }
+    }
+  }
+}
+
+/// One CSV row for `GET /history`; missing readings are empty
+fn history_row(s: &Sample) -> String {
+  let opt =
+    |v: Option<f32>, places: usize| v.map(|v| format!("{:.*}", places, v)).unwrap_or_default();
+  format!(
+    "{},{},{},{},{},{},{},{},{:.0},{:.0}",
+    s.start_secs,
+    opt(s.pool_temp_f, 1),
+    opt(s.spa_temp_f, 1),
+    opt(s.ph, 2),
+    opt(s.orp_mv, 0),
+    s.pump_secs,
+    s.heater_secs,
+    s.valve_changes,
+    s.dosed_ml,
+    s.energy_wh
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::auth::Role;
+  use crate::plant::SimMech;
+  use app_core::fault::{FaultyLights, FaultyMech};
+  use app_core::structs::{HasOSLights, System};
+  use std::io::{Read, Write};
+  use std::net::{SocketAddr, TcpStream};
+  use std::time::{Duration, Instant};
+
+  fn start() -> (SocketAddr, Arc<Mutex<SimSystem>>) {
+    let mut sys = System::new(
+      FaultyMech::new(SimMech::new(1.0)),
+      FaultyLights::new(HasOSLights),
+    );
+    // Real waits, so auto spa takes long enough to stop
+    sys.internal_test = false;
+    let stop = sys.stop.clone();
+    let system = Arc::new(Mutex::new(sys));
+    let api = Arc::new(Api {
+      system: system.clone(),
+      auth: Mutex::new(Auth::new(
+        Vec::new(),
+        vec![("t0ken".to_string(), Role::Operator)],
+      )),
+      assets: Assets::new(None),
+      scenes: Vec::new(),
+      stop,
+      secure: "",
+      maintenance_file: PathBuf::from("maintenance.toml"),
+    });
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let addr = server.server_addr().to_ip().unwrap();
+    thread::spawn(move || serve(server, api));
+    (addr, system)
+  }
+
+  // Status code and body
+  fn send(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+      stream,
+      "{} {} HTTP/1.1\r\nHost: test\r\nAuthorization: Bearer t0ken\r\n\
+       Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+      method,
+      path,
+      body.len(),
+      body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let body = response
+      .split_once("\r\n\r\n")
+      .map_or("", |(_, b)| b)
+      .to_string();
+    (response[9..12].parse().unwrap(), body)
+  }
+
+  fn post(addr: SocketAddr, path: &str, body: &str) -> u16 {
+    send(addr, "POST", path, body).0
+  }
+
+  #[test]
+  fn emergency_stop_aborts_a_routine_started_over_http() {
+    let (addr, system) = start();
+    let started = Instant::now();
+    let routine = thread::spawn(move || post(addr, "/toggle-button", "0"));
+
+    thread::sleep(Duration::from_millis(300));
+    // Still running, and holding the system
+    assert!(system.try_lock().is_err());
+    assert_eq!(post(addr, "/emergency-stop", ""), 200);
+
+    assert_eq!(routine.join().unwrap(), 200);
+    // Auto spa waits on valve travel for far longer than this
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let sys = system.lock().unwrap();
+    assert!(sys.estop_latched);
+    assert!(!sys.auto_spa_mode);
+    assert!(!sys.pump_running());
+  }
+
+  #[test]
+  fn history_is_its_own_path() {
+    let (addr, _) = start();
+    let (status, body) = send(addr, "GET", "/history", "");
+    assert_eq!(status, 200);
+    assert!(body.starts_with("start_secs,"));
+    assert_eq!(send(addr, "GET", "/history?tier=hours", "").0, 200);
+    assert_eq!(send(addr, "GET", "/history?x=1&tier=days", "").0, 200);
+    assert_eq!(send(addr, "GET", "/history?tier=weeks", "").0, 400);
+    assert_eq!(send(addr, "GET", "/historyfoo", "").0, 404);
+    assert_eq!(send(addr, "GET", "/history-anything?tier=days", "").0, 404);
+  }
+}
@@ -1,92 +1,173 @@
-use app_core::structs::{HasOSLights, HasOSMech, System};
+mod assets;
+mod auth;
+mod config;
+mod http;
+mod maintenance;
+mod mqtt;
+mod plant;
+mod scenes;
+mod sim_buttons;
+mod term_display;
+mod tls;
+
+use app_core::buttons::{Action, Button, ButtonProcessor};
+use app_core::circuits::AuxCircuit;
+use app_core::display::StatusScreen;
+use app_core::fault::{FaultyLights, FaultyMech};
+use app_core::history::History;
+use app_core::log_msg;
+use app_core::pool_light::SHOWS;
+use app_core::structs::{HasOSLights, System};
+use assets::Assets;
+use auth::{Auth, Role, User};
+use config::{Config, Start};
+use plant::SimMech;
+use sim_buttons::SimButtons;
 use std::io::{self, Write};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use term_display::TermDisplay;
 use termion::input::TermRead;
 use termion::raw::IntoRawMode;
 use termion::{clear, cursor};
-use tiny_http::{Header, Method, Response, Server};
+use tiny_http::Server;
+
+type SimSystem = System<FaultyMech<SimMech>, FaultyLights<HasOSLights>>;
+
+// Real time between saves of the run counters
+const SAVE_EVERY: Duration = Duration::from_secs(60);
+
+const CONTROLS: [&str; 21] = [
+  "  c - Toggle Quick Clean",
+  "  r - Toggle Filter Schedule",
+  "  h - Heater On",
+  "  j - Toggle Jets",
+  "  s - Spa Mode",
+  "  m - Switch Main Valve Orientation (Pool/Spa)",
+  "  f - Toggle Spillover (suction pool, return spa)",
+  "  k - Switch Heater Mode",
+  "  o - Toggle jets override (allow jets in pool mode)",
+  "  u - Pool light on/off, g - Next light show, y - Resync light",
+  "  z - Toggle super chlorinate",
+  "  w - Toggle suction air leak (pump can't prime)",
+  "  b - Backwash the filter",
+  "  p - Print Status",
+  "  0-7 - Tap panel button, Alt+0-7 - Long press",
+  "  x - Hold Filter + Clean buttons (stop filter)",
+  "  e - EMERGENCY STOP, a - Acknowledge stop",
+  "  d - Next display page, v - Display status/log",
+  "  [ / ] - Scroll display log back/forward",
+  "  l - Clear Screen",
+  "  q - Quit",
+];
+
+fn default_aux() -> Vec<AuxCircuit> {
+  vec![
+    AuxCircuit::new("Waterfall").needs_pump(),
+    AuxCircuit::new("Cleaner")
+      .needs_pump()
+      .limit_mins(180)
+      .daily(9 * 60, 12 * 60),
+    AuxCircuit::new("Blower").limit_mins(30),
+  ]
+}
 
 fn main() {
-  let system = Arc::new(Mutex::new(System::new(HasOSMech, HasOSLights)));
-  let server = Server::http("127.0.0.1:3000").unwrap();
+  let config = match Config::from_args(std::env::args().skip(1)) {
+    Ok(Start::Run(c)) => *c,
+    Ok(Start::Help) => {
+      println!("{}", config::USAGE);
+      return;
+    }
+    Err(e) => fail(&e),
+  };
+  let assets = Assets::new(config.assets.clone());
+  let mut log_file = config.open_log().unwrap_or_else(|e| fail(&e));
+  let server = if config.tls {
+    let ssl = tls::load_or_create(&config.tls_cert, &config.tls_key, config.bind)
+      .unwrap_or_else(|e| fail(&e));
+    Server::https(config.bind, ssl)
+  } else {
+    Server::http(config.bind)
+  }
+  .unwrap_or_else(|e| fail(&format!("can't listen on {}: {}", config.bind, e)));
+  let scheme = if config.tls { "https" } else { "http" };
+  // Kept off plain HTTP once it has been given over HTTPS
+  let secure = if config.tls { "; Secure" } else { "" };
+  let speed = config.speed;
 
-  {
+  // Nothing set up, so make a login up rather than leave the API open
+  let mut users = config.users.clone();
+  let generated = (users.is_empty() && config.tokens.is_empty()).then(|| {
+    let password = auth::random_hex()[..12].to_string();
+    users.push(User {
+      name: "admin".to_string(),
+      password: password.clone(),
+      role: Role::Operator,
+    });
+    password
+  });
+  let auth = Auth::new(users, config.tokens.clone());
+
+  let mech = SimMech::new(speed);
+  mech.spawn_stepper(Duration::from_millis(50));
+  let plant = mech.plant.clone();
+
+  let system = Arc::new(Mutex::new(System::new(
+    FaultyMech::new(mech),
+    FaultyLights::new(HasOSLights),
+  )));
+
+  // Raised without the lock, so a running routine sees it straight away
+  let stop = {
     let mut sys = system.lock().unwrap();
-    sys.internal_test = true;
+    sys.internal_test = !config.real_delays;
+    sys.jets_limit_secs = config.jets_limit_secs;
+    for c in default_aux() {
+      sys.add_aux(c);
+    }
+    sys.backwash.actuated = true;
+    // Line buckets up with the simulated clock
+    sys.history = History::new(plant.lock().unwrap().sim_secs as u64);
+    match maintenance::load(&config.maintenance_file) {
+      Ok(m) => sys.maintenance = m,
+      Err(e) => eprintln!("Maintenance: {}", e),
+    }
     eprintln!("System created, internal_test = {}", sys.internal_test);
+    sys.stop.clone()
+  };
+  if let Some((host, port)) = config.mqtt.clone() {
+    let settings = mqtt::Settings {
+      host,
+      port,
+      prefix: config.mqtt_prefix.clone(),
+      user: config.mqtt_user.clone(),
+      password: config.mqtt_password.clone(),
+    };
+    mqtt::start(settings, system.clone());
   }
 
-  let mut stdout = io::stdout().into_raw_mode().unwrap();
-  let html = std::fs::read_to_string("index.html").unwrap();
-  let system_clone = system.clone();
+  let mut stdout = io::stdout()
+    .into_raw_mode()
+    .unwrap_or_else(|e| fail(&format!("terminal: {}", e)));
+  let scenes = scenes::load(&config.scenes_file).unwrap_or_else(|e| {
+    eprintln!("Scenes: {}", e);
+    scenes::builtins()
+  });
 
+  let api = Arc::new(http::Api {
+    system: system.clone(),
+    auth: Mutex::new(auth),
+    assets,
+    scenes,
+    stop: stop.clone(),
+    secure,
+    maintenance_file: config.maintenance_file.clone(),
+  });
   thread::spawn(move || {
-    eprint!("Server running on http://127.0.0.1:3000");
-    for mut request in server.incoming_requests() {
-      let path = request.url();
-      let method = request.method();
-
-      match (method, path) {
-        (Method::Get, "/") => {
-          let response = Response::from_string(&html).with_header(
-            Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap(),
-          );
-          request.respond(response).ok();
-        }
-        (Method::Get, "/lights-status") => {
-          let mut sys = system_clone.lock().unwrap();
-          let lights = sys.get_light_status();
-          let mut bits: u16 = 0;
-          for (i, &light_on) in lights.iter().enumerate() {
-            if light_on {
-              bits |= 1 << i;
-            }
-          }
-          request
-            .respond(Response::from_data(bits.to_be_bytes()))
-            .ok();
-        }
-        (Method::Post, "/toggle-button") => {
-          let mut content = String::new();
-          request.as_reader().read_to_string(&mut content).ok();
-          let value: i32 = content.trim().parse().unwrap_or(-1);
-
-          let mut sys = system_clone.lock().unwrap();
-          match value {
-            0 => {
-              sys.auto_spa(None);
-            }
-            1 => {
-              sys.toggle_jets();
-            }
-            2 => {
-              sys.toggle_filter_schedule();
-            }
-            3 => {
-              sys.toggle_quick_clean();
-            }
-            4 => {
-              sys.toggle_main_valves();
-            }
-            6 => {
-              sys.toggle_heater_on();
-            }
-            7 => {
-              sys.toggle_heat_mode();
-            }
-            _ => {}
-          }
-          request.respond(Response::from_string("OK")).ok();
-        }
-        _ => {
-          request
-            .respond(Response::from_string("Not Found").with_status_code(404))
-            .ok();
-        }
-      }
-    }
+    eprint!("Server running on {}://{}", scheme, config.bind);
+    http::serve(server, api);
   });
 
   // Channel to send key presses from thread to main loop
@@ -95,27 +176,31 @@ fn main() {
   // Thread to capture keyboard input
   thread::spawn(move || {
     let stdin = io::stdin();
-    for key in stdin.keys() {
-      if let Ok(k) = key {
-        tx.send(k).ok();
+    for k in stdin.keys().flatten() {
+      if matches!(
+        k,
+        termion::event::Key::Char('e') | termion::event::Key::Char('E')
+      ) {
+        stop.raise();
       }
+      tx.send(k).ok();
     }
   });
 
   let clear_all = |stdout: &mut dyn Write| {
     write!(stdout, "{}{}", clear::All, cursor::Goto(1, 1)).unwrap();
     writeln!(stdout, "=== PoolMax System ===\r").unwrap();
-    writeln!(stdout, "Server: http://127.0.0.1:3000\r").unwrap();
+    writeln!(stdout, "Server: {}://{}\r", scheme, config.bind).unwrap();
+    if let Some(password) = &generated {
+      writeln!(stdout, "Login: admin / {}\r", password).unwrap();
+    }
     writeln!(stdout, "Controls:\r").unwrap();
-    writeln!(stdout, "  c - Toggle Quick Clean\r").unwrap();
-    writeln!(stdout, "  r - Toggle Filter Schedule\r").unwrap();
-    writeln!(stdout, "  h - Heater On\r").unwrap();
-    writeln!(stdout, "  j - Toggle Jets\r").unwrap();
-    writeln!(stdout, "  s - Spa Mode\r").unwrap();
-    writeln!(stdout, "  m - Switch Main Valve Orientation (Pool/Spa)\r").unwrap();
-    writeln!(stdout, "  k - Switch Heater Mode\r").unwrap();
-    writeln!(stdout, "  l - Clear Screen\r").unwrap();
-    writeln!(stdout, "  q - Quit\r").unwrap();
+    for line in CONTROLS {
+      writeln!(stdout, "{}\r", line).unwrap();
+    }
+    writeln!(stdout, "\r").unwrap();
+    writeln!(stdout, "=== Plant ===\r").unwrap();
+    writeln!(stdout, "\r").unwrap();
     writeln!(stdout, "\r").unwrap();
     writeln!(stdout, "=== Messages ===\r").unwrap();
     stdout.flush().unwrap();
@@ -123,9 +208,19 @@ fn main() {
 
   clear_all(&mut stdout);
 
-  let message_start_line = 14;
-  let mut message_lines: Vec<String> = Vec::new();
-  let max_messages = 48;
+  let plant_line = CONTROLS.len() as u16 + 6 + generated.is_some() as u16;
+  let message_start_line = plant_line + 3;
+
+  let lcd = TermDisplay::lcd_16x2(64, 3);
+  let oled = TermDisplay::oled_128x64(64, 3 + lcd.height() + 1);
+  let mut screen = StatusScreen::<48>::default();
+
+  let buttons = SimButtons::default();
+  let mut button_processor = ButtonProcessor::default();
+  let started = Instant::now();
+  let mut ticked = Instant::now();
+  let mut saved = Instant::now();
+  let mut sim_secs = 0.0;
 
   loop {
     if let Ok(key) = rx.try_recv() {
@@ -149,46 +244,164 @@ fn main() {
         Key::Char('k') | Key::Char('K') => {
           sys.toggle_heat_mode();
         }
+        Key::Char('f') | Key::Char('F') => {
+          sys.toggle_spillover();
+        }
+        Key::Char('o') | Key::Char('O') => {
+          let b = !sys.jets_override;
+          sys.set_jets_override(b);
+        }
         Key::Char('s') | Key::Char('S') => {
           sys.auto_spa(None);
         }
+        Key::Char('u') | Key::Char('U') => {
+          sys.toggle_pool_light();
+        }
+        Key::Char('g') | Key::Char('G') => {
+          let next = sys.pool_light.show.map_or(0, |s| (s + 1) % SHOWS.len());
+          sys.select_light_show(next);
+        }
+        Key::Char('y') | Key::Char('Y') => {
+          sys.resync_pool_light();
+        }
+        Key::Char('z') | Key::Char('Z') => {
+          sys.toggle_super_chlorinate();
+        }
+        Key::Char('b') | Key::Char('B') => {
+          sys.backwash();
+        }
+        Key::Char('w') | Key::Char('W') => {
+          let mut p = plant.lock().unwrap();
+          p.air_leak = !p.air_leak;
+        }
         Key::Char('p') | Key::Char('P') => {
           sys.display_status();
         }
         Key::Char('m') | Key::Char('M') => {
           sys.toggle_main_valves();
         }
+        Key::Char(c @ '0'..='7') => {
+          if let Some(b) = Button::from_index(c as usize - '0' as usize) {
+            buttons.tap(b);
+          }
+        }
+        Key::Alt(c @ '0'..='7') => {
+          if let Some(b) = Button::from_index(c as usize - '0' as usize) {
+            buttons.long_press(b);
+          }
+        }
+        Key::Char('x') | Key::Char('X') => {
+          buttons.hold(Button::FilterSchedule, 800);
+          buttons.hold(Button::QuickClean, 800);
+        }
+        Key::Char('e') | Key::Char('E') => {
+          sys.emergency_stop();
+        }
+        Key::Char('a') | Key::Char('A') => {
+          sys.acknowledge_emergency_stop();
+        }
+        Key::Char('d') | Key::Char('D') => {
+          screen.next_page(started.elapsed().as_millis() as u64);
+        }
+        Key::Char('v') | Key::Char('V') => {
+          screen.toggle_view();
+        }
+        Key::Char('[') => {
+          screen.scroll_up();
+        }
+        Key::Char(']') => {
+          screen.scroll_down();
+        }
         Key::Char('l') | Key::Char('L') => {
-          message_lines.clear();
+          screen.clear_log();
           clear_all(&mut stdout);
         }
         Key::Char('q') | Key::Char('Q') => {
           write!(stdout, "{}{}", clear::All, cursor::Goto(1, 1)).unwrap();
           writeln!(stdout, "Quitting...\r").unwrap();
+          if let Err(e) = maintenance::save(&config.maintenance_file, &sys.maintenance) {
+            writeln!(stdout, "Maintenance: {}\r", e).unwrap();
+          }
           break;
         }
         _ => {}
       }
     }
 
-    let mut has_new_messages = false;
+    let mut actions = Vec::new();
+    let now_ms = started.elapsed().as_millis() as u64;
+    button_processor.update(now_ms, &buttons, |e| actions.extend(Action::for_event(e)));
+    if !actions.is_empty() {
+      let mut sys = system.lock().unwrap();
+      for a in actions {
+        sys.perform(a);
+      }
+    }
 
-    {
+    // Timers run on simulated time
+    sim_secs += ticked.elapsed().as_secs_f64() * speed;
+    ticked = Instant::now();
+    if sim_secs >= 1.0 {
+      let minute = plant.lock().unwrap().minute_of_day();
+      let mut sys = system.lock().unwrap();
+      sys.tick(sim_secs as u64);
+      sys.aux_tick(sim_secs as u64, Some(minute));
+      sys.chlorinator_tick(sim_secs as u64);
+      sys.chemistry_tick(sim_secs as u64, Some(minute));
+      sys.pressure_tick(sim_secs as u64);
+      sys.history_tick(sim_secs as u64);
+      sim_secs = sim_secs.fract();
+    }
+    if saved.elapsed() >= SAVE_EVERY {
       let mut sys = system.lock().unwrap();
-      while let Some(msg) = sys.pop_message() {
-        message_lines.push(msg.to_string());
-        has_new_messages = true;
+      if let Err(e) = maintenance::save(&config.maintenance_file, &sys.maintenance) {
+        log_msg!(sys.message_queue, "Maintenance: {}", e);
+      }
+      saved = Instant::now();
+    }
+
+    let mut has_new_messages;
 
-        if message_lines.len() > max_messages {
-          message_lines.remove(0);
+    {
+      let mut sys = system.lock().unwrap();
+      // The screen's log is the one copy; the log file and the message
+      // pane below both read from it
+      let fed = screen.feed(&mut sys.message_queue);
+      has_new_messages = fed > 0;
+      if let Some(f) = log_file.as_mut() {
+        let skip = screen.messages().count().saturating_sub(fed);
+        let written = screen
+          .messages()
+          .skip(skip)
+          .try_for_each(|msg| writeln!(f, "{}", msg.get_str()));
+        if let Err(e) = written {
+          log_msg!(screen, "Log file: {}", e);
+          log_file = None;
+          has_new_messages = true;
         }
       }
+
+      screen.tick(now_ms);
+      screen.render(&*sys, &lcd);
+      screen.render(&*sys, &oled);
     }
+    lcd.draw(&mut stdout);
+    oled.draw(&mut stdout);
 
-    if has_new_messages {
-      message_lines.push("--------------------------------------".to_string());
+    let status = plant.lock().unwrap().status_line(speed);
+    write!(
+      stdout,
+      "{}{}{}",
+      cursor::Goto(1, plant_line),
+      clear::CurrentLine,
+      status
+    )
+    .unwrap();
 
-      for (i, line) in message_lines.iter().enumerate() {
+    if has_new_messages {
+      let separator = "--------------------------------------";
+      let lines = screen.messages().map(|m| m.get_str()).chain([separator]);
+      for (i, line) in lines.enumerate() {
         write!(
           stdout,
           "{}{}{}",
@@ -207,16 +420,8 @@ fn main() {
   }
 }
 
-// // For embedded/microcontroller implementation with LED screen
-// #[cfg(not(target_os = "linux"))]
-// fn display_on_led(system: &mut System<impl Mech, impl Lights>) {
-//   // This would be called in your embedded main loop
-//   if let Some(msg) = system.pop_message() {
-//     let msg_str = msg.get_str();
-
-//     // Display on LED screen (pseudo-code)
-//     // led_display.clear();
-//     // led_display.write_str(msg_str);
-//     // led_display.refresh();
-//   }
-// }
+/// Startup problems end here, before the terminal goes raw
+fn fail(msg: &str) -> ! {
+  eprintln!("desktop-sim: {}", msg);
+  std::process::exit(2);
+}
@@ -0,0 +1,38 @@
+// maintenance.rs - Run counters kept in a TOML file across restarts
+use app_core::maintenance::Maintenance;
+use std::path::Path;
+
+/// Counters from `path`, defaults for anything missing. A missing file
+/// just means a fresh install.
+pub fn load(path: &Path) -> Result<Maintenance, String> {
+  let mut m = Maintenance::default();
+
+  let text = match std::fs::read_to_string(path) {
+    Ok(t) => t,
+    Err(_) => return Ok(m),
+  };
+  let table: toml::Table = text
+    .parse()
+    .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+  for (key, value) in table {
+    let n = value
+      .as_integer()
+      .and_then(|n| u64::try_from(n).ok())
+      .ok_or_else(|| format!("{}: bad {} = {}", path.display(), key, value))?;
+    if !m.set(&key, n) {
+      return Err(format!("{}: unknown key {}", path.display(), key));
+    }
+  }
+
+  Ok(m)
+}
+
+pub fn save(path: &Path, m: &Maintenance) -> Result<(), String> {
+  let text: String = m
+    .fields()
+    .iter()
+    .map(|(k, v)| format!("{} = {}\n", k, v))
+    .collect();
+  std::fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))
+}
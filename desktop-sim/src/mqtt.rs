@@ -0,0 +1,659 @@
+// mqtt.rs - System state out to an MQTT broker, commands back in, and
+// Home Assistant discovery so the entities turn up on their own
+use crate::SimSystem;
+use app_core::log_msg;
+use app_core::scene::FilterRun;
+use app_core::structs::{PoolOrSpa, ValveMode};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Where Home Assistant looks for discovery payloads, its default
+const DISCOVERY_PREFIX: &str = "homeassistant";
+// Real time between looks for changed state
+const PUBLISH_EVERY: Duration = Duration::from_secs(1);
+// Real time between attempts while the broker can't be reached
+const RETRY_EVERY: Duration = Duration::from_secs(5);
+
+pub struct Settings {
+  pub host: String,
+  pub port: u16,
+  /// Topics go under `<prefix>/`; also the Home Assistant device id
+  pub prefix: String,
+  pub user: Option<String>,
+  pub password: Option<String>,
+}
+
+#[derive(Copy, Clone)]
+enum Kind {
+  Switch,
+  Select(&'static [&'static str]),
+  Sensor {
+    unit: &'static str,
+    class: &'static str,
+  },
+  BinarySensor(&'static str),
+  Button,
+}
+
+impl Kind {
+  fn component(self) -> &'static str {
+    match self {
+      Kind::Switch => "switch",
+      Kind::Select(_) => "select",
+      Kind::Sensor { .. } => "sensor",
+      Kind::BinarySensor(_) => "binary_sensor",
+      Kind::Button => "button",
+    }
+  }
+
+  fn has_state(self) -> bool {
+    !matches!(self, Kind::Button)
+  }
+
+  fn takes_commands(self) -> bool {
+    matches!(self, Kind::Switch | Kind::Select(_) | Kind::Button)
+  }
+}
+
+/// One Home Assistant entity. `id` is its topic under the prefix.
+struct Entity {
+  id: String,
+  name: String,
+  kind: Kind,
+}
+
+const FILTER_OPTIONS: &[&str] = &["off", "schedule", "quick_clean"];
+const VALVE_OPTIONS: &[&str] = &["pool", "spa", "spillover"];
+const HEAT_MODE_OPTIONS: &[&str] = &["pool", "spa"];
+
+const FIXED: [(&str, &str, Kind); 16] = [
+  ("filter", "Filter", Kind::Select(FILTER_OPTIONS)),
+  ("valves", "Valves", Kind::Select(VALVE_OPTIONS)),
+  ("heat_mode", "Heat mode", Kind::Select(HEAT_MODE_OPTIONS)),
+  ("heater", "Heater", Kind::Switch),
+  ("jets", "Jets", Kind::Switch),
+  ("pool_light", "Pool light", Kind::Switch),
+  ("super_chlorinate", "Super chlorinate", Kind::Switch),
+  ("pump", "Pump", Kind::BinarySensor("running")),
+  (
+    "stop_latched",
+    "Stop latched",
+    Kind::BinarySensor("problem"),
+  ),
+  (
+    "backwash_due",
+    "Backwash due",
+    Kind::BinarySensor("problem"),
+  ),
+  ("emergency_stop", "Emergency stop", Kind::Button),
+  ("acknowledge", "Acknowledge stop", Kind::Button),
+  ("pool_temp", "Pool temperature", TEMPERATURE),
+  ("spa_temp", "Spa temperature", TEMPERATURE),
+  ("air_temp", "Air temperature", TEMPERATURE),
+  (
+    "filter_pressure",
+    "Filter pressure",
+    Kind::Sensor {
+      unit: "psi",
+      class: "pressure",
+    },
+  ),
+];
+
+const TEMPERATURE: Kind = Kind::Sensor {
+  unit: "°F",
+  class: "temperature",
+};
+
+fn entities(sys: &SimSystem) -> Vec<Entity> {
+  let mut list: Vec<Entity> = FIXED
+    .iter()
+    .map(|(id, name, kind)| Entity {
+      id: id.to_string(),
+      name: name.to_string(),
+      kind: *kind,
+    })
+    .collect();
+  for (i, c) in sys.aux.iter().enumerate() {
+    list.push(Entity {
+      id: format!("aux_{}", i),
+      name: c.name.to_string(),
+      kind: Kind::Switch,
+    });
+  }
+  list
+}
+
+/// Payload per entity id. Unknown readings are left out rather than sent
+/// as something Home Assistant would take for a value.
+fn states(sys: &SimSystem) -> Vec<(String, String)> {
+  let on_off = |b: bool| if b { "ON" } else { "OFF" }.to_string();
+  let filter = match FilterRun::of(sys) {
+    FilterRun::Off => "off",
+    FilterRun::Schedule => "schedule",
+    FilterRun::QuickClean => "quick_clean",
+  };
+  let valves = match sys.valve_mode() {
+    ValveMode::Pool => "pool",
+    ValveMode::Spa => "spa",
+    ValveMode::Spillover => "spillover",
+  };
+  let heat_mode = match sys.heater.mode {
+    PoolOrSpa::Pool => "pool",
+    PoolOrSpa::Spa => "spa",
+  };
+
+  let mut out = vec![
+    ("filter".to_string(), filter.to_string()),
+    ("valves".to_string(), valves.to_string()),
+    ("heat_mode".to_string(), heat_mode.to_string()),
+    ("heater".to_string(), on_off(sys.heater.on)),
+    ("jets".to_string(), on_off(sys.jets_on)),
+    ("pool_light".to_string(), on_off(sys.pool_light.on)),
+    (
+      "super_chlorinate".to_string(),
+      on_off(sys.chlorinator.super_chlorinating()),
+    ),
+    ("pump".to_string(), on_off(sys.pump_running())),
+    ("stop_latched".to_string(), on_off(sys.estop_latched)),
+    (
+      "backwash_due".to_string(),
+      on_off(sys.pressure.backwash_due),
+    ),
+  ];
+  let r = sys.readings();
+  for (id, v) in [
+    ("pool_temp", r.pool_temp_f),
+    ("spa_temp", r.spa_temp_f),
+    ("air_temp", r.air_temp_f),
+    ("filter_pressure", r.filter_psi),
+  ] {
+    if let Some(v) = v {
+      out.push((id.to_string(), format!("{:.1}", v)));
+    }
+  }
+  for (i, c) in sys.aux.iter().enumerate() {
+    out.push((format!("aux_{}", i), on_off(c.on)));
+  }
+  out
+}
+
+/// Runs one command on `sys`; `None` if it isn't one
+fn command(sys: &mut SimSystem, id: &str, payload: &str) -> Option<bool> {
+  let on = match payload {
+    "ON" | "on" => Some(true),
+    "OFF" | "off" => Some(false),
+    _ => None,
+  };
+  match id {
+    "filter" => {
+      let f = match payload {
+        "off" => FilterRun::Off,
+        "schedule" => FilterRun::Schedule,
+        "quick_clean" => FilterRun::QuickClean,
+        _ => return None,
+      };
+      Some(sys.set_filter_run(f))
+    }
+    "valves" => {
+      let mode = match payload {
+        "pool" => ValveMode::Pool,
+        "spa" => ValveMode::Spa,
+        "spillover" => ValveMode::Spillover,
+        _ => return None,
+      };
+      Some(sys.set_valve_mode(mode))
+    }
+    "heat_mode" => {
+      let mode = match payload {
+        "pool" => PoolOrSpa::Pool,
+        "spa" => PoolOrSpa::Spa,
+        _ => return None,
+      };
+      Some(sys.set_heat_mode(mode))
+    }
+    "heater" => on.map(|b| sys.set_heater_on(b)),
+    "jets" => on.map(|b| sys.jets_on == b || sys.toggle_jets()),
+    "pool_light" => on.map(|b| sys.set_pool_light(b)),
+    "super_chlorinate" => on.map(|b| sys.set_super_chlorinate(b)),
+    "acknowledge" => Some(sys.acknowledge_emergency_stop()),
+    _ => {
+      let i = id.strip_prefix("aux_")?.parse::<usize>().ok()?;
+      if i >= sys.aux.len() {
+        return None;
+      }
+      on.map(|b| sys.set_aux(i, b))
+    }
+  }
+}
+
+/// What the connection thread passes on to the bridge thread
+enum Note {
+  Connected,
+  Lost(String),
+  Message(String, String),
+}
+
+/// Connects in the background and keeps at it; problems show up in the
+/// message log rather than stopping the sim
+pub fn start(settings: Settings, system: Arc<Mutex<SimSystem>>) {
+  let availability = format!("{}/status", settings.prefix);
+  let mut options = MqttOptions::new(
+    format!("{}-desktop-sim", settings.prefix),
+    settings.host.clone(),
+    settings.port,
+  );
+  options.set_keep_alive(Duration::from_secs(30));
+  options.set_last_will(LastWill::new(
+    availability.as_str(),
+    "offline",
+    QoS::AtLeastOnce,
+    true,
+  ));
+  if let Some(user) = &settings.user {
+    options.set_credentials(user, settings.password.clone().unwrap_or_default());
+  }
+  // Room for a whole round of discovery and state
+  let (client, mut connection) = Client::new(options, 64);
+
+  // Polls the connection and nothing else, so keep-alives go out even
+  // while a routine holds the system lock
+  let (tx, rx) = mpsc::channel();
+  thread::spawn(move || {
+    let mut up = None;
+    for event in connection.iter() {
+      let note = match event {
+        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+          up = Some(true);
+          Note::Connected
+        }
+        Ok(Event::Incoming(Packet::Publish(p))) => Note::Message(
+          p.topic,
+          String::from_utf8_lossy(&p.payload).trim().to_string(),
+        ),
+        Ok(_) => continue,
+        Err(e) => {
+          // Said once per outage, not on every retry
+          if up.replace(false) != Some(false) && tx.send(Note::Lost(e.to_string())).is_err() {
+            return;
+          }
+          thread::sleep(RETRY_EVERY);
+          continue;
+        }
+      };
+      if tx.send(note).is_err() {
+        return;
+      }
+    }
+  });
+
+  let stop = system.lock().unwrap().stop.clone();
+  thread::spawn(move || {
+    let address = format!("{}:{}", settings.host, settings.port);
+    let topic = |id: &str, leaf: &str| format!("{}/{}/{}", settings.prefix, id, leaf);
+    // Last payload sent per entity, so only changes go out
+    let mut sent: HashMap<String, String> = HashMap::new();
+    let mut connected = false;
+
+    loop {
+      match rx.recv_timeout(PUBLISH_EVERY) {
+        Ok(Note::Connected) => {
+          connected = true;
+          sent.clear();
+          let mut sys = system.lock().unwrap();
+          log_msg!(sys.message_queue, "MQTT: Connected to {}", address);
+          client
+            .subscribe(format!("{}/+/set", settings.prefix), QoS::AtLeastOnce)
+            .ok();
+          for e in entities(&sys) {
+            let config = discovery(&settings.prefix, &availability, &e);
+            client
+              .publish(
+                format!(
+                  "{}/{}/{}/{}/config",
+                  DISCOVERY_PREFIX,
+                  e.kind.component(),
+                  settings.prefix,
+                  e.id
+                ),
+                QoS::AtLeastOnce,
+                true,
+                config.to_string(),
+              )
+              .ok();
+          }
+          client
+            .publish(availability.as_str(), QoS::AtLeastOnce, true, "online")
+            .ok();
+        }
+        Ok(Note::Lost(e)) => {
+          connected = false;
+          let mut sys = system.lock().unwrap();
+          log_msg!(sys.message_queue, "MQTT: {} ({}), retrying", e, address);
+        }
+        Ok(Note::Message(t, payload)) => {
+          let id = t
+            .strip_prefix(&format!("{}/", settings.prefix))
+            .and_then(|rest| rest.strip_suffix("/set"))
+            .unwrap_or_default();
+          // Raised before waiting on the lock, like the panel's E-Stop
+          if id == "emergency_stop" {
+            stop.raise();
+            system.lock().unwrap().emergency_stop();
+            continue;
+          }
+          let mut sys = system.lock().unwrap();
+          if command(&mut sys, id, &payload).is_none() {
+            log_msg!(sys.message_queue, "MQTT: Ignored {} = {}", t, payload);
+          }
+        }
+        Err(RecvTimeoutError::Timeout) => {}
+        Err(RecvTimeoutError::Disconnected) => return,
+      }
+
+      if !connected {
+        continue;
+      }
+      let now = states(&system.lock().unwrap());
+      for (id, payload) in now {
+        if sent.get(&id) == Some(&payload) {
+          continue;
+        }
+        let ok = client
+          .publish(
+            topic(&id, "state"),
+            QoS::AtLeastOnce,
+            true,
+            payload.as_str(),
+          )
+          .is_ok();
+        if ok {
+          sent.insert(id, payload);
+        }
+      }
+    }
+  });
+}
+
+/// The retained config Home Assistant builds an entity from
+fn discovery(prefix: &str, availability: &str, e: &Entity) -> Value {
+  let mut config = json!({
+    "name": e.name,
+    "unique_id": format!("{}_{}", prefix, e.id),
+    "availability_topic": availability,
+    "device": {
+      "identifiers": [prefix],
+      "name": "PoolMax",
+      "manufacturer": "PoolMax",
+      "model": "desktop-sim",
+    },
+  });
+  if e.kind.has_state() {
+    config["state_topic"] = json!(format!("{}/{}/state", prefix, e.id));
+  }
+  if e.kind.takes_commands() {
+    config["command_topic"] = json!(format!("{}/{}/set", prefix, e.id));
+  }
+  match e.kind {
+    Kind::Select(options) => config["options"] = json!(options),
+    Kind::Sensor { unit, class } => {
+      config["unit_of_measurement"] = json!(unit);
+      config["device_class"] = json!(class);
+      config["state_class"] = json!("measurement");
+    }
+    Kind::BinarySensor(class) => config["device_class"] = json!(class),
+    Kind::Switch | Kind::Button => {}
+  }
+  config
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::plant::SimMech;
+  use app_core::circuits::AuxCircuit;
+  use app_core::fault::{FaultyLights, FaultyMech};
+  use app_core::structs::{HasOSLights, System};
+  use std::time::Instant;
+
+  fn system() -> SimSystem {
+    let mut sys = System::new(
+      FaultyMech::new(SimMech::new(1.0)),
+      FaultyLights::new(HasOSLights),
+    );
+    sys.internal_test = true;
+    sys.add_aux(AuxCircuit::new("Waterfall"));
+    sys.add_aux(AuxCircuit::new("Blower"));
+    sys
+  }
+
+  fn state(sys: &SimSystem, id: &str) -> Option<String> {
+    states(sys)
+      .into_iter()
+      .find(|(i, _)| i == id)
+      .map(|(_, p)| p)
+  }
+
+  fn entity(sys: &SimSystem, id: &str) -> Entity {
+    entities(sys).into_iter().find(|e| e.id == id).unwrap()
+  }
+
+  #[test]
+  fn every_entity_with_a_state_gets_one() {
+    let sys = system();
+    let ids: Vec<String> = states(&sys).into_iter().map(|(id, _)| id).collect();
+    let stateful: Vec<String> = entities(&sys)
+      .into_iter()
+      .filter(|e| e.kind.has_state())
+      .map(|e| e.id)
+      .collect();
+    assert_eq!(ids, stateful);
+    assert_eq!(entity(&sys, "aux_1").name, "Blower");
+
+    assert_eq!(state(&sys, "heater").unwrap(), "OFF");
+    assert_eq!(state(&sys, "jets").unwrap(), "OFF");
+    assert_eq!(state(&sys, "aux_0").unwrap(), "OFF");
+    assert_eq!(state(&sys, "stop_latched").unwrap(), "OFF");
+    let temp = state(&sys, "pool_temp").unwrap();
+    assert!(temp.parse::<f32>().is_ok() && temp.split('.').nth(1).unwrap().len() == 1);
+  }
+
+  #[test]
+  fn commands_reach_the_system_and_come_back_as_state() {
+    let mut sys = system();
+    for (id, payload) in [
+      ("heater", "ON"),
+      ("pool_light", "on"),
+      ("aux_1", "ON"),
+      ("valves", "spa"),
+      ("heat_mode", "spa"),
+      ("filter", "quick_clean"),
+      ("jets", "ON"),
+    ] {
+      assert_eq!(
+        command(&mut sys, id, payload),
+        Some(true),
+        "{} {}",
+        id,
+        payload
+      );
+      let expect = match payload {
+        "on" => "ON",
+        p => p,
+      };
+      assert_eq!(state(&sys, id).unwrap(), expect, "{}", id);
+    }
+    assert!(sys.heater.on && sys.pool_light.on && sys.jets_on);
+    assert!(sys.aux[1].on && !sys.aux[0].on);
+    assert_eq!(sys.valve_mode(), ValveMode::Spa);
+    assert_eq!(sys.heater.mode, PoolOrSpa::Spa);
+
+    // Asking for what's already so leaves it alone
+    assert_eq!(command(&mut sys, "jets", "ON"), Some(true));
+    assert!(sys.jets_on);
+    assert_eq!(command(&mut sys, "jets", "OFF"), Some(true));
+    assert_eq!(state(&sys, "jets").unwrap(), "OFF");
+  }
+
+  #[test]
+  fn unknown_commands_are_refused() {
+    let mut sys = system();
+    for (id, payload) in [
+      ("filter", "turbo"),
+      ("valves", "ON"),
+      ("heat_mode", "hot_tub"),
+      ("heater", "maybe"),
+      ("aux_2", "ON"),
+      ("aux_x", "ON"),
+      ("pump", "ON"),
+      ("pool_temp", "80"),
+      ("", "ON"),
+      // Handled before the lock is taken, never here
+      ("emergency_stop", "PRESS"),
+    ] {
+      assert_eq!(command(&mut sys, id, payload), None, "{} {}", id, payload);
+    }
+    assert!(!sys.estop_latched);
+    assert!(!sys.heater.on);
+  }
+
+  #[test]
+  fn discovery_matches_the_kind() {
+    let sys = system();
+    let config = |id: &str| discovery("pool", "pool/status", &entity(&sys, id));
+
+    let filter = config("filter");
+    assert_eq!(filter["name"], "Filter");
+    assert_eq!(filter["unique_id"], "pool_filter");
+    assert_eq!(filter["availability_topic"], "pool/status");
+    assert_eq!(filter["device"]["identifiers"], json!(["pool"]));
+    assert_eq!(filter["state_topic"], "pool/filter/state");
+    assert_eq!(filter["command_topic"], "pool/filter/set");
+    assert_eq!(filter["options"], json!(FILTER_OPTIONS));
+
+    let temp = config("spa_temp");
+    assert_eq!(temp["unit_of_measurement"], "°F");
+    assert_eq!(temp["device_class"], "temperature");
+    assert_eq!(temp["state_class"], "measurement");
+    assert!(temp.get("command_topic").is_none());
+
+    let pump = config("pump");
+    assert_eq!(pump["device_class"], "running");
+    assert_eq!(pump["state_topic"], "pool/pump/state");
+    assert!(pump.get("command_topic").is_none());
+
+    let stop = config("emergency_stop");
+    assert_eq!(stop["command_topic"], "pool/emergency_stop/set");
+    assert!(stop.get("state_topic").is_none());
+
+    let aux = config("aux_0");
+    assert_eq!(aux["name"], "Waterfall");
+    assert_eq!(aux["command_topic"], "pool/aux_0/set");
+    assert!(aux.get("options").is_none());
+  }
+
+  /// Needs a broker without a login, e.g.
+  ///
+  ///   mosquitto -p 1883
+  ///   MQTT_HOST=localhost cargo test -p desktop-sim -- --ignored mqtt
+  ///
+  /// Topics go under a prefix of their own, so a shared broker is fine.
+  #[test]
+  #[ignore]
+  fn round_trip_through_a_broker() {
+    let host = std::env::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_string());
+    let prefix = format!("poolmax-test-{}", std::process::id());
+    let system = Arc::new(Mutex::new(system()));
+    start(
+      Settings {
+        host: host.clone(),
+        port: 1883,
+        prefix: prefix.clone(),
+        user: None,
+        password: None,
+      },
+      system.clone(),
+    );
+
+    let (client, mut connection) = Client::new(
+      MqttOptions::new(format!("{}-watch", prefix), host, 1883),
+      16,
+    );
+    client
+      .subscribe(
+        format!("{}/+/{}/+/config", DISCOVERY_PREFIX, prefix),
+        QoS::AtLeastOnce,
+      )
+      .unwrap();
+    client
+      .subscribe(format!("{}/#", prefix), QoS::AtLeastOnce)
+      .unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+      for event in connection.iter() {
+        if let Ok(Event::Incoming(Packet::Publish(p))) = event {
+          let payload = String::from_utf8_lossy(&p.payload).to_string();
+          if tx.send((p.topic, payload)).is_err() {
+            return;
+          }
+        }
+      }
+    });
+    let wait_for = |topic: &str, check: &dyn Fn(&str) -> bool| {
+      let end = Instant::now() + Duration::from_secs(10);
+      while let Ok((t, p)) = rx.recv_timeout(end.saturating_duration_since(Instant::now())) {
+        if t == topic && check(&p) {
+          return p;
+        }
+      }
+      panic!("nothing on {}", topic);
+    };
+
+    let config = wait_for(
+      &format!("{}/switch/{}/jets/config", DISCOVERY_PREFIX, prefix),
+      &|_| true,
+    );
+    let config: Value = serde_json::from_str(&config).unwrap();
+    assert_eq!(config["command_topic"], format!("{}/jets/set", prefix));
+    wait_for(&format!("{}/status", prefix), &|p| p == "online");
+    wait_for(&format!("{}/jets/state", prefix), &|p| p == "OFF");
+
+    // Jets only run with the valves on the spa
+    client
+      .publish(
+        format!("{}/valves/set", prefix),
+        QoS::AtLeastOnce,
+        false,
+        "spa",
+      )
+      .unwrap();
+    wait_for(&format!("{}/valves/state", prefix), &|p| p == "spa");
+
+    client
+      .publish(
+        format!("{}/jets/set", prefix),
+        QoS::AtLeastOnce,
+        false,
+        "ON",
+      )
+      .unwrap();
+    wait_for(&format!("{}/jets/state", prefix), &|p| p == "ON");
+    assert!(system.lock().unwrap().jets_on);
+
+    client
+      .publish(
+        format!("{}/emergency_stop/set", prefix),
+        QoS::AtLeastOnce,
+        false,
+        "PRESS",
+      )
+      .unwrap();
+    wait_for(&format!("{}/stop_latched/state", prefix), &|p| p == "ON");
+    assert!(system.lock().unwrap().estop_latched);
+  }
+}
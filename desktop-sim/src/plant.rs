@@ -0,0 +1,596 @@
+use app_core::backwash::Multiport;
+use app_core::chemistry::{ChemSensors, DosePump, DosingMech};
+use app_core::chlorinator::ChlorinatorMech;
+use app_core::circuits::AuxMech;
+use app_core::pool_light::SHOWS;
+use app_core::structs::{Mech, PoolOrSpa, PoolValve, Sensors};
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const POOL_GALLONS: f64 = 15_000.0;
+const SPA_GALLONS: f64 = 600.0;
+const LBS_PER_GALLON: f64 = 8.34;
+const HEATER_BTU_HR: f64 = 250_000.0;
+const HEATER_EFFICIENCY: f64 = 0.84;
+const PUMP_GPM: f64 = 60.0;
+// Heater pressure switch won't fire below this flow
+const HEATER_MIN_GPM: f64 = 20.0;
+// Thermostat setting for each heater mode
+const POOL_SETPOINT_F: f64 = 82.0;
+const SPA_SETPOINT_F: f64 = 102.0;
+// Fraction of the temperature difference to air lost per hour
+const POOL_LOSS_PER_HR: f64 = 0.02;
+const SPA_LOSS_PER_HR: f64 = 0.15;
+const JETS_LOSS_FACTOR: f64 = 2.0;
+const VALVE_TRAVEL_SECS: f64 = 20.0;
+const SALT_LOSS_PER_DAY: f64 = 0.002;
+// Chemistry of the whole body of water, pool and spa share it
+const PH_RISE_PER_DAY: f64 = 0.15;
+// Sun and bather load burn off this fraction of the free chlorine a day
+const FC_LOSS_PER_DAY: f64 = 0.3;
+const CELL_PPM_PER_DAY: f64 = 3.0;
+const ACID_ML_PER_MIN: f64 = 50.0;
+const PH_PER_ML_ACID: f64 = 0.0003;
+// 10% liquid chlorine
+const CHLORINE_ML_PER_MIN: f64 = 100.0;
+const FC_PPM_PER_ML: f64 = 0.00176;
+// Filter gauge at full flow through a clean filter, and how fast dirt
+// builds on it with the pump running
+const CLEAN_FILTER_PSI: f64 = 12.0;
+const DIRT_PSI_PER_HOUR: f64 = 0.25;
+// Share of the dirt a minute of backwashing flushes out
+const BACKWASH_PER_MIN: f64 = 0.5;
+// Pool light: back on within this steps to the next show, off inside the
+// reset window goes back to the first. The real window is 11-14s; it's
+// wider here because the plant steps 3 simulated seconds at a time.
+const LIGHT_CYCLE_SECS: f64 = 5.0;
+const LIGHT_RESET_SECS: std::ops::Range<f64> = 8.0..17.0;
+
+/// Water, pump, heater and valves of a pool/spa combo, advanced in simulated seconds
+pub struct Plant {
+  pub sim_secs: f64,
+  pub pool_temp_f: f64,
+  pub spa_temp_f: f64,
+  pub air_temp_f: f64,
+  /// Suction valve position, 0.0 is pool and 1.0 is spa
+  pub valve_pos: f64,
+  pub valve_target: f64,
+  /// Return valve, same scale
+  pub return_pos: f64,
+  pub return_target: f64,
+  pub pool_valve: PoolValve,
+  pub quick_clean: bool,
+  pub filter_sched: bool,
+  pub heater_relay: bool,
+  pub heater_mode: PoolOrSpa,
+  pub jets: bool,
+  /// Auxiliary relays, grown as `AuxMech` addresses them
+  pub aux: Vec<bool>,
+  pub light_power: bool,
+  /// Show the light is really on, whatever `System` thinks
+  pub light_show: usize,
+  light_off_at: f64,
+  /// Salt cell output, 0-100%
+  pub cell_output: u8,
+  pub salt_ppm: f64,
+  pub ph: f64,
+  /// Free chlorine
+  pub fc_ppm: f64,
+  pub acid_pump: bool,
+  pub chlorine_pump: bool,
+  /// What the dirt in the filter adds to the gauge
+  pub filter_dirt_psi: f64,
+  /// Air drawn in on the suction side, so the pump can't prime
+  pub air_leak: bool,
+  pub multiport: Multiport,
+}
+
+impl Plant {
+  pub fn new() -> Self {
+    Plant {
+      // Start the clock at 8am
+      sim_secs: 8.0 * 3600.0,
+      pool_temp_f: 74.0,
+      spa_temp_f: 74.0,
+      air_temp_f: 70.0,
+      valve_pos: 0.0,
+      valve_target: 0.0,
+      return_pos: 0.0,
+      return_target: 0.0,
+      pool_valve: PoolValve::Skimmer,
+      quick_clean: false,
+      filter_sched: false,
+      heater_relay: false,
+      heater_mode: PoolOrSpa::Pool,
+      jets: false,
+      aux: Vec::new(),
+      light_power: false,
+      // Left on some show by whoever had it last
+      light_show: 6,
+      light_off_at: f64::NEG_INFINITY,
+      cell_output: 0,
+      salt_ppm: 3200.0,
+      ph: 7.7,
+      fc_ppm: 1.5,
+      acid_pump: false,
+      chlorine_pump: false,
+      filter_dirt_psi: 0.0,
+      air_leak: false,
+      multiport: Multiport::Filter,
+    }
+  }
+
+  pub fn pump_on(&self) -> bool {
+    self.quick_clean || self.filter_sched
+  }
+
+  pub fn valve_moving(&self) -> bool {
+    (self.valve_pos - self.valve_target).abs() > f64::EPSILON
+      || (self.return_pos - self.return_target).abs() > f64::EPSILON
+  }
+
+  /// Suction from the pool, return to the spa
+  pub fn spilling_over(&self) -> bool {
+    self.valve_pos < 0.5 && self.return_pos >= 0.5
+  }
+
+  pub fn flow_gpm(&self) -> f64 {
+    if !self.pump_on() || self.air_leak {
+      return 0.0;
+    }
+    // A half-open valve throttles the flow
+    let throttle = 1.0
+      - 0.6
+        * (PI * self.valve_pos)
+          .sin()
+          .max((PI * self.return_pos).sin());
+    PUMP_GPM * throttle
+  }
+
+  /// Rises with flow and with dirt in the filter
+  pub fn filter_psi(&self) -> f64 {
+    let flow = self.flow_gpm() / PUMP_GPM;
+    if flow == 0.0 {
+      return 0.0;
+    }
+    (CLEAN_FILTER_PSI + self.filter_dirt_psi) * flow
+  }
+
+  /// The cell's flow switch cuts it without water moving
+  pub fn cell_producing(&self) -> bool {
+    self.cell_output > 0 && self.flow_gpm() > 0.0
+  }
+
+  /// Water in the cell is whatever the suction side draws, a little warmer
+  /// while it's working
+  pub fn cell_temp_f(&self) -> f64 {
+    let water = self.heater_inlet_f();
+    if self.cell_producing() {
+      water + 2.0 * self.cell_output as f64 / 100.0
+    } else {
+      water
+    }
+  }
+
+  /// Rough fit of ORP to free chlorine and pH
+  pub fn orp_mv(&self) -> f64 {
+    650.0 + 120.0 * self.fc_ppm.max(0.05).log10() - 60.0 * (self.ph - 7.5)
+  }
+
+  /// The heater's thermostat reads the water coming in, which is whatever
+  /// the suction side draws
+  pub fn heater_inlet_f(&self) -> f64 {
+    if self.valve_pos < 0.5 {
+      self.pool_temp_f
+    } else {
+      self.spa_temp_f
+    }
+  }
+
+  pub fn heater_setpoint_f(&self) -> f64 {
+    match self.heater_mode {
+      PoolOrSpa::Pool => POOL_SETPOINT_F,
+      PoolOrSpa::Spa => SPA_SETPOINT_F,
+    }
+  }
+
+  pub fn heater_firing(&self) -> bool {
+    self.heater_relay
+      && self.flow_gpm() >= HEATER_MIN_GPM
+      && self.heater_inlet_f() < self.heater_setpoint_f()
+  }
+
+  pub fn step(&mut self, dt: f64) {
+    self.sim_secs += dt;
+    let hours = dt / 3600.0;
+
+    // Daily air swing, coldest around 3am
+    let day_frac = (self.sim_secs / 86_400.0).fract();
+    self.air_temp_f = 70.0 + 8.0 * (2.0 * PI * (day_frac - 0.375)).sin();
+
+    let max_travel = dt / VALVE_TRAVEL_SECS;
+    let delta = (self.valve_target - self.valve_pos).clamp(-max_travel, max_travel);
+    self.valve_pos += delta;
+    let delta = (self.return_target - self.return_pos).clamp(-max_travel, max_travel);
+    self.return_pos += delta;
+
+    // Heated water goes wherever the return points
+    if self.heater_firing() {
+      let btu = HEATER_BTU_HR * HEATER_EFFICIENCY * hours;
+      if self.return_pos < 0.5 {
+        self.pool_temp_f += btu / (POOL_GALLONS * LBS_PER_GALLON);
+      } else {
+        self.spa_temp_f += btu / (SPA_GALLONS * LBS_PER_GALLON);
+      }
+    }
+
+    let flow = self.flow_gpm() / PUMP_GPM;
+    if self.multiport == Multiport::Backwash {
+      self.filter_dirt_psi *= (1.0 - BACKWASH_PER_MIN).powf(flow * hours * 60.0);
+    } else {
+      self.filter_dirt_psi += DIRT_PSI_PER_HOUR * flow * hours;
+    }
+
+    // Splash-out and backwash dilute the salt a little each day
+    self.salt_ppm -= self.salt_ppm * SALT_LOSS_PER_DAY * hours / 24.0;
+
+    // Aeration drives pH up, faster with the jets or spillover going
+    let aeration = if self.jets || self.spilling_over() {
+      2.0
+    } else {
+      1.0
+    };
+    self.ph += PH_RISE_PER_DAY * aeration * hours / 24.0;
+    self.fc_ppm -= self.fc_ppm * FC_LOSS_PER_DAY * hours / 24.0;
+    if self.cell_producing() {
+      self.fc_ppm += CELL_PPM_PER_DAY * self.cell_output as f64 / 100.0 * hours / 24.0;
+    }
+    // Dosing pumps inject into the return, nothing gets in without flow
+    if self.flow_gpm() > 0.0 {
+      let mins = hours * 60.0;
+      if self.acid_pump {
+        self.ph -= ACID_ML_PER_MIN * mins * PH_PER_ML_ACID;
+      }
+      if self.chlorine_pump {
+        self.fc_ppm += CHLORINE_ML_PER_MIN * mins * FC_PPM_PER_ML;
+      }
+    }
+
+    // Pool water pumped into the spa pushes the same amount back over the edge
+    if self.spilling_over() {
+      let gallons = self.flow_gpm() * 60.0 * hours;
+      let spa_mix = (gallons / SPA_GALLONS).min(1.0);
+      let pool_mix = (gallons / POOL_GALLONS).min(1.0);
+      let (pool, spa) = (self.pool_temp_f, self.spa_temp_f);
+      self.spa_temp_f += (pool - spa) * spa_mix;
+      self.pool_temp_f += (spa - pool) * pool_mix;
+    }
+
+    let spa_loss = if self.jets {
+      SPA_LOSS_PER_HR * JETS_LOSS_FACTOR
+    } else {
+      SPA_LOSS_PER_HR
+    };
+    self.pool_temp_f -= (self.pool_temp_f - self.air_temp_f) * (POOL_LOSS_PER_HR * hours).min(1.0);
+    self.spa_temp_f -= (self.spa_temp_f - self.air_temp_f) * (spa_loss * hours).min(1.0);
+  }
+
+  pub fn set_light_power(&mut self, on: bool) {
+    if on == self.light_power {
+      return;
+    }
+    if on {
+      let off = self.sim_secs - self.light_off_at;
+      if off < LIGHT_CYCLE_SECS {
+        self.light_show = (self.light_show + 1) % SHOWS.len();
+      } else if LIGHT_RESET_SECS.contains(&off) {
+        self.light_show = 0;
+      }
+    } else {
+      self.light_off_at = self.sim_secs;
+    }
+    self.light_power = on;
+  }
+
+  /// Minutes since midnight on the simulated clock
+  pub fn minute_of_day(&self) -> u32 {
+    ((self.sim_secs as u64 / 60) % (24 * 60)) as u32
+  }
+
+  pub fn status_line(&self, speed: f64) -> String {
+    let t = self.sim_secs as u64;
+    let valve = if self.valve_moving() {
+      format!("moving {:.0}%", self.valve_pos.max(self.return_pos) * 100.0)
+    } else if self.spilling_over() {
+      "SPILLOVER".to_string()
+    } else if self.valve_pos < 0.5 {
+      "POOL".to_string()
+    } else {
+      "SPA".to_string()
+    };
+
+    format!(
+      "Pool {:.1}F  Spa {:.1}F  Air {:.1}F  Flow {:.0} gpm{}  Filter {:.1} psi{}  Valves {}  Heater {}  Light {}  Cell {}%  Salt {:.0}  pH {:.2}  ORP {:.0}  Day {} {:02}:{:02} (x{})",
+      self.pool_temp_f,
+      self.spa_temp_f,
+      self.air_temp_f,
+      self.flow_gpm(),
+      if self.air_leak { " AIR LEAK" } else { "" },
+      self.filter_psi(),
+      match self.multiport {
+        Multiport::Filter => String::new(),
+        m => format!(" {}", m.to_string().to_uppercase()),
+      },
+      valve,
+      if self.heater_firing() {
+        "FIRING"
+      } else if !self.heater_relay {
+        "off"
+      } else if self.flow_gpm() < HEATER_MIN_GPM {
+        "NO FLOW"
+      } else {
+        "AT TEMP"
+      },
+      if self.light_power { SHOWS[self.light_show] } else { "off" },
+      if self.cell_producing() { self.cell_output } else { 0 },
+      self.salt_ppm,
+      self.ph,
+      self.orp_mv(),
+      t / 86_400 + 1,
+      (t / 3600) % 24,
+      (t / 60) % 60,
+      speed
+    )
+  }
+}
+
+fn valve_target(m: PoolOrSpa) -> f64 {
+  match m {
+    PoolOrSpa::Pool => 0.0,
+    PoolOrSpa::Spa => 1.0,
+  }
+}
+
+/// `Mech` backed by a shared `Plant`, stepped in accelerated time on its own thread
+#[derive(Clone)]
+pub struct SimMech {
+  pub plant: Arc<Mutex<Plant>>,
+  pub speed: f64,
+}
+
+impl SimMech {
+  pub fn new(speed: f64) -> Self {
+    SimMech {
+      plant: Arc::new(Mutex::new(Plant::new())),
+      speed,
+    }
+  }
+
+  /// Advance the plant by `tick` of real time, scaled by `speed`, forever
+  pub fn spawn_stepper(&self, tick: Duration) {
+    let plant = self.plant.clone();
+    let dt = tick.as_secs_f64() * self.speed;
+    thread::spawn(move || loop {
+      plant.lock().unwrap().step(dt);
+      thread::sleep(tick);
+    });
+  }
+
+  fn with<R>(&self, f: impl FnOnce(&mut Plant) -> R) -> R {
+    f(&mut self.plant.lock().unwrap())
+  }
+}
+
+impl Mech for SimMech {
+  fn delay_secs(&self, secs: u64) {
+    thread::sleep(Duration::from_secs_f64(secs as f64 / self.speed));
+  }
+
+  fn set_quick_clean(&self, v: bool) -> bool {
+    self.with(|p| p.quick_clean = v);
+    true
+  }
+  fn mech_set_filter_sched(&self, v: bool) -> bool {
+    self.with(|p| p.filter_sched = v);
+    true
+  }
+
+  fn mech_pool_valve_to(&self, v: PoolValve) -> bool {
+    self.with(|p| p.pool_valve = v);
+    true
+  }
+  fn mech_main_valve_to(&self, m: PoolOrSpa) -> bool {
+    self.with(|p| {
+      p.valve_target = valve_target(m);
+      p.return_target = valve_target(m);
+    });
+    true
+  }
+  fn mech_return_valve_to(&self, m: PoolOrSpa) -> bool {
+    self.with(|p| p.return_target = valve_target(m));
+    true
+  }
+  fn mech_pool_light(&self, on: bool) -> bool {
+    self.with(|p| p.set_light_power(on));
+    true
+  }
+  fn mech_multiport_to(&self, m: Multiport) -> bool {
+    self.with(|p| p.multiport = m);
+    true
+  }
+
+  fn heater_on_toggle(&self, b: bool) -> bool {
+    self.with(|p| p.heater_relay = b);
+    true
+  }
+  fn heater_mode_toggle(&self, m: PoolOrSpa) -> bool {
+    self.with(|p| p.heater_mode = m);
+    true
+  }
+
+  fn jets_on_toggle(&self, b: bool) -> bool {
+    self.with(|p| p.jets = b);
+    true
+  }
+
+  fn mech_aux_off(&self, index: usize) -> bool {
+    self.aux_set(index, false)
+  }
+  fn mech_cell_off(&self) -> bool {
+    self.cell_output(0)
+  }
+  fn mech_dose_pump_off(&self, pump: DosePump) -> bool {
+    self.dose_pump(pump, false)
+  }
+}
+
+impl AuxMech for SimMech {
+  fn aux_set(&self, index: usize, on: bool) -> bool {
+    self.with(|p| {
+      if p.aux.len() <= index {
+        p.aux.resize(index + 1, false);
+      }
+      p.aux[index] = on;
+    });
+    true
+  }
+}
+
+impl ChlorinatorMech for SimMech {
+  fn cell_output(&self, percent: u8) -> bool {
+    self.with(|p| p.cell_output = percent);
+    true
+  }
+  fn salt_ppm(&self) -> Option<u32> {
+    Some(self.with(|p| p.salt_ppm as u32))
+  }
+  fn cell_temp_f(&self) -> Option<f32> {
+    Some(self.with(|p| p.cell_temp_f() as f32))
+  }
+}
+
+impl DosingMech for SimMech {
+  fn dose_pump(&self, pump: DosePump, on: bool) -> bool {
+    self.with(|p| match pump {
+      DosePump::Acid => p.acid_pump = on,
+      DosePump::Chlorine => p.chlorine_pump = on,
+    });
+    true
+  }
+}
+
+impl ChemSensors for SimMech {
+  fn ph(&self) -> Option<f32> {
+    Some(self.with(|p| p.ph as f32))
+  }
+  fn orp_mv(&self) -> Option<f32> {
+    Some(self.with(|p| p.orp_mv() as f32))
+  }
+}
+
+impl Sensors for SimMech {
+  fn pool_temp_f(&self) -> Option<f32> {
+    Some(self.with(|p| p.pool_temp_f as f32))
+  }
+  fn spa_temp_f(&self) -> Option<f32> {
+    Some(self.with(|p| p.spa_temp_f as f32))
+  }
+  fn air_temp_f(&self) -> Option<f32> {
+    Some(self.with(|p| p.air_temp_f as f32))
+  }
+  fn flow_gpm(&self) -> Option<f32> {
+    Some(self.with(|p| p.flow_gpm() as f32))
+  }
+  fn filter_psi(&self) -> Option<f32> {
+    Some(self.with(|p| p.filter_psi() as f32))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Spa valves in place and the heater relay closed
+  fn spa_heating() -> Plant {
+    let mut p = Plant::new();
+    p.valve_pos = 1.0;
+    p.valve_target = 1.0;
+    p.return_pos = 1.0;
+    p.return_target = 1.0;
+    p.heater_mode = PoolOrSpa::Spa;
+    p.heater_relay = true;
+    p
+  }
+
+  #[test]
+  fn heater_warms_the_spa_only_with_flow() {
+    let mut p = spa_heating();
+    p.step(600.0);
+    assert!(p.spa_temp_f < 74.0, "no flow, no heat: {}", p.spa_temp_f);
+
+    p.quick_clean = true;
+    let before = p.spa_temp_f;
+    p.step(600.0);
+    assert!(
+      p.spa_temp_f > before + 5.0,
+      "{} -> {}",
+      before,
+      p.spa_temp_f
+    );
+    assert!((p.pool_temp_f - 74.0).abs() < 0.1);
+  }
+
+  #[test]
+  fn heater_mode_sets_the_thermostat() {
+    let mut p = spa_heating();
+    p.quick_clean = true;
+    p.spa_temp_f = 90.0;
+    assert!(p.heater_firing());
+
+    p.heater_mode = PoolOrSpa::Pool;
+    assert!(!p.heater_firing());
+    p.step(600.0);
+    assert!(p.spa_temp_f < 90.0);
+  }
+
+  #[test]
+  fn water_drifts_toward_air() {
+    let mut p = Plant::new();
+    p.pool_temp_f = 85.0;
+    p.spa_temp_f = 100.0;
+    for _ in 0..60 {
+      p.step(60.0);
+    }
+    assert!(p.pool_temp_f < 85.0 && p.pool_temp_f > 80.0);
+    // The spa is smaller and loses heat faster
+    assert!(100.0 - p.spa_temp_f > 85.0 - p.pool_temp_f);
+  }
+
+  #[test]
+  fn valves_travel_at_a_fixed_rate() {
+    let mut p = Plant::new();
+    p.valve_target = 1.0;
+    p.step(VALVE_TRAVEL_SECS / 2.0);
+    assert!(p.valve_moving());
+    assert!((p.valve_pos - 0.5).abs() < 1e-9);
+    p.step(VALVE_TRAVEL_SECS);
+    assert_eq!(p.valve_pos, 1.0);
+  }
+
+  #[test]
+  fn sensors_read_the_plant() {
+    let mech = SimMech::new(60.0);
+    assert_eq!(mech.flow_gpm(), Some(0.0));
+    assert_eq!(mech.filter_psi(), Some(0.0));
+    assert_eq!(mech.pool_temp_f(), Some(74.0));
+
+    mech.set_quick_clean(true);
+    assert_eq!(mech.flow_gpm(), Some(PUMP_GPM as f32));
+    assert_eq!(mech.filter_psi(), Some(CLEAN_FILTER_PSI as f32));
+
+    mech.plant.lock().unwrap().air_leak = true;
+    assert_eq!(mech.flow_gpm(), Some(0.0));
+  }
+}
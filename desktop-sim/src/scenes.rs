@@ -0,0 +1,51 @@
+// scenes.rs - Built-in scenes plus user-defined ones from a TOML file
+use app_core::scene::Scene;
+use std::path::Path;
+
+pub fn builtins() -> Vec<Scene> {
+  Scene::BUILTIN
+    .iter()
+    .filter_map(|n| Scene::builtin(n))
+    .collect()
+}
+
+/// Built-ins, then every `[name]` table in `path`. A user scene with a
+/// built-in's name replaces it. A missing file just means no user scenes.
+pub fn load(path: &Path) -> Result<Vec<Scene>, String> {
+  let mut scenes = builtins();
+
+  let text = match std::fs::read_to_string(path) {
+    Ok(t) => t,
+    Err(_) => return Ok(scenes),
+  };
+  let table: toml::Table = text
+    .parse()
+    .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+  for (name, targets) in table {
+    let targets = targets
+      .as_table()
+      .ok_or_else(|| format!("{}: [{}] is not a table", path.display(), name))?;
+    let mut scene = Scene::named(&name);
+    for (key, value) in targets {
+      let text = match value.as_str() {
+        Some(s) => s.to_string(),
+        None => value.to_string(),
+      };
+      if !scene.set(key, &text) {
+        return Err(format!(
+          "{}: [{}] bad {} = {}",
+          path.display(),
+          name,
+          key,
+          value
+        ));
+      }
+    }
+
+    scenes.retain(|s| s.name != scene.name);
+    scenes.push(scene);
+  }
+
+  Ok(scenes)
+}
@@ -0,0 +1,39 @@
+use app_core::buttons::{Button, Buttons};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Keyboard-driven stand-in for the front panel pins. A key press holds the
+/// matching virtual button down for a fixed time.
+#[derive(Clone, Default)]
+pub struct SimButtons {
+  held_until: Arc<Mutex<[Option<Instant>; 16]>>,
+}
+
+impl SimButtons {
+  pub fn hold(&self, b: Button, ms: u64) {
+    let until = Instant::now() + Duration::from_millis(ms);
+    self.held_until.lock().unwrap()[b.index()] = Some(until);
+  }
+
+  pub fn tap(&self, b: Button) {
+    self.hold(b, 150);
+  }
+
+  pub fn long_press(&self, b: Button) {
+    self.hold(b, 2000);
+  }
+}
+
+impl Buttons for SimButtons {
+  fn sample(&self) -> u16 {
+    let now = Instant::now();
+    let held = self.held_until.lock().unwrap();
+    let mut bits = 0;
+    for (i, until) in held.iter().enumerate() {
+      if until.is_some_and(|u| u > now) {
+        bits |= 1 << i;
+      }
+    }
+    bits
+  }
+}
@@ -0,0 +1,86 @@
+// term_display.rs - Character displays drawn as boxes in the terminal
+use app_core::display::Display;
+use std::cell::RefCell;
+use std::io::Write;
+use termion::cursor;
+
+/// Emulates a character display at a fixed spot on the terminal. Rows are
+/// buffered by `write_line` and drawn by `draw`.
+pub struct TermDisplay {
+  name: &'static str,
+  cols: usize,
+  rows: RefCell<Vec<String>>,
+  x: u16,
+  y: u16,
+}
+
+impl TermDisplay {
+  /// 16x2 HD44780-style LCD
+  pub fn lcd_16x2(x: u16, y: u16) -> Self {
+    Self::new("LCD 16x2", 16, 2, x, y)
+  }
+
+  /// 128x64 OLED as 21x8 text with a 6x8 font
+  pub fn oled_128x64(x: u16, y: u16) -> Self {
+    Self::new("OLED 128x64", 21, 8, x, y)
+  }
+
+  fn new(name: &'static str, cols: usize, rows: usize, x: u16, y: u16) -> Self {
+    TermDisplay {
+      name,
+      cols,
+      rows: RefCell::new(vec![String::new(); rows]),
+      x,
+      y,
+    }
+  }
+
+  /// Terminal rows taken up, including the frame and caption
+  pub fn height(&self) -> u16 {
+    self.rows.borrow().len() as u16 + 3
+  }
+
+  pub fn draw(&self, out: &mut dyn Write) {
+    let width = self.cols;
+    write!(out, "{}{}", cursor::Goto(self.x, self.y), self.name).unwrap();
+    write!(
+      out,
+      "{}+{}+",
+      cursor::Goto(self.x, self.y + 1),
+      "-".repeat(width)
+    )
+    .unwrap();
+    for (i, row) in self.rows.borrow().iter().enumerate() {
+      write!(
+        out,
+        "{}|{:<width$}|",
+        cursor::Goto(self.x, self.y + 2 + i as u16),
+        row
+      )
+      .unwrap();
+    }
+    write!(
+      out,
+      "{}+{}+",
+      cursor::Goto(self.x, self.y + self.height() - 1),
+      "-".repeat(width)
+    )
+    .unwrap();
+  }
+}
+
+impl Display for TermDisplay {
+  fn size(&self) -> (usize, usize) {
+    (self.cols, self.rows.borrow().len())
+  }
+
+  fn write_line(&self, row: usize, text: &str) -> bool {
+    match self.rows.borrow_mut().get_mut(row) {
+      Some(r) => {
+        *r = text.to_string();
+        true
+      }
+      None => false,
+    }
+  }
+}
@@ -0,0 +1,381 @@
+// tls.rs - Certificate and key for HTTPS, self-signed on first run
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tiny_http::SslConfig;
+
+/// Self-signed certificates are good for this long
+const VALID_DAYS: u64 = 10 * 365;
+
+/// The PEM files at `cert` and `key`, made first if neither exists
+pub fn load_or_create(cert: &Path, key: &Path, bind: SocketAddr) -> Result<SslConfig, String> {
+  match (cert.exists(), key.exists()) {
+    (true, true) => {}
+    (false, false) => {
+      let (cert_pem, key_pem) = self_signed(bind.ip())?;
+      write_private(key, &key_pem)?;
+      std::fs::write(cert, cert_pem).map_err(|e| format!("{}: {}", cert.display(), e))?;
+      eprintln!("Created a self-signed certificate in {}", cert.display());
+    }
+    (true, false) => {
+      return Err(format!(
+        "{} has no key at {}",
+        cert.display(),
+        key.display()
+      ))
+    }
+    (false, true) => {
+      return Err(format!(
+        "{} has no certificate at {}",
+        key.display(),
+        cert.display()
+      ))
+    }
+  }
+
+  let certificate = std::fs::read(cert).map_err(|e| format!("{}: {}", cert.display(), e))?;
+  let private_key = std::fs::read(key).map_err(|e| format!("{}: {}", key.display(), e))?;
+
+  // tiny_http panics on a key it can't parse, so look first
+  match rustls_pemfile::certs(&mut certificate.as_slice()) {
+    Ok(c) if !c.is_empty() => {}
+    _ => return Err(format!("{}: no PEM certificate", cert.display())),
+  }
+  let pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut private_key.as_slice());
+  let rsa = rustls_pemfile::rsa_private_keys(&mut private_key.as_slice());
+  if !matches!(pkcs8, Ok(k) if !k.is_empty()) && !matches!(rsa, Ok(k) if !k.is_empty()) {
+    return Err(format!(
+      "{}: no unencrypted PKCS#8 or RSA private key",
+      key.display()
+    ));
+  }
+
+  Ok(SslConfig {
+    certificate,
+    private_key,
+  })
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, text: &str) -> Result<(), String> {
+  use std::io::Write;
+  use std::os::unix::fs::OpenOptionsExt;
+  std::fs::OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .mode(0o600)
+    .open(path)
+    .and_then(|mut f| f.write_all(text.as_bytes()))
+    .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, text: &str) -> Result<(), String> {
+  std::fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// An ECDSA P-256 certificate for localhost and `ip`, as PEM
+/// (certificate, PKCS#8 key)
+fn self_signed(ip: IpAddr) -> Result<(String, String), String> {
+  let rng = SystemRandom::new();
+  let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+    .map_err(|_| "could not generate a key".to_string())?;
+  let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+    .map_err(|_| "could not load the generated key".to_string())?;
+
+  let mut serial = [0u8; 16];
+  rng
+    .fill(&mut serial)
+    .map_err(|_| "no randomness for the serial".to_string())?;
+  // Positive and without a leading zero byte
+  serial[0] = (serial[0] & 0x7f) | 0x40;
+
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_err(|_| "clock is before 1970".to_string())?
+    .as_secs();
+  let name = der::seq(&[der::set(&[der::seq(&[
+    der::oid(OID_COMMON_NAME),
+    der::utf8("PoolMax desktop-sim"),
+  ])])]);
+
+  let mut alt_names = vec![
+    der::tagged(0x82, b"localhost"),
+    der::tagged(0x87, &[127, 0, 0, 1]),
+  ];
+  match ip {
+    IpAddr::V4(v4) if !v4.is_unspecified() && !v4.is_loopback() => {
+      alt_names.push(der::tagged(0x87, &v4.octets()))
+    }
+    IpAddr::V6(v6) if !v6.is_unspecified() => alt_names.push(der::tagged(0x87, &v6.octets())),
+    _ => {}
+  }
+  let extensions = der::tagged(
+    0xa3,
+    &der::seq(&[der::seq(&[
+      der::oid(OID_SUBJECT_ALT_NAME),
+      der::tagged(0x04, &der::seq(&alt_names)),
+    ])]),
+  );
+
+  let tbs = der::seq(&[
+    // Version 3
+    der::tagged(0xa0, &der::int(&[2])),
+    der::int(&serial),
+    der::seq(&[der::oid(OID_ECDSA_SHA256)]),
+    name.clone(),
+    der::seq(&[der::utc_time(now), der::utc_time(now + VALID_DAYS * 86_400)]),
+    name,
+    der::seq(&[
+      der::seq(&[der::oid(OID_EC_PUBLIC_KEY), der::oid(OID_P256)]),
+      der::bit_string(pair.public_key().as_ref()),
+    ]),
+    extensions,
+  ]);
+  let signature = pair
+    .sign(&rng, &tbs)
+    .map_err(|_| "could not sign the certificate".to_string())?;
+  let cert = der::seq(&[
+    tbs,
+    der::seq(&[der::oid(OID_ECDSA_SHA256)]),
+    der::bit_string(signature.as_ref()),
+  ]);
+
+  Ok((
+    pem("CERTIFICATE", &cert),
+    pem("PRIVATE KEY", pkcs8.as_ref()),
+  ))
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+  let b64 = base64::encode(der);
+  let mut out = format!("-----BEGIN {}-----\n", label);
+  for line in b64.as_bytes().chunks(64) {
+    out.push_str(std::str::from_utf8(line).unwrap_or_default());
+    out.push('\n');
+  }
+  out.push_str(&format!("-----END {}-----\n", label));
+  out
+}
+
+// Encoded object identifiers, without tag and length
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+/// Just enough DER to write one certificate
+mod der {
+  pub fn tagged(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = body.len();
+    if len < 0x80 {
+      out.push(len as u8);
+    } else {
+      let bytes = len.to_be_bytes();
+      let skip = bytes.iter().take_while(|b| **b == 0).count();
+      out.push(0x80 | (bytes.len() - skip) as u8);
+      out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(body);
+    out
+  }
+
+  pub fn seq(parts: &[Vec<u8>]) -> Vec<u8> {
+    tagged(0x30, &parts.concat())
+  }
+
+  pub fn set(parts: &[Vec<u8>]) -> Vec<u8> {
+    tagged(0x31, &parts.concat())
+  }
+
+  /// Big-endian and already positive
+  pub fn int(bytes: &[u8]) -> Vec<u8> {
+    tagged(0x02, bytes)
+  }
+
+  pub fn oid(encoded: &[u8]) -> Vec<u8> {
+    tagged(0x06, encoded)
+  }
+
+  pub fn utf8(s: &str) -> Vec<u8> {
+    tagged(0x0c, s.as_bytes())
+  }
+
+  pub fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    tagged(0x03, &[&[0u8][..], bytes].concat())
+  }
+
+  /// YYMMDDHHMMSSZ, fine until 2050
+  pub fn utc_time(unix_secs: u64) -> Vec<u8> {
+    let days = (unix_secs / 86_400) as i64;
+    let secs = unix_secs % 86_400;
+    let (y, m, d) = civil_from_days(days);
+    let s = format!(
+      "{:02}{:02}{:02}{:02}{:02}{:02}Z",
+      y % 100,
+      m,
+      d,
+      secs / 3600,
+      secs / 60 % 60,
+      secs % 60
+    );
+    tagged(0x17, s.as_bytes())
+  }
+
+  // Days since 1970-01-01 to a proleptic Gregorian date (Howard Hinnant)
+  fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use webpki::{DnsNameRef, EndEntityCert, Error, Time, TlsServerTrustAnchors, TrustAnchor};
+
+  fn cert_der(cert_pem: &str) -> Vec<u8> {
+    let mut certs = rustls_pemfile::certs(&mut cert_pem.as_bytes()).unwrap();
+    assert_eq!(certs.len(), 1);
+    certs.remove(0)
+  }
+
+  // Tag, contents and whatever follows
+  fn read(der: &[u8]) -> (u8, &[u8], &[u8]) {
+    let (len, start) = match der[1] {
+      n if n < 0x80 => (n as usize, 2),
+      n => {
+        let end = 2 + (n & 0x7f) as usize;
+        let len = der[2..end].iter().fold(0, |len, b| len << 8 | *b as usize);
+        (len, end)
+      }
+    };
+    (der[0], &der[start..start + len], &der[start + len..])
+  }
+
+  fn items(mut der: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut out = Vec::new();
+    while !der.is_empty() {
+      let (tag, body, rest) = read(der);
+      out.push((tag, body));
+      der = rest;
+    }
+    out
+  }
+
+  // The subject alt names, as text
+  fn alt_names(cert: &[u8]) -> Vec<String> {
+    let (_, cert, _) = read(cert);
+    let (_, tbs, _) = read(cert);
+    let (_, extensions) = items(tbs)
+      .into_iter()
+      .find(|(tag, _)| *tag == 0xa3)
+      .unwrap();
+    let (_, extensions, _) = read(extensions);
+    for (_, ext) in items(extensions) {
+      let fields = items(ext);
+      if fields[0].1 != OID_SUBJECT_ALT_NAME {
+        continue;
+      }
+      let (_, names, _) = read(fields.last().unwrap().1);
+      return items(names)
+        .into_iter()
+        .map(|(tag, name)| match (tag, name.len()) {
+          (0x82, _) => String::from_utf8(name.to_vec()).unwrap(),
+          (0x87, 4) => IpAddr::from(<[u8; 4]>::try_from(name).unwrap()).to_string(),
+          (0x87, 16) => IpAddr::from(<[u8; 16]>::try_from(name).unwrap()).to_string(),
+          _ => panic!("alt name {:02x} {:?}", tag, name),
+        })
+        .collect();
+    }
+    panic!("no subject alt names");
+  }
+
+  #[test]
+  fn self_signed_certificate_verifies() {
+    let (cert_pem, key_pem) = self_signed([192, 168, 1, 20].into()).unwrap();
+    let der = cert_der(&cert_pem);
+    assert_eq!(alt_names(&der), ["localhost", "127.0.0.1", "192.168.1.20"]);
+
+    // Trusting it as its own root checks the signature and the dates
+    let cert = EndEntityCert::try_from(der.as_slice()).unwrap();
+    let anchors = [TrustAnchor::try_from_cert_der(&der).unwrap()];
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap()
+      .as_secs();
+    let valid_at = |secs: u64| {
+      cert.verify_is_valid_tls_server_cert(
+        &[&webpki::ECDSA_P256_SHA256],
+        &TlsServerTrustAnchors(&anchors),
+        &[],
+        Time::from_seconds_since_unix_epoch(secs),
+      )
+    };
+    let until = now + VALID_DAYS * 86_400;
+    assert_eq!(valid_at(now + 60), Ok(()));
+    assert_eq!(valid_at(until - 60), Ok(()));
+    assert_eq!(valid_at(now - 60), Err(Error::CertNotValidYet));
+    assert_eq!(valid_at(until + 60), Err(Error::CertExpired));
+
+    let name = |n| DnsNameRef::try_from_ascii_str(n).unwrap();
+    assert_eq!(cert.verify_is_valid_for_dns_name(name("localhost")), Ok(()));
+    assert!(cert
+      .verify_is_valid_for_dns_name(name("example.com"))
+      .is_err());
+
+    // And the key written alongside it is the one in the certificate
+    let pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut key_pem.as_bytes()).unwrap();
+    let pair = EcdsaKeyPair::from_pkcs8(
+      &ECDSA_P256_SHA256_ASN1_SIGNING,
+      &pkcs8[0],
+      &SystemRandom::new(),
+    )
+    .unwrap();
+    let message = b"desktop-sim";
+    let signature = pair.sign(&SystemRandom::new(), message).unwrap();
+    assert_eq!(
+      cert.verify_signature(&webpki::ECDSA_P256_SHA256, message, signature.as_ref()),
+      Ok(())
+    );
+  }
+
+  #[test]
+  fn wildcard_and_loopback_binds_add_no_names() {
+    for ip in ["0.0.0.0", "127.0.0.1", "::"] {
+      let (cert_pem, _) = self_signed(ip.parse().unwrap()).unwrap();
+      assert_eq!(
+        alt_names(&cert_der(&cert_pem)),
+        ["localhost", "127.0.0.1"],
+        "{}",
+        ip
+      );
+    }
+    let (cert_pem, _) = self_signed("fd00::5".parse().unwrap()).unwrap();
+    assert_eq!(
+      alt_names(&cert_der(&cert_pem)),
+      ["localhost", "127.0.0.1", "fd00::5"]
+    );
+  }
+
+  #[test]
+  fn dates_are_utc_time() {
+    assert_eq!(der::utc_time(0), der::tagged(0x17, b"700101000000Z"));
+    assert_eq!(
+      der::utc_time(1_709_210_096),
+      der::tagged(0x17, b"240229123456Z")
+    );
+  }
+}